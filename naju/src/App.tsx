@@ -27,6 +27,12 @@ import {
   createErrorReport,
   deleteErrorReport,
   listErrorReports,
+  validateStorage,
+  getStoreProblem,
+  recoverStore,
+  formatPatientCode,
+  readStoredFile,
+  serverError,
 } from "./lib/api";
 import { buildProfileMap } from "./lib/profile";
 
@@ -97,6 +103,15 @@ function fileIcon(file: PatientFile) {
   return "📎";
 }
 
+function FileLabelChip({ file }: { file: PatientFile }) {
+  if (!file.label && !file.color) return null;
+  return (
+    <span className="fileLabel" style={file.color ? { borderColor: file.color, color: file.color } : undefined}>
+      {file.label || "●"}
+    </span>
+  );
+}
+
 function isImage(path: string) {
   return path.startsWith("data:image/") || /\.(png|jpg|jpeg|webp|gif)$/i.test(path);
 }
@@ -2635,8 +2650,28 @@ function FilePreviewModal({
   onClose: () => void;
 }) {
   const meta = parseMetaJson(file);
-  const isImageFile = file.kind === "attachment" && isImage(file.path);
-  const isPdfFile = file.kind === "attachment" && isPdf(file.path);
+  // Files stored gzipped are unpacked into a blob: URL; the stored data URL would show gzip bytes.
+  const compressed = meta?.compressed === true;
+  const [unpackedUrl, setUnpackedUrl] = useState<string | null>(null);
+  useEffect(() => {
+    if (!compressed) return;
+    let url: string | null = null;
+    let cancelled = false;
+    readStoredFile(file.id)
+      .then((r) => {
+        if (cancelled) URL.revokeObjectURL(r.url);
+        else setUnpackedUrl((url = r.url));
+      })
+      .catch(() => undefined);
+    return () => {
+      cancelled = true;
+      if (url) URL.revokeObjectURL(url);
+    };
+  }, [file.id, compressed]);
+  const src = compressed ? unpackedUrl ?? "" : file.path;
+  const typePath = compressed ? `data:${meta?.mime ?? ""};` : file.path;
+  const isImageFile = file.kind === "attachment" && isImage(typePath);
+  const isPdfFile = file.kind === "attachment" && isPdf(typePath);
 
   return (
     <Modal
@@ -2648,9 +2683,9 @@ function FilePreviewModal({
         {file.kind === "attachment" ? (
           <div className="previewBody">
             {isImageFile ? (
-              <img className="previewImage" src={file.path} alt={`Vista previa de ${file.filename}`} />
+              <img className="previewImage" src={src} alt={`Vista previa de ${file.filename}`} />
             ) : isPdfFile ? (
-              <object className="previewPdf" data={file.path} type="application/pdf">
+              <object className="previewPdf" data={src} type="application/pdf">
                 <p>Vista previa no disponible.</p>
               </object>
             ) : (
@@ -2659,7 +2694,7 @@ function FilePreviewModal({
                 <div style={{ color: "var(--muted)" }}>Descarga para abrir este tipo de archivo.</div>
               </div>
             )}
-            <a className="pillBtn" href={file.path} download={file.filename}>
+            <a className="pillBtn" href={src} download={file.filename}>
               Descargar
             </a>
           </div>
@@ -2698,6 +2733,12 @@ function FilePreviewModal({
               ))}
             </div>
             <div className="previewNote">{meta?.observaciones ?? "Sin observaciones adicionales."}</div>
+            {typeof meta?.signature_path === "string" ? (
+              <div className="kv">
+                <div className="k">Firma</div>
+                <img className="v" src={meta.signature_path} alt="Firma del profesional" style={{ maxWidth: 240, maxHeight: 96 }} />
+              </div>
+            ) : null}
           </div>
         ) : (
           <div className="previewBody">
@@ -3173,7 +3214,7 @@ export default function App() {
     try {
       const res = await fetch("/__naju_update_check", { cache: "no-store" });
       const info = await res.json();
-      if (!info?.ok) throw new Error(info?.error || "No se pudo verificar");
+      if (!info?.ok) throw serverError(info);
       if (!info.behind) {
         pushToast({ type: "ok", msg: "Ya estás en la última versión ✅" });
         return;
@@ -3192,7 +3233,7 @@ export default function App() {
     try {
       const res = await fetch("/__naju_update_apply", { method: "POST" });
       const out = await res.json();
-      if (!out?.ok) throw new Error(out?.error || out?.detail || "No se pudo actualizar");
+      if (!out?.ok) throw serverError(out);
       pushToast({ type: "ok", msg: out?.message || (out?.updated ? "Actualizado ✅" : "Sin cambios") });
       setShowUpdate(false);
       // After pulling, Vite usually detects file changes. Force a hard reload just in case.
@@ -3213,7 +3254,7 @@ export default function App() {
     const q = query.trim().toLowerCase();
     if (!q) return patients;
     return patients.filter((p) => {
-      const hay = `${p.name} ${p.doc_type ?? ""} ${p.doc_number ?? ""} ${p.insurer ?? ""}`.toLowerCase();
+      const hay = `${p.name} ${p.doc_type ?? ""} ${p.doc_number ?? ""} ${p.insurer ?? ""} ${formatPatientCode(p.patient_code)}`.toLowerCase();
       return hay.includes(q);
     });
   }, [patients, query]);
@@ -3354,13 +3395,31 @@ export default function App() {
 
 
   useEffect(() => {
+    async function loadAll() {
+      await refreshPatients();
+      await refreshAllFiles();
+      await refreshAppointments();
+      await refreshErrorReports();
+    }
     (async () => {
       try {
-        await refreshPatients();
-        await refreshAllFiles();
-        await refreshAppointments();
-        await refreshErrorReports();
+        await loadAll();
+        const storage = await validateStorage();
+        if (!storage.healthy) pushToast({ type: "err", msg: `Atención, tus datos podrían no guardarse. ${storage.reason ?? ""}`.trim() });
       } catch (e: any) {
+        const problem = getStoreProblem();
+        const source = problem?.suggestion === "restore_local_copy" ? "local_copy" : problem?.suggestion === "restore_backup" ? "backup" : null;
+        const label = source === "local_copy" ? "la copia guardada en este navegador" : "el último respaldo";
+        if (source && confirm(`${errMsg(e)}\n\n¿Recuperar los datos desde ${label}? El archivo dañado se conserva aparte.`)) {
+          try {
+            await recoverStore(source);
+            await loadAll();
+            pushToast({ type: "ok", msg: "Datos recuperados ✅" });
+          } catch (e2: any) {
+            pushToast({ type: "err", msg: errMsg(e2) });
+          }
+          return;
+        }
         pushToast({ type: "err", msg: `Error cargando pacientes: ${errMsg(e)}` });
       }
     })();
@@ -3479,10 +3538,18 @@ export default function App() {
     const files = e.target.files ? Array.from(e.target.files) : [];
     if (!files.length) return;
     try {
-      await importFiles(selected.id, files);
+      const { imported, failed, warning } = await importFiles(selected.id, files);
       await refreshFiles(selected.id);
       await refreshAllFiles();
-      pushToast({ type: "ok", msg: "Archivos adjuntados ✅" });
+      const problems = [
+        failed.length ? `No se adjuntaron ${failed.length}: ${failed.map((f) => `${f.source} (${f.reason})`).join(", ")}.` : "",
+        warning ?? "",
+      ].filter(Boolean);
+      pushToast(
+        problems.length
+          ? { type: "err", msg: `Archivos adjuntados: ${imported.length}. ${problems.join(" ")}` }
+          : { type: "ok", msg: "Archivos adjuntados ✅" },
+      );
       startVT(() => setSection("archivos"));
     } catch (err: any) {
       pushToast({ type: "err", msg: `Error adjuntar: ${errMsg(err)}` });
@@ -3703,7 +3770,7 @@ export default function App() {
                   </span>
                 </h2>
                 <p className="hint" style={{ margin: 0 }}>
-                  {valOrDash(selected.doc_type)} {valOrDash(selected.doc_number)} · {valOrDash(selected.insurer)}
+                  N.º {formatPatientCode(selected.patient_code)} · {valOrDash(selected.doc_type)} {valOrDash(selected.doc_number)} · {valOrDash(selected.insurer)}
                 </p>
               </div>
             )}
@@ -4098,7 +4165,9 @@ export default function App() {
                       <div key={f.id} className="fileRow">
                         <div className="fileIcon">{fileIcon(f)}</div>
                         <div className="fileMeta">
-                          <div className="fileName">{f.filename}</div>
+                          <div className="fileName">
+                            {f.filename} <FileLabelChip file={f} />
+                          </div>
                           <div className="fileSub">{isoToNice(f.created_at)}</div>
                         </div>
                         <button className="smallBtn" onClick={() => actionOpenFile(f)}>
@@ -4173,7 +4242,9 @@ export default function App() {
                       <div key={f.id} className="fileRow">
                         <div className="fileIcon">{fileIcon(f)}</div>
                         <div className="fileMeta">
-                          <div className="fileName">{f.filename}</div>
+                          <div className="fileName">
+                            {f.filename} <FileLabelChip file={f} />
+                          </div>
                           <div className="fileSub">{isoToNice(f.created_at)}</div>
                         </div>
                         <button className="smallBtn" onClick={() => actionOpenFile(f)}>
@@ -4206,7 +4277,9 @@ export default function App() {
                       <div key={f.id} className="fileRow">
                         <div className="fileIcon">{fileIcon(f)}</div>
                         <div className="fileMeta">
-                          <div className="fileName">{f.filename}</div>
+                          <div className="fileName">
+                            {f.filename} <FileLabelChip file={f} />
+                          </div>
                           <div className="fileSub">{isoToNice(f.created_at)}</div>
                         </div>
                         <button className="smallBtn" onClick={() => actionOpenFile(f)}>
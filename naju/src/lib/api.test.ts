@@ -0,0 +1,248 @@
+import { afterEach, beforeEach, describe, expect, it, vi } from "vitest";
+
+type Api = typeof import("./api");
+
+// The dev server as the client sees it: /__naju_store kept in memory, every other endpoint missing.
+type FakeServer = {
+  store: unknown;
+  calls: { url: string; method: string }[];
+  // Statuses for the next store POSTs, in order; the last one repeats.
+  storeStatuses: number[];
+};
+
+let server: FakeServer;
+let api: Api;
+let t: typeof import("./messages").t;
+
+function json(status: number, body: unknown) {
+  return new Response(JSON.stringify(body), { status, headers: { "Content-Type": "application/json" } });
+}
+
+// api.ts caches the store in module state, so each test imports a fresh copy.
+async function loadApi(store: unknown = null) {
+  server = { store, calls: [], storeStatuses: [] };
+  localStorage.clear();
+  vi.stubGlobal(
+    "fetch",
+    vi.fn(async (input: RequestInfo | URL, init?: RequestInit) => {
+      const url = String(input);
+      const method = (init?.method ?? "GET").toUpperCase();
+      server.calls.push({ url, method });
+      if (url.split("?")[0] !== "/__naju_store") return json(404, { ok: false, error: "not_found" });
+      if (method === "GET") return json(200, server.store ?? {});
+      const status = (server.storeStatuses.length > 1 ? server.storeStatuses.shift() : server.storeStatuses[0]) ?? 200;
+      if (status !== 200) return json(status, { ok: false, busy: status === 503, error: status === 503 ? "store_busy" : "server_error" });
+      server.store = JSON.parse(String(init?.body));
+      return json(200, { ok: true });
+    })
+  );
+  vi.resetModules();
+  api = await import("./api");
+  t = (await import("./messages")).t;
+}
+
+// POSTs that would leave something on disk: the store itself, an asset or a patient folder marker.
+function diskWrites() {
+  return server.calls.filter((c) => c.method === "POST" && /^\/__naju_(store|asset|marker)\b/.test(c.url));
+}
+
+function pngFile(name = "foto.png") {
+  return new File([new Uint8Array([0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0, 0, 0, 0x0d])], name, { type: "image/png" });
+}
+
+// Makes the next save of the store fail midway, as a full browser quota would.
+function failNextStoreSave() {
+  const setItem = Storage.prototype.setItem;
+  let armed = true;
+  vi.spyOn(Storage.prototype, "setItem").mockImplementation(function (this: Storage, key: string, value: string) {
+    if (armed && key === "naju_web_store") {
+      armed = false;
+      throw new DOMException("quota exceeded", "QuotaExceededError");
+    }
+    setItem.call(this, key, value);
+  });
+}
+
+beforeEach(() => loadApi());
+
+afterEach(() => {
+  vi.useRealTimers();
+  vi.unstubAllGlobals();
+  vi.restoreAllMocks();
+});
+
+describe("unknown patient", () => {
+  it("importFiles fails before anything is written", async () => {
+    const file = new File(["hola"], "nota.txt", { type: "text/plain" });
+    await expect(api.importFiles("no-such-patient", [file])).rejects.toThrow(t("patient_not_found"));
+    expect(diskWrites()).toEqual([]);
+    expect(await api.listAllFiles()).toEqual([]);
+  });
+
+  it("setPatientPhoto fails before anything is written", async () => {
+    await expect(api.setPatientPhoto("no-such-patient", pngFile())).rejects.toThrow(t("patient_not_found"));
+    expect(diskWrites()).toEqual([]);
+    expect(await api.listAllFiles()).toEqual([]);
+  });
+});
+
+describe("listPatients search", () => {
+  async function names(query: string) {
+    return (await api.listPatients(query)).map((p) => p.name).sort();
+  }
+
+  it("matches accented and unaccented names both ways", async () => {
+    await api.createPatient({ name: "José Pérez" });
+    await api.createPatient({ name: "Jose Perez" });
+    await api.createPatient({ name: "Ana Gómez", insurer: "Sanitas" });
+    expect(await names("jose")).toEqual(["Jose Perez", "José Pérez"]);
+    expect(await names("JOSÉ PÉREZ")).toEqual(["Jose Perez", "José Pérez"]);
+    expect(await names("gomez")).toEqual(["Ana Gómez"]);
+    expect(await names("sanitás")).toEqual(["Ana Gómez"]);
+  });
+
+  it("backfills the search column of patients saved without it", async () => {
+    const iso = "2025-01-10T09:00:00.000Z";
+    await loadApi({ patients: [{ id: "p1", name: "Martín Ñáñez", insurer: null, created_at: iso, updated_at: iso }] });
+    expect(await names("martin nanez")).toEqual(["Martín Ñáñez"]);
+  });
+});
+
+describe("setPatientPhoto", () => {
+  it("rejects a file whose content is not an image, whatever its extension", async () => {
+    const patient = await api.createPatient({ name: "Laura Ruiz" });
+    const pdf = new File(["%PDF-1.7\n1 0 obj\n"], "foto.jpg", { type: "image/jpeg" });
+    await expect(api.setPatientPhoto(patient.id, pdf)).rejects.toThrow(t("photo_not_image", { name: "foto.jpg" }));
+    expect((await api.getPatient(patient.id)).photo_path).toBeNull();
+    expect(await api.listPatientPhotos(patient.id)).toEqual([]);
+  });
+
+  it("accepts an image even under a misleading name", async () => {
+    const patient = await api.createPatient({ name: "Laura Ruiz" });
+    const updated = await api.setPatientPhoto(patient.id, pngFile("foto.pdf"));
+    const photos = await api.listPatientPhotos(patient.id);
+    expect(photos).toHaveLength(1);
+    expect(photos[0]).toMatchObject({ filename: "foto.pdf", primary: true });
+    expect(updated.photo_path).toBe(photos[0].path);
+  });
+});
+
+describe("commit rollback", () => {
+  it("setPatientPhoto leaves neither the photo nor the files row behind", async () => {
+    const patient = await api.createPatient({ name: "Pedro Soto" });
+    failNextStoreSave();
+    await expect(api.setPatientPhoto(patient.id, pngFile())).rejects.toThrow("quota exceeded");
+    expect((await api.getPatient(patient.id)).photo_path).toBeNull();
+    expect(await api.listAllFiles()).toEqual([]);
+  });
+
+  it("importFiles keeps none of the batch", async () => {
+    const patient = await api.createPatient({ name: "Pedro Soto" });
+    failNextStoreSave();
+    const files = [new File(["a"], "a.txt", { type: "text/plain" }), new File(["b"], "b.txt", { type: "text/plain" })];
+    await expect(api.importFiles(patient.id, files)).rejects.toThrow("quota exceeded");
+    expect(await api.listAllFiles()).toEqual([]);
+  });
+
+  it("createMentalExam adds no exam and the next write still goes through", async () => {
+    const patient = await api.createPatient({ name: "Pedro Soto" });
+    failNextStoreSave();
+    await expect(api.createMentalExam(patient.id, { fecha: "2025-03-01" })).rejects.toThrow("quota exceeded");
+    expect(await api.listPatientFiles(patient.id)).toEqual([]);
+    const { file } = await api.createMentalExam(patient.id, { fecha: "2025-03-01" });
+    expect((await api.listPatientFiles(patient.id)).map((f) => f.id)).toEqual([file.id]);
+  });
+});
+
+describe("store.json busy", () => {
+  it("retries a save that finds the file locked and succeeds once it frees up", async () => {
+    server.storeStatuses = [503, 503, 200];
+    const patient = await api.createPatient({ name: "Inés Mora" });
+    expect(server.calls.filter((c) => c.url === "/__naju_store" && c.method === "POST")).toHaveLength(3);
+    expect((server.store as any).patients.map((p: { id: string }) => p.id)).toEqual([patient.id]);
+    expect(api.hasPendingStoreWrite()).toBe(false);
+  });
+
+  it("keeps the change and resyncs in the background when the lock outlasts every retry", async () => {
+    vi.useFakeTimers();
+    server.storeStatuses = [503];
+    const pending = api.createPatient({ name: "Inés Mora" });
+    await vi.advanceTimersByTimeAsync(5_000);
+    const patient = await pending;
+    expect(api.hasPendingStoreWrite()).toBe(true);
+    expect((await api.listPatients()).map((p) => p.id)).toEqual([patient.id]);
+
+    server.storeStatuses = [200];
+    await vi.advanceTimersByTimeAsync(15_000);
+    expect(api.hasPendingStoreWrite()).toBe(false);
+    expect((server.store as any).patients.map((p: { id: string }) => p.id)).toEqual([patient.id]);
+  });
+});
+
+describe("minors", () => {
+  // Midday UTC is the same calendar day in any clinic time zone within ±11 h.
+  beforeEach(() => {
+    vi.useFakeTimers({ toFake: ["Date"] });
+    vi.setSystemTime(new Date("2026-03-15T12:00:00Z"));
+  });
+
+  it("turns adult on the 18th birthday, not the day after", async () => {
+    const adult = await api.createPatient({ name: "Sofía Díaz", birth_date: "2008-03-15" });
+    expect(adult).toMatchObject({ age: 18, is_minor: false });
+    const minor = await api.createPatient({ name: "Tomás Díaz", birth_date: "2008-03-16", emergency_contact: "Madre 3001234567" });
+    expect(minor).toMatchObject({ age: 17, is_minor: true });
+  });
+
+  it("has no flag without a birth date", async () => {
+    expect(await api.createPatient({ name: "Sin Fecha" })).toMatchObject({ age: null, is_minor: null });
+  });
+
+  it("requires an emergency contact for a minor on create and update", async () => {
+    await expect(api.createPatient({ name: "Tomás Díaz", birth_date: "2008-03-16" })).rejects.toThrow(t("emergency_contact_required"));
+    await expect(api.createPatient({ name: "Tomás Díaz", birth_date: "2008-03-16", emergency_contact: "  " })).rejects.toThrow(t("emergency_contact_required"));
+    const adult = await api.createPatient({ name: "Sofía Díaz", birth_date: "2008-03-15" });
+    await expect(api.updatePatient(adult.id, { name: "Sofía Díaz", birth_date: "2008-03-16" })).rejects.toThrow(t("emergency_contact_required"));
+    expect(await api.getPatient(adult.id)).toMatchObject({ birth_date: "2008-03-15", is_minor: false });
+  });
+});
+
+describe("appointment overlap", () => {
+  let patientId: string;
+
+  beforeEach(async () => {
+    patientId = (await api.createPatient({ name: "Carla Vega" })).id;
+    await api.createAppointment({ patient_id: patientId, title: "Control", start_iso: "2026-05-04T14:00:00.000Z", end_iso: "2026-05-04T15:00:00.000Z" });
+  });
+
+  function book(start_iso: string, end_iso: string, allowOverlap?: boolean) {
+    return api.createAppointment({ patient_id: patientId, title: "Nueva", start_iso, end_iso }, allowOverlap);
+  }
+
+  it("rejects the exact same slot and names the clash", async () => {
+    await expect(book("2026-05-04T14:00:00.000Z", "2026-05-04T15:00:00.000Z")).rejects.toThrow(/Control/);
+  });
+
+  it("rejects a partial overlap at either end", async () => {
+    await expect(book("2026-05-04T13:30:00.000Z", "2026-05-04T14:30:00.000Z")).rejects.toThrow(/Control/);
+    await expect(book("2026-05-04T14:45:00.000Z", "2026-05-04T15:30:00.000Z")).rejects.toThrow(/Control/);
+    expect(await api.listAppointments()).toHaveLength(1);
+  });
+
+  it("accepts appointments that only touch", async () => {
+    await book("2026-05-04T13:00:00.000Z", "2026-05-04T14:00:00.000Z");
+    await book("2026-05-04T15:00:00.000Z", "2026-05-04T16:00:00.000Z");
+    expect(await api.listAppointments()).toHaveLength(3);
+  });
+
+  it("books over a clash only when allowOverlap is passed", async () => {
+    await book("2026-05-04T14:00:00.000Z", "2026-05-04T15:00:00.000Z", true);
+    expect(await api.listAppointments()).toHaveLength(2);
+  });
+
+  it("checks a moved appointment against the others but not itself", async () => {
+    const later = await book("2026-05-04T16:00:00.000Z", "2026-05-04T17:00:00.000Z");
+    await expect(api.updateAppointment(later.id, { start_iso: "2026-05-04T14:30:00.000Z", end_iso: "2026-05-04T15:30:00.000Z" })).rejects.toThrow(/Control/);
+    const moved = await api.updateAppointment(later.id, { start_iso: "2026-05-04T16:30:00.000Z", end_iso: "2026-05-04T17:30:00.000Z" });
+    expect(moved.start_iso).toBe("2026-05-04T16:30:00.000Z");
+  });
+});
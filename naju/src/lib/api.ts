@@ -1,11 +1,22 @@
+import { type AuditAction, type CommandTiming, audit, command, flushLog, flushLogOnExit, isReadOnlyMode, log, recordTiming, setAuditPatientResolver, setReadOnlyMode, timingStats } from "./log";
+import { LANGS, type Lang, getLang, isMessageId, setLang, t } from "./messages";
+import { levenshtein, looksLikeMojibake, normText, repairMojibake, stripAccents } from "./text";
+import { decryptBytes, deriveKey, encryptBytes, randomBytes } from "./crypto";
+import { accessEventsToCsv, appointmentsToIcs, downloadDataUrl, downloadTextFile } from "./export";
+import { code128B } from "./barcode";
+import { type CsvDelimiter, decodeCsvBytes, detectDelimiter, parseCsv } from "./csv";
+import { PDF_PAGE_HEIGHT, PdfPage } from "./pdf";
+
 export type Patient = {
   id: string;
+  patient_code: number; // sequential, for saying "paciente 0423" aloud; see formatPatientCode
   name: string;
   doc_type: string | null;
   doc_number: string | null;
   insurer: string | null;
   birth_date: string | null;
   sex: string | null;
+  gender_identity: string | null; // one of settings.gender_identities; `sex` stays the clinical field
   phone: string | null;
   email: string | null;
   address: string | null;
@@ -13,6 +24,18 @@ export type Patient = {
   notes: string | null;
   photo_path: string | null;
   drive_folder_id?: string | null;
+  contact_preference: ContactPreference | null;
+  consent_data: boolean;
+  consent_contact: boolean;
+  consent_at: string | null; // last time a consent flag went from false to true
+  archived_at: string | null; // archived patients are hidden from lists but keep all their data
+  status: string; // one of settings.patient_statuses
+  tags: string[];
+  provider_id: number | null; // assigned clinician (Store.providers); null while unassigned
+  age?: number | null; // derived by rowToPatient on the way out, never stored
+  is_minor?: boolean | null;
+  intake_file_id?: number; // only on createPatient's result, when settings.auto_create_intake made one
+  search_norm?: string; // normText(name + insurer), kept in sync on every write
   created_at: string;
   updated_at: string;
 };
@@ -24,21 +47,56 @@ export type PatientInput = {
   insurer?: string | null;
   birth_date?: string | null;
   sex?: string | null;
+  gender_identity?: string | null;
   phone?: string | null;
   email?: string | null;
   address?: string | null;
   emergency_contact?: string | null;
   notes?: string | null;
+  contact_preference?: ContactPreference | null;
+  consent_data?: boolean;
+  consent_contact?: boolean;
+  status?: string;
+};
+
+export const CONTACT_PREFERENCES = ["phone", "email", "none"] as const;
+
+export type ContactPreference = (typeof CONTACT_PREFERENCES)[number];
+
+export const CUSTOM_FIELD_TYPES = ["text", "number", "date", "bool"] as const;
+
+export type CustomFieldType = (typeof CUSTOM_FIELD_TYPES)[number];
+
+export type CustomFieldDef = {
+  key: string; // snake_case, stable; the label can change freely
+  label: string;
+  type: CustomFieldType;
+  required: boolean;
 };
 
+export type CustomFieldValue = string | number | boolean;
+
+export type CustomValue = { patient_id: string; key: string; value: CustomFieldValue };
+
+export const FILE_KINDS = ["attachment", "exam", "note", "photo"] as const;
+
+export type FileKind = (typeof FILE_KINDS)[number];
+
 export type PatientFile = {
   id: number;
   patient_id: string;
-  kind: "attachment" | "exam" | "note" | "photo";
+  kind: FileKind;
   filename: string;
   created_at: string;
   path: string;
   meta_json: string | null;
+  sha256?: string | null;
+  archived?: boolean; // hidden from the working list, never deleted
+  sort_order?: number; // manual position set by reorderFiles
+  pinned?: boolean; // listed before everything else; at most MAX_PINNED_FILES per patient
+  appointment_id?: number; // the visit it belongs to, always an appointment of the same patient
+  label?: string; // free-form chip text ("urgente"), on top of `kind`
+  color?: string; // chip color, "#rgb" or "#rrggbb"
 };
 
 export type Appointment = {
@@ -48,6 +106,7 @@ export type Appointment = {
   start_iso: string; // ISO string in UTC (Date.toISOString())
   end_iso: string;   // ISO string in UTC (Date.toISOString())
   notes: string | null;
+  needs_followup?: boolean; // the patient should get a new appointment after this one
   created_at: string;
   updated_at: string;
 };
@@ -88,44 +147,243 @@ export type ErrorReportInput = {
 };
 
 
+export type TrashedFile = {
+  file: PatientFile; // on-disk assets: `path` points into the patient's .trash folder
+  original_path: string;
+  deleted_at: string;
+};
+
+export type BackupSettings = {
+  auto_backup_enabled: boolean;
+  auto_backup_dir: string; // empty = patients/backups inside the install
+  auto_backup_keep_count: number;
+};
+
+export type Settings = BackupSettings & {
+  language: Lang;
+  last_backup_at: string | null;
+  folder_markers: boolean;
+  import_filename_template: string; // empty = keep the original filename
+  auto_create_intake: boolean; // new patients start with a blank "admision" exam
+  timezone: string | null; // clinic's IANA zone for calendar dates; null = this machine's zone
+  gender_identities: string[]; // options offered for Patient.gender_identity
+  working_hours: WorkingHours; // default window for findFreeSlots
+  duplicate_name_threshold: number; // 0..1: share of a name that may differ for duplicateReport to pair it
+  auto_archive_after_days: number | null; // null = retention disabled
+  max_files_warning: number; // imports past this many files per patient come back with a warning
+  encryption_salt: string | null; // base64 PBKDF2 salt, set on first unlock
+  encryption_check: string | null; // known text encrypted with the key, to reject a wrong passphrase
+  patient_statuses: string[]; // workflow states offered for patients; always includes DEFAULT_PATIENT_STATUS
+  allowed_import_extensions: string[]; // lowercase, no dot; empty = any file can be imported
+  compress_attachments: boolean; // gzip imported attachments that shrink; see COMPRESSED_EXTENSIONS
+};
+
+const DEFAULT_PATIENT_STATUS = "activo";
+
+export type WorkingHours = { start: string; end: string }; // "HH:MM", clinic time
+
+const DEFAULT_SETTINGS: Settings = {
+  language: "es",
+  auto_backup_enabled: false,
+  auto_backup_dir: "",
+  auto_backup_keep_count: 7,
+  last_backup_at: null,
+  folder_markers: false,
+  import_filename_template: "",
+  auto_create_intake: false,
+  timezone: null,
+  duplicate_name_threshold: 0.2,
+  gender_identities: ["mujer", "hombre", "no binario", "otra", "prefiere no decir"],
+  working_hours: { start: "08:00", end: "18:00" },
+  auto_archive_after_days: null,
+  max_files_warning: 500,
+  encryption_salt: null,
+  encryption_check: null,
+  patient_statuses: [DEFAULT_PATIENT_STATUS, "en admisión", "en tratamiento", "alta"],
+  allowed_import_extensions: [],
+  compress_attachments: false,
+};
+
+// Snapshot of a patient as it was before an edit; undoLastEdit() puts it back.
+export type PatientRevision = {
+  patient_id: string;
+  saved_at: string;
+  cmd: string;
+  reason?: string; // why, when the command asks for it (renamePatient)
+  snapshot: Patient;
+};
+
+// `related_id` is the `relation` of `patient_id`: { patient_id: hijo, related_id: madre, relation: "madre" }.
+export type PatientRelation = {
+  patient_id: string;
+  related_id: string;
+  relation: string;
+  created_at: string;
+};
+
+// A pointer to material kept outside NAJU (network folder, old records); nothing is copied.
+export type PatientLink = {
+  id: number;
+  patient_id: string;
+  label: string;
+  target_path: string;
+  created_at: string;
+};
+
+// A clinician patients can be assigned to. Inactive providers keep their history but take no new patients.
+export type Provider = {
+  id: number;
+  name: string;
+  specialty: string | null;
+  active: boolean;
+  created_at: string;
+  updated_at: string;
+};
+
 type Store = {
   patients: Patient[];
   files: PatientFile[];
+  trash: TrashedFile[];
+  insurers: string[];
   appointments: Appointment[];
   errorReports: ErrorReport[];
+  customFields: CustomFieldDef[];
+  customValues: CustomValue[];
+  patientHistory: PatientRevision[];
+  patientRelations: PatientRelation[];
+  patientLinks: PatientLink[];
+  providers: Provider[];
   nextFileId: number;
   nextAppointmentId: number;
   nextErrorId: number;
+  nextLinkId: number;
+  nextProviderId: number;
+  nextPatientCode: number;
+  settings: Settings;
+  savedAt: string | null; // stamped by persistStore; decides whether a dirty browser copy is newer than store.json
 };
 
 const STORAGE_KEY = "naju_web_store";
+// Set while the localStorage copy is ahead of /__naju_store (a POST was pending or failed).
+const DIRTY_KEY = "naju_store_dirty";
 // Dev-only endpoint (served by Vite middleware) that persists the store inside the project folder.
 // Falls back to localStorage automatically when the endpoint is not available.
 const FILE_STORE_ENDPOINT = "/__naju_store";
+const BACKUP_ENDPOINT = "/__naju_backup";
+const REVEAL_ENDPOINT = "/__naju_reveal";
+const DISK_ENDPOINT = "/__naju_disk";
+const MARKER_ENDPOINT = "/__naju_marker";
+const REPAIR_FOLDERS_ENDPOINT = "/__naju_repair_folders";
+const AUDIT_ENDPOINT = "/__naju_audit";
+const PATHS_ENDPOINT = "/__naju_paths";
+const SCAN_ASSETS_ENDPOINT = "/__naju_scan_assets";
+const USAGE_ENDPOINT = "/__naju_usage";
+const STORAGE_ENDPOINT = "/__naju_storage";
+const EXPORT_FILE_ENDPOINT = "/__naju_export_file";
+const FETCH_URL_ENDPOINT = "/__naju_fetch_url";
+const OPEN_PATH_ENDPOINT = "/__naju_open_path";
+const RECOVER_STORE_ENDPOINT = "/__naju_recover_store";
+const TRASH_ENDPOINT = "/__naju_trash";
+
+// The error of a failed local-server call; every endpoint answers with a message id (see sendJson in vite.config.ts).
+export function serverError(j: any): Error {
+  const code = String(j?.error ?? "");
+  if (code === "server_error" || !isMessageId(code)) return new Error(t("server_error", { detail: String(j?.detail ?? code) }));
+  return new Error(t(code, { detail: String(j?.detail ?? "") }));
+}
 
 let cachedStore: Store | null = null;
+let storeWritesInFlight = 0;
+// Set when the last load failed in a way recoverStore() can fix; see getStoreProblem().
+let storeProblem: StoreProblem | null = null;
+
+function patientSearchNorm(p: Pick<Patient, "name" | "insurer">) {
+  return normText([p.name, p.insurer].filter(Boolean).join(" "));
+}
+
+// Backfills columns added after a patient was first written (search_norm, consent flags).
+function normalizePatient(p: Patient): Patient {
+  const { age: _age, is_minor: _isMinor, intake_file_id: _intake, ...stored } = p;
+  return {
+    ...stored,
+    contact_preference: CONTACT_PREFERENCES.includes(p.contact_preference as ContactPreference) ? p.contact_preference : null,
+    consent_data: p.consent_data === true,
+    consent_contact: p.consent_contact === true,
+    consent_at: p.consent_at ?? null,
+    archived_at: p.archived_at ?? null,
+    status: typeof p.status === "string" && p.status ? p.status : DEFAULT_PATIENT_STATUS,
+    gender_identity: p.gender_identity ?? null,
+    tags: Array.isArray(p.tags) ? p.tags : [],
+    patient_code: typeof p.patient_code === "number" ? p.patient_code : 0, // 0 = not assigned yet, see assignPatientCodes
+    provider_id: typeof p.provider_id === "number" ? p.provider_id : null,
+    search_norm: typeof p.search_norm === "string" ? p.search_norm : patientSearchNorm(p),
+  };
+}
+
+function normalizeSettings(input: any): Settings {
+  const settings: Settings = { ...DEFAULT_SETTINGS, ...(input && typeof input === "object" ? input : {}) };
+  // Stores from before language lived here: adopt the one the UI was already using.
+  if (!LANGS.includes(input?.language)) settings.language = getLang();
+  return settings;
+}
+
+// Patients from before patient_code get one in creation order, after the highest code in use.
+// Returns the next free code.
+function assignPatientCodes(patients: Patient[], next: number) {
+  let counter = patients.reduce((max, p) => Math.max(max, p.patient_code + 1), next);
+  const missing = patients.filter((p) => !p.patient_code).sort((a, b) => a.created_at.localeCompare(b.created_at));
+  for (const p of missing) p.patient_code = counter++;
+  return counter;
+}
 
 function normalizeStore(input: any): Store {
+  const patients: Patient[] = (Array.isArray(input?.patients) ? (input.patients as Patient[]) : []).map(normalizePatient);
+  const nextPatientCode = assignPatientCodes(patients, typeof input?.nextPatientCode === "number" ? input.nextPatientCode : 1);
   return {
-    patients: Array.isArray(input?.patients) ? (input.patients as Patient[]) : [],
+    patients,
     files: Array.isArray(input?.files) ? (input.files as PatientFile[]) : [],
+    trash: Array.isArray(input?.trash) ? (input.trash as TrashedFile[]) : [],
+    // First load of an older store: seed the lookup from the insurers already typed on patients.
+    insurers: Array.isArray(input?.insurers)
+      ? (input.insurers as string[])
+      : patients.reduce<string[]>((acc, p) => addInsurerName(acc, p.insurer), []),
     appointments: Array.isArray(input?.appointments) ? (input.appointments as Appointment[]) : [],
     errorReports: Array.isArray(input?.errorReports) ? (input.errorReports as ErrorReport[]) : [],
+    customFields: Array.isArray(input?.customFields) ? (input.customFields as CustomFieldDef[]) : [],
+    customValues: Array.isArray(input?.customValues) ? (input.customValues as CustomValue[]) : [],
+    patientHistory: Array.isArray(input?.patientHistory) ? (input.patientHistory as PatientRevision[]) : [],
+    patientRelations: Array.isArray(input?.patientRelations) ? (input.patientRelations as PatientRelation[]) : [],
+    patientLinks: Array.isArray(input?.patientLinks) ? (input.patientLinks as PatientLink[]) : [],
+    providers: Array.isArray(input?.providers) ? (input.providers as Provider[]) : [],
     nextFileId: typeof input?.nextFileId === "number" ? input.nextFileId : 1,
     nextAppointmentId: typeof input?.nextAppointmentId === "number" ? input.nextAppointmentId : 1,
     nextErrorId: typeof input?.nextErrorId === "number" ? input.nextErrorId : 1,
+    nextLinkId: typeof input?.nextLinkId === "number" ? input.nextLinkId : 1,
+    nextProviderId: typeof input?.nextProviderId === "number" ? input.nextProviderId : 1,
+    nextPatientCode,
+    settings: normalizeSettings(input?.settings),
+    savedAt: typeof input?.savedAt === "string" ? input.savedAt : null,
   };
 }
 
+function insurerKey(name: string) {
+  return name.trim().toLowerCase();
+}
+
+// Adds `name` unless a case-insensitive variant is already known.
+function addInsurerName(list: string[], name: string | null | undefined) {
+  const clean = (name ?? "").trim();
+  if (clean && !list.some((n) => insurerKey(n) === insurerKey(clean))) list.push(clean);
+  return list;
+}
+
 function loadStoreFromLocalStorage(): Store {
   const raw = localStorage.getItem(STORAGE_KEY);
-  if (!raw) {
-    return { patients: [], files: [], appointments: [], errorReports: [], nextFileId: 1, nextAppointmentId: 1, nextErrorId: 1 };
-  }
+  if (!raw) return normalizeStore({});
   try {
     return normalizeStore(JSON.parse(raw));
   } catch {
-    return { patients: [], files: [], appointments: [], errorReports: [], nextFileId: 1, nextAppointmentId: 1, nextErrorId: 1 };
+    return normalizeStore({});
   }
 }
 
@@ -133,91 +391,858 @@ function saveStoreToLocalStorage(store: Store) {
   localStorage.setItem(STORAGE_KEY, JSON.stringify(store));
 }
 
-async function loadStoreAsync(): Promise<Store> {
-  // Try dev file-store first; fallback to localStorage.
+// store.json as the dev server has it, or null (no server, or a problem recorded in storeProblem).
+async function fetchServerStore(): Promise<Store | null> {
+  // Lets the server look for recovery backups in the configured folder too, should store.json be damaged.
+  const backupDir = localStorage.getItem(STORAGE_KEY) ? loadStoreFromLocalStorage().settings.auto_backup_dir : "";
+  const url = backupDir ? `${FILE_STORE_ENDPOINT}?backupDir=${encodeURIComponent(backupDir)}` : FILE_STORE_ENDPOINT;
   try {
-    const res = await fetch(FILE_STORE_ENDPOINT, { cache: "no-store" });
-    if (res.ok) {
-      const parsed = await res.json();
-      const store = normalizeStore(parsed);
-      saveStoreToLocalStorage(store); // mirror for backup
-      return store;
+    for (let attempt = 0; ; attempt++) {
+      const res = await fetch(url, { cache: "no-store" });
+      if (res.ok) return normalizeStore(await res.json());
+      // A lock left by a backup or antivirus usually clears within a second: wait it out first.
+      if (res.status === 503 && attempt < STORE_RETRY_DELAYS_MS.length) {
+        await sleep(STORE_RETRY_DELAYS_MS[attempt]);
+        continue;
+      }
+      const j = await res.json().catch(() => null);
+      if (j?.problem) storeProblem = describeStoreProblem(j);
+      return null;
     }
   } catch {
-    // ignore
+    return null;
+  }
+}
+
+// Stores saved before savedAt existed count as oldest.
+function isNewerStore(a: Store, b: Store) {
+  return Boolean(a.savedAt) && (!b.savedAt || a.savedAt! > b.savedAt);
+}
+
+async function loadStoreAsync(): Promise<Store> {
+  // Try dev file-store first; fallback to localStorage.
+  storeProblem = null;
+  const server = await fetchServerStore();
+  // The last session closed with an unsynced write. The browser copy is pushed back only over a
+  // store.json that loaded fine and isn't newer (a restore or another tab may have saved since).
+  if (server && localStorage.getItem(DIRTY_KEY) && localStorage.getItem(STORAGE_KEY)) {
+    const local = loadStoreFromLocalStorage();
+    if (!isNewerStore(server, local)) {
+      await persistStore(local);
+      return local;
+    }
+    localStorage.removeItem(DIRTY_KEY);
   }
+  if (server) {
+    saveStoreToLocalStorage(server); // mirror for backup
+    return server;
+  }
+  // Quietly falling back to the browser copy would hide the damage and overwrite store.json on the next save.
+  if (storeProblem) throw new Error(t(storeProblem.kind === "locked" ? "store_busy" : "store_damaged", { reason: storeProblem.detail }));
   return loadStoreFromLocalStorage();
 }
 
+export type StoreProblem = {
+  kind: "locked" | "malformed" | "unreadable";
+  detail: string;
+  backup_path: string | null; // newest backup whose store.json parses, in patients/backups or settings.auto_backup_dir
+  has_local_copy: boolean; // the browser mirror, usually newer than any backup
+  suggestion: "retry" | "restore_local_copy" | "restore_backup" | "none";
+};
+
+function describeStoreProblem(j: any): StoreProblem {
+  const kind: StoreProblem["kind"] = j.problem === "locked" || j.problem === "malformed" ? j.problem : "unreadable";
+  const backup = typeof j.backup === "string" ? j.backup : null;
+  const hasLocal = Boolean(localStorage.getItem(STORAGE_KEY));
+  return {
+    kind,
+    detail: String(j.detail ?? ""),
+    backup_path: backup,
+    has_local_copy: hasLocal,
+    suggestion: kind === "locked" ? "retry" : hasLocal ? "restore_local_copy" : backup ? "restore_backup" : "none",
+  };
+}
+
+/** Why the store failed to load, when it did; the UI uses `suggestion` to offer recoverStore(). */
+export function getStoreProblem(): StoreProblem | null {
+  return storeProblem;
+}
+
 async function getStore(): Promise<Store> {
   if (cachedStore) return cachedStore;
+  const started = performance.now();
   cachedStore = await loadStoreAsync();
+  // Shows up in perfStats() as its own line: the cost of (re)loading store.json, separate from the command that triggered it.
+  recordTiming("load_store", performance.now() - started);
+  setLang(cachedStore.settings.language);
   return cachedStore;
 }
 
+// Backoff between attempts when the server reports store.json as busy (503).
+const STORE_RETRY_DELAYS_MS = [100, 250, 500, 1000];
+// Before re-sending a change that found store.json busy after all of the above.
+const STORE_RESYNC_DELAY_MS = 15_000;
+
+function sleep(ms: number) {
+  return new Promise((resolve) => setTimeout(resolve, ms));
+}
+
+let pendingResync: ReturnType<typeof setTimeout> | null = null;
+
+/**
+ * True while the latest change is saved in the browser but store.json was busy after every retry.
+ * The change is not lost: it is re-sent in the background (and on next start), so the UI should
+ * say "saved, syncing" rather than offer to redo it.
+ */
+export function hasPendingStoreWrite(): boolean {
+  return pendingResync !== null;
+}
+
 async function persistStore(store: Store) {
   cachedStore = store;
+  store.savedAt = nowIso();
   saveStoreToLocalStorage(store);
+  localStorage.setItem(DIRTY_KEY, "1");
+  storeWritesInFlight++;
+  let ok = false;
+  let busy = false;
+  try {
+    const body = JSON.stringify(store);
+    for (let attempt = 0; ; attempt++) {
+      const res = await fetch(FILE_STORE_ENDPOINT, { method: "POST", headers: { "Content-Type": "application/json" }, body });
+      ok = res.ok;
+      busy = res.status === 503;
+      if (!busy || attempt >= STORE_RETRY_DELAYS_MS.length) break;
+      await sleep(STORE_RETRY_DELAYS_MS[attempt]);
+    }
+  } catch {
+    // ignore (localStorage already persisted)
+  } finally {
+    storeWritesInFlight--;
+  }
+  if (ok && !storeWritesInFlight) localStorage.removeItem(DIRTY_KEY);
+  if (ok && pendingResync) {
+    clearTimeout(pendingResync);
+    pendingResync = null;
+  }
+  // Still busy after every retry. Throwing would invite the caller to redo a change that is already
+  // in the store, so it stays saved-but-pending and goes out again with the next write or the resync.
+  if (busy && !pendingResync) {
+    pendingResync = setTimeout(() => {
+      pendingResync = null;
+      if (cachedStore && localStorage.getItem(DIRTY_KEY)) void persistStore(cachedStore);
+    }, STORE_RESYNC_DELAY_MS);
+  }
+}
+
+/**
+ * Exit hook (pagehide): ships buffered log lines and, if a store write is still in flight,
+ * re-sends the store as a beacon. Beacons are size-limited, so the dirty flag stays set and
+ * the next startup re-pushes the localStorage copy either way. A backup already posted
+ * finishes server-side; its last_backup_at may be lost, which only means an extra backup.
+ */
+export function flushOnExit() {
+  flushLogOnExit();
+  if (!storeWritesInFlight || !cachedStore) return;
+  try {
+    navigator.sendBeacon(FILE_STORE_ENDPOINT, new Blob([JSON.stringify(cachedStore)], { type: "application/json" }));
+  } catch {
+    // ignore (recovered on next start)
+  }
+}
+
+export { type CommandTiming, getLogPath } from "./log";
+
+// Best-effort: refreshes the patient's folder marker when enabled. Failures never block the edit.
+async function refreshFolderMarker(store: Store, patient: Patient) {
+  if (!store.settings.folder_markers) return;
   try {
-    await fetch(FILE_STORE_ENDPOINT, {
+    await fetch(MARKER_ENDPOINT, {
       method: "POST",
       headers: { "Content-Type": "application/json" },
-      body: JSON.stringify(store),
+      body: JSON.stringify({ patientId: patient.id, name: patient.name, doc: [patient.doc_type, patient.doc_number].filter(Boolean).join(" ") }),
     });
   } catch {
-    // ignore (localStorage already persisted)
+    // ignore (no dev server)
+  }
+}
+
+export const setFolderMarkers = command("set_folder_markers", async (enabled: boolean): Promise<boolean> => {
+  return (await setSetting("folder_markers", enabled)) as boolean;
+});
+
+type NewFile = Omit<PatientFile, "id">;
+
+/**
+ * Runs `mutate` and persists the result as one unit. `mutate` is synchronous so no other command can
+ * interleave; do async work (reading files, hashing) before calling. If persisting fails, the store is
+ * restored in place and `onRollback` undoes side effects made beforehand.
+ */
+async function commit<T>(mutate: (store: Store) => T, onRollback?: () => Promise<void> | void): Promise<T> {
+  const store = await getStore();
+  const snapshot = JSON.stringify(store);
+  try {
+    const out = mutate(store);
+    await persistStore(store);
+    return out;
+  } catch (e) {
+    Object.assign(store, normalizeStore(JSON.parse(snapshot)));
+    saveStoreToLocalStorage(store);
+    await onRollback?.();
+    throw e;
+  }
+}
+
+function addFiles(store: Store, drafts: NewFile[]): PatientFile[] {
+  const added = drafts.map((d) => ({ id: store.nextFileId++, ...d }));
+  store.files.unshift(...added.slice().reverse());
+  return added;
+}
+
+export type FreeSpace = {
+  free_bytes: number;
+  total_bytes: number | null;
+  source: "disk" | "browser";
+};
+
+// Headroom kept free on top of what an operation needs.
+const FREE_SPACE_MARGIN_BYTES = 50 * 1024 * 1024;
+
+async function readFreeSpace(): Promise<FreeSpace | null> {
+  try {
+    const res = await fetch(DISK_ENDPOINT, { cache: "no-store" });
+    if (res.ok) {
+      const j = await res.json();
+      if (j?.ok) return { free_bytes: Number(j.free_bytes), total_bytes: Number(j.total_bytes), source: "disk" };
+    }
+  } catch {
+    // fall through to the browser quota
+  }
+  try {
+    const est = await navigator.storage?.estimate?.();
+    if (est?.quota !== undefined) {
+      return { free_bytes: Math.max(0, est.quota - (est.usage ?? 0)), total_bytes: est.quota, source: "browser" };
+    }
+  } catch {
+    // ignore
+  }
+  return null;
+}
+
+// Throws before any write when free space can be measured and is below `requiredBytes` + margin.
+async function checkFreeSpace(requiredBytes: number) {
+  const space = await readFreeSpace();
+  if (!space) return;
+  if (space.free_bytes < requiredBytes + FREE_SPACE_MARGIN_BYTES) {
+    throw new Error(t("insufficient_space", { mb: Math.ceil((requiredBytes + FREE_SPACE_MARGIN_BYTES) / 1048576) }));
+  }
+}
+
+export const getFreeSpace = command("get_free_space", async (): Promise<FreeSpace | null> => {
+  return readFreeSpace();
+});
+
+export type StorageStatus = {
+  healthy: boolean;
+  path: string | null; // data folder; null when the store only lives in the browser
+  writable: boolean;
+  store_reachable: boolean;
+  free_bytes: number | null;
+  reason: string | null; // why it's unhealthy, in plain words
+  source: "disk" | "browser";
+};
+
+// Below this, the next few imports or backups are likely to fail.
+const LOW_STORAGE_BYTES = 200 * 1024 * 1024;
+
+function probeLocalStorage() {
+  const key = `${STORAGE_KEY}_probe`;
+  try {
+    localStorage.setItem(key, "ok");
+    localStorage.removeItem(key);
+    return true;
+  } catch {
+    return false;
+  }
+}
+
+/** Checks that the data folder takes writes and has room. Meant for startup, before the user enters data. */
+export const validateStorage = command("validate_storage", async (): Promise<StorageStatus> => {
+  let status: StorageStatus | null = null;
+  try {
+    const res = await fetch(STORAGE_ENDPOINT, { cache: "no-store" });
+    if (res.ok) {
+      const j = await res.json();
+      if (j?.ok) {
+        status = {
+          healthy: false,
+          path: String(j.path),
+          writable: j.writable === true,
+          store_reachable: j.store_reachable === true,
+          free_bytes: typeof j.free_bytes === "number" ? j.free_bytes : null,
+          // The server sends a message id, or the raw OS error code when the probe itself failed.
+          reason: j.reason ? t("storage_failed", { path: String(j.path), reason: isMessageId(String(j.reason)) ? t(j.reason) : String(j.reason) }) : null,
+          source: "disk",
+        };
+      }
+    }
+  } catch {
+    // no dev server: check the browser storage instead
+  }
+  if (!status) {
+    const writable = probeLocalStorage();
+    const space = await readFreeSpace();
+    status = {
+      healthy: false,
+      path: null,
+      writable,
+      store_reachable: writable,
+      free_bytes: space?.free_bytes ?? null,
+      reason: writable ? null : t("storage_not_writable"),
+      source: "browser",
+    };
   }
+  if (!status.reason && status.free_bytes !== null && status.free_bytes < LOW_STORAGE_BYTES) {
+    status.reason = t("storage_low", { mb: Math.floor(status.free_bytes / 1048576) });
+  }
+  status.healthy = status.writable && status.store_reachable && !status.reason;
+  return status;
+});
+
+export type SettingKey = keyof Settings;
+
+// Per-key validation for setSetting; returns the cleaned value or throws. last_backup_at is
+// written by runBackup only, so it has no entry and can't be set.
+const SETTING_VALIDATORS: { [K in SettingKey]?: (value: unknown) => Settings[K] } = {
+  language: (v) => {
+    if (!LANGS.includes(v as Lang)) throw new Error(t("unsupported_language", { lang: String(v) }));
+    return v as Lang;
+  },
+  auto_backup_enabled: (v) => settingBool("auto_backup_enabled", v),
+  auto_backup_dir: (v) => settingString("auto_backup_dir", v),
+  auto_backup_keep_count: (v) => settingInt("auto_backup_keep_count", v, 1),
+  folder_markers: (v) => settingBool("folder_markers", v),
+  auto_create_intake: (v) => settingBool("auto_create_intake", v),
+  compress_attachments: (v) => settingBool("compress_attachments", v),
+  gender_identities: (v) => {
+    if (!Array.isArray(v)) throw new Error(t("invalid_field_value", { field: "gender_identities" }));
+    const list = v.map((x) => (typeof x === "string" ? x.trim() : "")).filter(Boolean);
+    if (list.length !== v.length || !list.length) throw new Error(t("invalid_field_value", { field: "gender_identities" }));
+    return list.filter((x, i) => list.indexOf(x) === i);
+  },
+  working_hours: (v) => validateWorkingHours(v),
+  duplicate_name_threshold: (v) => {
+    if (typeof v !== "number" || !(v >= 0 && v <= 1)) throw new Error(t("invalid_field_value", { field: "duplicate_name_threshold" }));
+    return v;
+  },
+  timezone: (v) => {
+    if (v === null || v === "") return null;
+    if (typeof v !== "string" || !isValidTimeZone(v)) throw new Error(t("invalid_timezone", { tz: String(v) }));
+    return v;
+  },
+  import_filename_template: (v) => {
+    const template = settingString("import_filename_template", v);
+    validateFilenameTemplate(template);
+    return template;
+  },
+  auto_archive_after_days: (v) => (v === null ? null : settingInt("auto_archive_after_days", v, 1)),
+  max_files_warning: (v) => settingInt("max_files_warning", v, 0),
+  patient_statuses: (v) => {
+    if (!Array.isArray(v)) throw new Error(t("invalid_field_value", { field: "patient_statuses" }));
+    const list = v.map((x) => (typeof x === "string" ? x.trim() : "")).filter(Boolean);
+    if (list.length !== v.length) throw new Error(t("invalid_field_value", { field: "patient_statuses" }));
+    return [DEFAULT_PATIENT_STATUS, ...list.filter((x, i) => x !== DEFAULT_PATIENT_STATUS && list.indexOf(x) === i)];
+  },
+  allowed_import_extensions: (v) => {
+    if (!Array.isArray(v)) throw new Error(t("invalid_field_value", { field: "allowed_import_extensions" }));
+    const list = v.map((x) => (typeof x === "string" ? x.trim().replace(/^\./, "").toLowerCase() : "")).filter(Boolean);
+    if (list.length !== v.length) throw new Error(t("invalid_field_value", { field: "allowed_import_extensions" }));
+    return list.filter((x, i) => list.indexOf(x) === i);
+  },
+};
+
+const HHMM_RE = /^([01]\d|2[0-3]):[0-5]\d$/;
+
+function validateWorkingHours(v: unknown): WorkingHours {
+  const wh = v as WorkingHours | null;
+  if (!wh || typeof wh !== "object" || !HHMM_RE.test(wh.start) || !HHMM_RE.test(wh.end) || wh.start >= wh.end) {
+    throw new Error(t("invalid_field_value", { field: "working_hours" }));
+  }
+  return { start: wh.start, end: wh.end };
+}
+
+function settingBool(key: SettingKey, v: unknown) {
+  if (typeof v !== "boolean") throw new Error(t("invalid_field_value", { field: key }));
+  return v;
+}
+
+function settingString(key: SettingKey, v: unknown) {
+  if (typeof v !== "string") throw new Error(t("invalid_field_value", { field: key }));
+  return v.trim();
+}
+
+function settingInt(key: SettingKey, v: unknown, min: number) {
+  if (typeof v !== "number" || !Number.isInteger(v) || v < min) throw new Error(t("invalid_field_value", { field: key }));
+  return v;
+}
+
+function isSettingKey(key: string): key is SettingKey {
+  return Object.prototype.hasOwnProperty.call(DEFAULT_SETTINGS, key);
+}
+
+export const getAllSettings = command("get_all_settings", async (): Promise<Settings> => {
+  return { ...(await getStore()).settings };
+});
+
+// Known keys always have a value: unset ones fall back to DEFAULT_SETTINGS.
+export const getSetting = command("get_setting", async (key: string): Promise<Settings[SettingKey]> => {
+  if (!isSettingKey(key)) throw new Error(t("unknown_setting", { key }));
+  return (await getStore()).settings[key] ?? DEFAULT_SETTINGS[key];
+});
+
+// Everything a saved setting changes outside the store: the UI language, and folder markers written
+// for every patient when they're turned on. Every way of saving settings ends here.
+async function applySettingEffects(store: Store, changed: Partial<Settings>) {
+  if (changed.language !== undefined) setLang(changed.language);
+  if (changed.folder_markers) for (const p of store.patients) await refreshFolderMarker(store, p);
 }
 
-function normQuery(q?: string) {
-  return (q ?? "").trim().toLowerCase();
+// Validates every entry first and saves them together, so one bad value changes nothing.
+// setSetting and the per-setting helpers (setMaxFilesWarning, ...) all go through here.
+async function saveSettings(values: Record<string, unknown>): Promise<Partial<Settings>> {
+  const clean: Partial<Settings> = {};
+  for (const [key, value] of Object.entries(values)) {
+    if (!isSettingKey(key)) throw new Error(t("unknown_setting", { key }));
+    const validate = SETTING_VALIDATORS[key] as ((v: unknown) => Settings[SettingKey]) | undefined;
+    if (!validate) throw new Error(t("setting_read_only", { key }));
+    (clean as Record<string, unknown>)[key] = validate(value);
+  }
+  const store = await getStore();
+  store.settings = { ...store.settings, ...clean };
+  await persistStore(store);
+  await applySettingEffects(store, clean);
+  return clean;
 }
 
+export const setSetting = command("set_setting", async (key: string, value: unknown): Promise<Settings[SettingKey]> => {
+  const clean = await saveSettings({ [key]: value });
+  return clean[key as SettingKey] as Settings[SettingKey];
+});
+
+export const getLanguage = command("get_language", async (): Promise<Lang> => {
+  return (await getStore()).settings.language;
+});
+
+export const setLanguage = command("set_language", async (lang: string): Promise<Lang> => {
+  return (await setSetting("language", lang)) as Lang;
+});
+
 function newId() {
   return typeof crypto !== "undefined" && "randomUUID" in crypto
     ? crypto.randomUUID()
     : `p_${Date.now()}_${Math.random().toString(16).slice(2)}`;
 }
 
+// Stored timestamps stay in UTC ("Z"): unambiguous across sites and they sort as plain strings.
+// The clinic's zone only decides calendar days and the times people read (see clinicIso).
 function nowIso() {
   return new Date().toISOString();
 }
 
-function readFileAsDataUrl(file: File): Promise<string> {
+function isValidTimeZone(tz: string) {
+  try {
+    new Intl.DateTimeFormat("en-US", { timeZone: tz });
+    return true;
+  } catch {
+    return false;
+  }
+}
+
+function clinicParts(d: Date) {
+  const timeZone = cachedStore?.settings.timezone ?? undefined;
+  const parts = new Intl.DateTimeFormat("en-US", {
+    timeZone,
+    year: "numeric",
+    month: "2-digit",
+    day: "2-digit",
+    hour: "2-digit",
+    minute: "2-digit",
+    second: "2-digit",
+    hour12: false,
+  }).formatToParts(d);
+  const get = (type: string) => Number(parts.find((p) => p.type === type)?.value ?? 0);
+  // Some engines print midnight as "24" with hour12: false.
+  return { year: get("year"), month: get("month"), day: get("day"), hour: get("hour") % 24, minute: get("minute"), second: get("second") };
+}
+
+// YYYY-MM-DD of the instant `d` on the clinic's calendar.
+function clinicDateIso(d = new Date()) {
+  const p = clinicParts(d);
+  return `${p.year}-${String(p.month).padStart(2, "0")}-${String(p.day).padStart(2, "0")}`;
+}
+
+// The instant at wall-clock `hhmm` on `date` (YYYY-MM-DD) in the clinic's zone. Two passes settle DST edges.
+function clinicInstant(date: string, hhmm: string): Date {
+  const [y, m, d] = date.split("-").map(Number);
+  const [h, mi] = hhmm.split(":").map(Number);
+  const wanted = Date.UTC(y, m - 1, d, h, mi);
+  let at = wanted;
+  for (let i = 0; i < 2; i++) {
+    const p = clinicParts(new Date(at));
+    at += wanted - Date.UTC(p.year, p.month - 1, p.day, p.hour, p.minute);
+  }
+  return new Date(at);
+}
+
+/** RFC 3339 in the clinic's zone with an explicit offset, e.g. "2024-05-01T09:30:00-05:00". */
+export function clinicIso(d = new Date()) {
+  const p = clinicParts(d);
+  const pad = (n: number) => String(n).padStart(2, "0");
+  const offset = Math.round((Date.UTC(p.year, p.month - 1, p.day, p.hour, p.minute, p.second) - Math.floor(d.getTime() / 1000) * 1000) / 60000);
+  const sign = offset < 0 ? "-" : "+";
+  const abs = Math.abs(offset);
+  return `${p.year}-${pad(p.month)}-${pad(p.day)}T${pad(p.hour)}:${pad(p.minute)}:${pad(p.second)}${sign}${pad(Math.floor(abs / 60))}:${pad(abs % 60)}`;
+}
+
+function readFileAsDataUrl(file: Blob): Promise<string> {
   return new Promise((resolve, reject) => {
     const reader = new FileReader();
-    reader.onerror = () => reject(new Error("No se pudo leer el archivo"));
+    reader.onerror = () => reject(new Error(t("file_read_failed")));
     reader.onload = () => resolve(String(reader.result));
     reader.readAsDataURL(file);
   });
 }
 
-export async function listPatients(query?: string): Promise<Patient[]> {
+// Unset keys don't filter; `consent_data: false` lists the patients still missing that consent.
+export type PatientFilters = {
+  consent_data?: boolean;
+  consent_contact?: boolean;
+  contact_preference?: ContactPreference | null;
+  fuzzy?: boolean; // also return near-miss names ("gonzales" for "González"), ranked after exact matches
+  archived?: boolean; // default false: archived patients only show up when asked for
+  status?: string;
+  tag?: string;
+  provider_id?: number | null; // null lists the unassigned patients
+};
+
+// Only the most recently updated patients are scored, so a fuzzy search stays cheap on big stores.
+const FUZZY_SCAN_LIMIT = 5000;
+
+// Sum of per-word edit distances, or null when some query word has no close name word.
+// A word is close when it prefixes a name word or is within ~1 edit per 4 letters.
+function fuzzyNameScore(query: string, name: string): number | null {
+  const words = name.split(" ").filter(Boolean);
+  let total = 0;
+  for (const q of query.split(" ")) {
+    const max = Math.max(1, Math.floor(q.length / 4));
+    let best = max + 1;
+    for (const w of words) {
+      best = Math.min(best, w.startsWith(q) ? 0 : levenshtein(q, w, max));
+      if (best === 0) break;
+    }
+    if (best > max) return null;
+    total += best;
+  }
+  return total;
+}
+
+function matchesFilters(p: Patient, filters: PatientFilters) {
+  if (Boolean(p.archived_at) !== (filters.archived ?? false)) return false;
+  if (filters.status !== undefined && p.status !== filters.status) return false;
+  if (filters.tag !== undefined && !p.tags.includes(filters.tag)) return false;
+  if (filters.provider_id !== undefined && p.provider_id !== filters.provider_id) return false;
+  if (filters.consent_data !== undefined && p.consent_data !== filters.consent_data) return false;
+  if (filters.consent_contact !== undefined && p.consent_contact !== filters.consent_contact) return false;
+  if (filters.contact_preference !== undefined && p.contact_preference !== filters.contact_preference) return false;
+  return true;
+}
+
+export const listPatients = command("list_patients", async (query?: string, filters?: PatientFilters): Promise<Patient[]> => {
   const store = await getStore();
-  const q = normQuery(query);
-  const patients = q
+  const q = normText(query);
+  let patients = q
     ? store.patients.filter((p) => {
-        const haystack = [p.name, p.doc_type, p.doc_number, p.insurer, p.phone, p.email]
-          .filter(Boolean)
-          .join(" ")
-          .toLowerCase();
+        const haystack = [
+          p.search_norm ?? patientSearchNorm(p),
+          normText([p.doc_type, p.doc_number, p.phone, p.email].filter(Boolean).join(" ")),
+          formatPatientCode(p.patient_code),
+        ].join(" ");
         return haystack.includes(q);
       })
     : store.patients;
-  return [...patients].sort((a, b) => b.updated_at.localeCompare(a.updated_at));
+  patients = patients.filter((p) => matchesFilters(p, filters ?? {}));
+  const sorted = [...patients].sort((a, b) => b.updated_at.localeCompare(a.updated_at));
+  if (!q || !filters?.fuzzy) return sorted.map(rowToPatient);
+
+  const exact = new Set(sorted.map((p) => p.id));
+  const near: { patient: Patient; score: number }[] = [];
+  const candidates = store.patients
+    .filter((p) => !exact.has(p.id) && matchesFilters(p, filters))
+    .sort((a, b) => b.updated_at.localeCompare(a.updated_at))
+    .slice(0, FUZZY_SCAN_LIMIT);
+  for (const patient of candidates) {
+    const score = fuzzyNameScore(q, normText(patient.name));
+    if (score !== null) near.push({ patient, score });
+  }
+  near.sort((a, b) => a.score - b.score || b.patient.updated_at.localeCompare(a.patient.updated_at));
+  return [...sorted, ...near.map((n) => n.patient)].map(rowToPatient);
+});
+
+export type PatientIndexBucket = { count: number; ids?: string[] };
+
+/**
+ * A–Z jump list: active patients counted by the first letter of their normalized name ("Ávila" goes
+ * under A). Names starting with a digit or symbol land in "#". Buckets come back in alphabetical order.
+ */
+export const patientsIndex = command("patients_index", async (includeIds = false): Promise<Record<string, PatientIndexBucket>> => {
+  const store = await getStore();
+  const buckets: Record<string, PatientIndexBucket> = {};
+  for (const p of store.patients) {
+    if (p.archived_at) continue;
+    const first = normText(p.name).charAt(0).toUpperCase();
+    const key = /^[A-Z]$/.test(first) ? first : "#";
+    const bucket = (buckets[key] ??= includeIds ? { count: 0, ids: [] } : { count: 0 });
+    bucket.count++;
+    bucket.ids?.push(p.id);
+  }
+  const ordered: Record<string, PatientIndexBucket> = {};
+  for (const key of Object.keys(buckets).sort((a, b) => (a === "#" ? 1 : b === "#" ? -1 : a.localeCompare(b)))) ordered[key] = buckets[key];
+  return ordered;
+});
+
+export type DuplicateMember = Pick<Patient, "id" | "name" | "doc_type" | "doc_number" | "birth_date" | "created_at">;
+
+export type DuplicateCluster = {
+  reason: "doc_number" | "name_birth_date";
+  confidence: number; // 1 for the same document; below that, how alike the least similar pair of names is
+  members: DuplicateMember[];
+};
+
+function docKey(doc: string | null) {
+  return (doc ?? "").replace(/[\s.\-]/g, "").toLowerCase();
+}
+
+/**
+ * Likely duplicate patients for the merge workflow: same document number, or the same birth date
+ * with names within settings.duplicate_name_threshold. Most certain clusters first.
+ */
+export const duplicateReport = command("duplicate_report", async (): Promise<DuplicateCluster[]> => {
+  const store = await getStore();
+  const member = (p: Patient): DuplicateMember => ({ id: p.id, name: p.name, doc_type: p.doc_type, doc_number: p.doc_number, birth_date: p.birth_date, created_at: p.created_at });
+  const clusters: DuplicateCluster[] = [];
+
+  const byDoc = new Map<string, Patient[]>();
+  for (const p of store.patients) {
+    const key = docKey(p.doc_number);
+    if (key) byDoc.set(key, [...(byDoc.get(key) ?? []), p]);
+  }
+  const docSets = new Set<string>();
+  for (const group of byDoc.values()) {
+    if (group.length < 2) continue;
+    docSets.add(group.map((p) => p.id).sort().join(","));
+    clusters.push({ reason: "doc_number", confidence: 1, members: group.map(member) });
+  }
+
+  const threshold = store.settings.duplicate_name_threshold;
+  const byBirth = new Map<string, Patient[]>();
+  for (const p of store.patients) if (p.birth_date) byBirth.set(p.birth_date, [...(byBirth.get(p.birth_date) ?? []), p]);
+  for (const group of byBirth.values()) {
+    if (group.length < 2) continue;
+    const names = group.map((p) => normText(p.name));
+    // Union-find over the pairs that are close enough; each root becomes one cluster.
+    const parent = group.map((_, i) => i);
+    const root = (i: number): number => (parent[i] === i ? i : (parent[i] = root(parent[i])));
+    const weakest = new Map<number, number>();
+    for (let i = 0; i < group.length; i++) {
+      for (let j = i + 1; j < group.length; j++) {
+        const len = Math.max(names[i].length, names[j].length, 1);
+        const max = Math.floor(len * threshold);
+        const dist = levenshtein(names[i], names[j], max);
+        if (dist > max) continue;
+        const similarity = 1 - dist / len;
+        const [a, b] = [root(i), root(j)];
+        parent[b] = a;
+        weakest.set(a, Math.min(similarity, weakest.get(a) ?? 1, weakest.get(b) ?? 1));
+      }
+    }
+    const members = new Map<number, Patient[]>();
+    group.forEach((p, i) => members.set(root(i), [...(members.get(root(i)) ?? []), p]));
+    for (const [r, ps] of members) {
+      if (ps.length < 2 || docSets.has(ps.map((p) => p.id).sort().join(","))) continue;
+      clusters.push({ reason: "name_birth_date", confidence: Math.round((weakest.get(r) ?? 0) * 0.95 * 100) / 100, members: ps.map(member) });
+    }
+  }
+  return clusters.sort((a, b) => b.confidence - a.confidence || b.members.length - a.members.length);
+});
+
+export type AgeBucket = { label: string; min: number | null; max: number | null; count: number };
+
+/**
+ * Patients per age range for the reports chart: "0-9", "10-19", … up to the oldest patient, empty
+ * ranges included so the axis has no gaps, then "unknown" for missing or unreadable birth dates.
+ */
+export const ageDistribution = command("age_distribution", async (bucketSize = 10): Promise<AgeBucket[]> => {
+  if (!Number.isInteger(bucketSize) || bucketSize < 1) throw new Error(t("invalid_field_value", { field: "bucket_size" }));
+  const counts: number[] = [];
+  let unknown = 0;
+  for (const p of (await getStore()).patients) {
+    const age = ageOn(p.birth_date);
+    if (age === null) unknown++;
+    else {
+      const i = Math.floor(age / bucketSize);
+      while (counts.length <= i) counts.push(0);
+      counts[i]++;
+    }
+  }
+  const buckets: AgeBucket[] = counts.map((count, i) => {
+    const min = i * bucketSize;
+    const max = min + bucketSize - 1;
+    return { label: bucketSize === 1 ? String(min) : `${min}-${max}`, min, max, count };
+  });
+  buckets.push({ label: "unknown", min: null, max: null, count: unknown });
+  return buckets;
+});
+
+export const getPatient = command("get_patient", async (patientId: string): Promise<Patient> => {
+  const patient = (await getStore()).patients.find((p) => p.id === patientId);
+  if (!patient) throw new Error(t("patient_not_found"));
+  return rowToPatient(patient);
+});
+
+const PATIENT_CODE_DIGITS = 4;
+
+/** Zero-padded, as staff say and write it: 423 -> "0423". */
+export function formatPatientCode(code: number) {
+  return String(code).padStart(PATIENT_CODE_DIGITS, "0");
+}
+
+// Accepts the number or its padded text form ("0423").
+export const getPatientByCode = command("get_patient_by_code", async (code: number | string): Promise<Patient> => {
+  const n = typeof code === "number" ? code : /^\s*\d+\s*$/.test(code) ? Number(code) : NaN;
+  const patient = Number.isInteger(n) && n > 0 ? (await getStore()).patients.find((p) => p.patient_code === n) : undefined;
+  if (!patient) throw new Error(t("patient_not_found"));
+  return rowToPatient(patient);
+});
+
+// The two shapes newId() produces; anything else a scanner reads is rejected outright.
+const PATIENT_ID_RE = /^(?:[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}|p_\d+_[0-9a-f]+)$/i;
+
+// Scan-to-open: `code` is what the barcode reader typed from a patient label.
+export const findPatientByCode = command("find_patient_by_code", async (code: string): Promise<Patient> => {
+  const id = String(code ?? "").trim();
+  if (!PATIENT_ID_RE.test(id)) throw new Error(t("invalid_patient_code"));
+  const patient = (await getStore()).patients.find((p) => p.id.toLowerCase() === id.toLowerCase());
+  if (!patient) throw new Error(t("patient_not_found"));
+  return rowToPatient(patient);
+});
+
+const PATIENT_INPUT_KEYS = [
+  "name",
+  "doc_type",
+  "doc_number",
+  "insurer",
+  "birth_date",
+  "sex",
+  "phone",
+  "email",
+  "address",
+  "emergency_contact",
+  "notes",
+] as const;
+
+type PatientInputKey = (typeof PATIENT_INPUT_KEYS)[number];
+
+function validatePatientField(key: PatientInputKey, value: unknown): string | null {
+  if (value === undefined || value === null) {
+    if (key === "name") throw new Error(t("name_required"));
+    return null;
+  }
+  if (typeof value !== "string") throw new Error(t("invalid_field_value", { field: key }));
+  if (key === "name" && !value.trim()) throw new Error(t("name_required"));
+  return value;
+}
+
+const CONSENT_INPUT_KEYS = ["contact_preference", "consent_data", "consent_contact"] as const;
+
+function validateGenderIdentity(store: Store, value: unknown): string | null {
+  if (value === undefined || value === null || value === "") return null;
+  const identity = typeof value === "string" ? value.trim() : "";
+  if (!store.settings.gender_identities.includes(identity)) throw new Error(t("invalid_field_value", { field: "gender_identity" }));
+  return identity;
+}
+
+function validateStatus(store: Store, value: unknown): string {
+  if (value === undefined || value === null) return DEFAULT_PATIENT_STATUS;
+  const status = typeof value === "string" ? value.trim() : "";
+  if (!store.settings.patient_statuses.includes(status)) throw new Error(t("invalid_status", { status: String(value) }));
+  return status;
+}
+
+type ConsentInputKey = (typeof CONSENT_INPUT_KEYS)[number];
+
+function validateContactPreference(value: unknown): ContactPreference | null {
+  if (value === undefined || value === null) return null;
+  if (!CONTACT_PREFERENCES.includes(value as ContactPreference)) throw new Error(t("invalid_contact_preference", { value: String(value) }));
+  return value as ContactPreference;
+}
+
+function validateConsentFlag(key: ConsentInputKey, value: unknown): boolean {
+  if (value === undefined || value === null) return false;
+  if (typeof value !== "boolean") throw new Error(t("invalid_field_value", { field: key }));
+  return value;
+}
+
+// consent_at records when consent was (re)granted, so it only moves when a flag goes from false to true.
+function stampConsent(prev: Pick<Patient, "consent_data" | "consent_contact"> | null, next: Patient, iso: string) {
+  const granted = (next.consent_data && !prev?.consent_data) || (next.consent_contact && !prev?.consent_contact);
+  if (granted) next.consent_at = iso;
 }
 
-export async function createPatient(input: PatientInput): Promise<Patient> {
+const MINOR_AGE = 18;
+
+// Completed years at `today`; null without a valid birth date.
+function ageOn(birthDate: string | null, today = new Date()): number | null {
+  const birth = parseBirthDate(birthDate);
+  if (!birth) return null;
+  // Birthdays turn over on the clinic's calendar, not the browser's.
+  const now = clinicParts(today);
+  let age = now.year - birth.year;
+  if (now.month < birth.month || (now.month === birth.month && now.day < birth.day)) age--;
+  return Math.max(0, age);
+}
+
+// Adds the derived age/is_minor to a patient leaving the api; the stored record never has them.
+function rowToPatient(p: Patient): Patient {
+  const age = ageOn(p.birth_date);
+  return { ...p, age, is_minor: age === null ? null : age < MINOR_AGE };
+}
+
+function assertMinorHasEmergencyContact(p: Patient) {
+  const age = ageOn(p.birth_date);
+  if (age !== null && age < MINOR_AGE && !(p.emergency_contact ?? "").trim()) throw new Error(t("emergency_contact_required"));
+}
+
+function assertUniqueDoc(store: Store, docNumber: string | null | undefined, exceptId?: string) {
+  const doc = (docNumber ?? "").trim().toLowerCase();
+  if (!doc) return;
+  const clash = store.patients.find((p) => p.id !== exceptId && (p.doc_number ?? "").trim().toLowerCase() === doc);
+  if (clash) throw new Error(t("duplicate_doc", { doc: (docNumber ?? "").trim() }));
+}
+
+export const createPatient = command("create_patient", async (input: PatientInput): Promise<Patient> => {
   const store = await getStore();
   const iso = nowIso();
-  const patient: Patient = {
+  // Everything is validated on this draft first; the code is only taken once the patient is accepted.
+  const draft: Patient = {
     id: newId(),
-    name: input.name,
+    patient_code: 0,
+    name: validatePatientField("name", input.name) as string,
     doc_type: input.doc_type ?? null,
     doc_number: input.doc_number ?? null,
     insurer: input.insurer ?? null,
     birth_date: input.birth_date ?? null,
     sex: input.sex ?? null,
+    gender_identity: validateGenderIdentity(store, input.gender_identity),
     phone: input.phone ?? null,
     email: input.email ?? null,
     address: input.address ?? null,
@@ -225,118 +1250,2390 @@ export async function createPatient(input: PatientInput): Promise<Patient> {
     notes: input.notes ?? null,
     photo_path: null,
     drive_folder_id: null,
+    contact_preference: validateContactPreference(input.contact_preference),
+    consent_data: validateConsentFlag("consent_data", input.consent_data),
+    consent_contact: validateConsentFlag("consent_contact", input.consent_contact),
+    consent_at: null,
+    archived_at: null,
+    status: validateStatus(store, input.status),
+    tags: [],
+    provider_id: null,
     created_at: iso,
     updated_at: iso,
   };
-  store.patients.unshift(patient);
-  await persistStore(store);
-  return patient;
-}
+  stampConsent(null, draft, iso);
+  assertMinorHasEmergencyContact(draft);
+  draft.search_norm = patientSearchNorm(draft);
+  const patient = await commit((s) => {
+    assertUniqueDoc(s, draft.doc_number);
+    // Taken from the counter, never from the patient count, so a deleted patient's code isn't reused.
+    const added: Patient = { ...draft, patient_code: s.nextPatientCode++ };
+    s.patients.unshift(added);
+    addInsurerName(s.insurers, added.insurer);
+    return added;
+  });
+  await refreshFolderMarker(store, patient);
+  const created = rowToPatient(patient);
+  // The patient is already saved: a failed intake exam is logged and doesn't undo it.
+  if (store.settings.auto_create_intake) {
+    try {
+      created.intake_file_id = (await createExam(patient.id, "admision", { fecha: clinicDateIso() })).file.id;
+    } catch {
+      log("error", "create_patient", "intake exam failed", { ids: [patient.id] });
+    }
+  }
+  return created;
+});
 
-export async function updatePatient(patientId: string, input: PatientInput): Promise<Patient> {
+// Walk-in registration: just name and document, the rest is filled in later with updatePatient/patchPatient.
+export const quickCreatePatient = command("quick_create_patient", async (name: string, docNumber?: string | null): Promise<Patient> => {
+  return createPatient({ name: name.trim(), doc_number: docNumber?.trim() || null });
+});
+
+export const updatePatient = command("update_patient", async (patientId: string, input: PatientInput): Promise<Patient> => {
   const store = await getStore();
   const idx = store.patients.findIndex((p) => p.id === patientId);
-  if (idx === -1) throw new Error("Paciente no encontrado");
+  if (idx === -1) throw new Error(t("patient_not_found"));
   const current = store.patients[idx];
+  if ((input.doc_number ?? "").trim() !== (current.doc_number ?? "").trim()) assertUniqueDoc(store, input.doc_number, patientId);
+  const iso = nowIso();
+  // Consent fields and gender identity are kept when omitted: the edit form doesn't carry them.
   const updated: Patient = {
     ...current,
-    name: input.name,
+    name: validatePatientField("name", input.name) as string,
     doc_type: input.doc_type ?? null,
     doc_number: input.doc_number ?? null,
     insurer: input.insurer ?? null,
     birth_date: input.birth_date ?? null,
     sex: input.sex ?? null,
+    gender_identity: input.gender_identity !== undefined ? validateGenderIdentity(store, input.gender_identity) : current.gender_identity,
     phone: input.phone ?? null,
     email: input.email ?? null,
     address: input.address ?? null,
     emergency_contact: input.emergency_contact ?? null,
     notes: input.notes ?? null,
-    updated_at: nowIso(),
+    contact_preference: input.contact_preference !== undefined ? validateContactPreference(input.contact_preference) : current.contact_preference,
+    consent_data: input.consent_data !== undefined ? validateConsentFlag("consent_data", input.consent_data) : current.consent_data,
+    status: input.status !== undefined ? validateStatus(store, input.status) : current.status,
+    consent_contact: input.consent_contact !== undefined ? validateConsentFlag("consent_contact", input.consent_contact) : current.consent_contact,
+    updated_at: iso,
   };
+  stampConsent(current, updated, iso);
+  assertMinorHasEmergencyContact(updated);
+  updated.search_norm = patientSearchNorm(updated);
+  recordRevision(store, current, "update_patient");
   store.patients[idx] = updated;
+  addInsurerName(store.insurers, updated.insurer);
   await persistStore(store);
-  return updated;
-}
+  await refreshFolderMarker(store, updated);
+  return rowToPatient(updated);
+});
 
-export async function deletePatient(patientId: string): Promise<void> {
-  const store = await getStore();
-  store.patients = store.patients.filter((p) => p.id !== patientId);
-  store.files = store.files.filter((f) => f.patient_id !== patientId);
-  store.appointments = store.appointments.filter((a) => a.patient_id !== patientId);
-  await persistStore(store);
+const PATIENT_HISTORY_LIMIT = 10;
+
+// Keeps the last PATIENT_HISTORY_LIMIT pre-edit snapshots per patient.
+function recordRevision(store: Store, previous: Patient, cmd: string, reason?: string) {
+  store.patientHistory.push({ patient_id: previous.id, saved_at: nowIso(), cmd, ...(reason ? { reason } : {}), snapshot: normalizePatient(previous) });
+  const own = store.patientHistory.filter((r) => r.patient_id === previous.id);
+  if (own.length > PATIENT_HISTORY_LIMIT) {
+    const drop = new Set(own.slice(0, own.length - PATIENT_HISTORY_LIMIT));
+    store.patientHistory = store.patientHistory.filter((r) => !drop.has(r));
+  }
 }
 
-export async function setPatientPhoto(patientId: string, file: File): Promise<Patient> {
+// Only the keys present in `changes` are touched; unknown keys are rejected so typos don't pass silently.
+export const patchPatient = command("patch_patient", async (patientId: string, changes: Record<string, unknown>): Promise<Patient> => {
   const store = await getStore();
   const idx = store.patients.findIndex((p) => p.id === patientId);
-  if (idx === -1) throw new Error("Paciente no encontrado");
-  const dataUrl = await readFileAsDataUrl(file);
-  const updated: Patient = {
-    ...store.patients[idx],
-    photo_path: dataUrl,
-    updated_at: nowIso(),
-  };
+  if (idx === -1) throw new Error(t("patient_not_found"));
+  const known: readonly string[] = [...PATIENT_INPUT_KEYS, ...CONSENT_INPUT_KEYS, "status", "gender_identity"];
+  const unknownKeys = Object.keys(changes).filter((k) => !known.includes(k));
+  if (unknownKeys.length) throw new Error(t("unknown_fields", { fields: unknownKeys.join(", ") }));
+
+  const iso = nowIso();
+  const current = store.patients[idx];
+  const updated: Patient = { ...current, updated_at: iso };
+  for (const key of Object.keys(changes)) {
+    if (key === "status") updated.status = validateStatus(store, changes[key]);
+    else if (key === "gender_identity") updated.gender_identity = validateGenderIdentity(store, changes[key]);
+    else if (key === "contact_preference") updated.contact_preference = validateContactPreference(changes[key]);
+    else if (key === "consent_data" || key === "consent_contact") updated[key] = validateConsentFlag(key, changes[key]);
+    else (updated as Record<PatientInputKey, string | null>)[key as PatientInputKey] = validatePatientField(key as PatientInputKey, changes[key]);
+  }
+  stampConsent(current, updated, iso);
+  assertMinorHasEmergencyContact(updated);
+  updated.search_norm = patientSearchNorm(updated);
+  recordRevision(store, current, "patch_patient");
   store.patients[idx] = updated;
+  addInsurerName(store.insurers, updated.insurer);
   await persistStore(store);
-  return updated;
+  await refreshFolderMarker(store, updated);
+  return rowToPatient(updated);
+});
+
+// Workflow-board shortcut. Like every mutation it lands in the audit trail with the patient id.
+export const setPatientStatus = command("set_patient_status", async (patientId: string, status: string): Promise<Patient> => {
+  return patchPatient(patientId, { status });
+});
+
+/**
+ * Name change (marriage, legal change) with a stated reason. The previous name and the reason are
+ * kept in the patient's revision history, since the logs never hold names or free text; the
+ * folder marker is rewritten with the new name.
+ */
+export const renamePatient = command("rename_patient", async (patientId: string, newName: string, reason: string): Promise<Patient> => {
+  const name = validatePatientField("name", newName) as string;
+  const why = String(reason ?? "").trim();
+  if (!why) throw new Error(t("rename_reason_required"));
+  const renamed = await commit((s) => {
+    const idx = s.patients.findIndex((p) => p.id === patientId);
+    if (idx === -1) throw new Error(t("patient_not_found"));
+    const current = s.patients[idx];
+    if (current.name === name) return current;
+    const updated: Patient = { ...current, name, updated_at: nowIso() };
+    updated.search_norm = patientSearchNorm(updated);
+    recordRevision(s, current, "rename_patient", why);
+    s.patients[idx] = updated;
+    return updated;
+  });
+  await refreshFolderMarker(await getStore(), renamed);
+  return rowToPatient(renamed);
+});
+
+/** Newest first. */
+export const listPatientRevisions = command("list_patient_revisions", async (patientId: string): Promise<PatientRevision[]> => {
+  const store = await getStore();
+  return store.patientHistory.filter((r) => r.patient_id === patientId).reverse();
+});
+
+// Restores the snapshot taken before the last edit and consumes it, so repeated undos walk further back.
+export const undoLastEdit = command("undo_last_edit", async (patientId: string): Promise<Patient> => {
+  const restored = await commit((s) => {
+    const idx = s.patients.findIndex((p) => p.id === patientId);
+    if (idx === -1) throw new Error(t("patient_not_found"));
+    const revIdx = s.patientHistory.map((r) => r.patient_id).lastIndexOf(patientId);
+    if (revIdx === -1) throw new Error(t("nothing_to_undo"));
+    const [revision] = s.patientHistory.splice(revIdx, 1);
+    const current = s.patients[idx];
+    // Archive state, tags, the provider and the photo have their own commands; undo only reverts what the edit form changed.
+    const updated: Patient = {
+      ...normalizePatient(revision.snapshot),
+      id: current.id,
+      patient_code: current.patient_code,
+      created_at: current.created_at,
+      photo_path: current.photo_path,
+      drive_folder_id: current.drive_folder_id,
+      archived_at: current.archived_at,
+      tags: current.tags,
+      provider_id: current.provider_id,
+      updated_at: nowIso(),
+    };
+    if ((updated.doc_number ?? "").trim() !== (current.doc_number ?? "").trim()) assertUniqueDoc(s, updated.doc_number, patientId);
+    updated.search_norm = patientSearchNorm(updated);
+    s.patients[idx] = updated;
+    addInsurerName(s.insurers, updated.insurer);
+    return updated;
+  });
+  await refreshFolderMarker(await getStore(), restored);
+  return rowToPatient(restored);
+});
+
+export type EncodingIssue = { patient_id: string; field: PatientInputKey; value: string; suggestion: string | null };
+
+/** Patient text fields that look double-encoded ("JosÃ©"), with the repaired value when one exists. */
+export const scanEncodingIssues = command("scan_encoding_issues", async (): Promise<EncodingIssue[]> => {
+  const store = await getStore();
+  const issues: EncodingIssue[] = [];
+  for (const p of store.patients) {
+    for (const field of PATIENT_INPUT_KEYS) {
+      const value = p[field];
+      if (typeof value === "string" && looksLikeMojibake(value)) issues.push({ patient_id: p.id, field, value, suggestion: repairMojibake(value) });
+    }
+  }
+  return issues;
+});
+
+export type EncodingFix = { field: PatientInputKey; before: string; after: string; applied: boolean };
+
+/**
+ * Repairs one field. Without `confirm` it only previews; with it, the value is saved through
+ * patchPatient (validated, kept in the edit history so it can be undone).
+ */
+export const fixEncoding = command("fix_encoding", async (patientId: string, field: string, confirm = false): Promise<EncodingFix> => {
+  if (!(PATIENT_INPUT_KEYS as readonly string[]).includes(field)) throw new Error(t("unknown_fields", { fields: field }));
+  const key = field as PatientInputKey;
+  const patient = (await getStore()).patients.find((p) => p.id === patientId);
+  if (!patient) throw new Error(t("patient_not_found"));
+  const before = patient[key] ?? "";
+  const after = looksLikeMojibake(before) ? repairMojibake(before) : null;
+  if (after === null) throw new Error(t("encoding_not_fixable", { field }));
+  if (confirm) await patchPatient(patientId, { [key]: after });
+  return { field: key, before, after, applied: confirm };
+});
+
+// Each entry is satisfied when any of its fields has a value (phone or email counts as "contacto").
+const REQUIRED_PATIENT_DATA: { label: string; fields: PatientInputKey[] }[] = [
+  { label: "doc_number", fields: ["doc_number"] },
+  { label: "birth_date", fields: ["birth_date"] },
+  { label: "contacto", fields: ["phone", "email"] },
+];
+
+export type IncompletePatient = { patient: Patient; missing: string[] };
+
+export const listIncompletePatients = command("list_incomplete_patients", async (): Promise<IncompletePatient[]> => {
+  const store = await getStore();
+  const out: IncompletePatient[] = [];
+  for (const patient of store.patients) {
+    if (patient.archived_at) continue;
+    const missing = REQUIRED_PATIENT_DATA.filter((req) => !req.fields.some((f) => (patient[f] ?? "").trim())).map((req) => req.label);
+    for (const def of store.customFields) {
+      if (def.required && !store.customValues.some((v) => v.patient_id === patient.id && v.key === def.key)) missing.push(def.key);
+    }
+    if (missing.length) out.push({ patient: rowToPatient(patient), missing });
+  }
+  return out.sort((a, b) => b.missing.length - a.missing.length || a.patient.name.localeCompare(b.patient.name));
+});
+
+// Strict YYYY-MM-DD (what the date input stores); anything else, or an impossible date, is null.
+function parseBirthDate(raw: string | null | undefined): { year: number; month: number; day: number } | null {
+  const m = /^(\d{4})-(\d{2})-(\d{2})$/.exec((raw ?? "").trim());
+  if (!m) return null;
+  const [year, month, day] = [Number(m[1]), Number(m[2]), Number(m[3])];
+  const d = new Date(year, month - 1, day);
+  if (d.getFullYear() !== year || d.getMonth() !== month - 1 || d.getDate() !== day) return null;
+  return { year, month, day };
 }
 
-export async function importFiles(patientId: string, files: File[]): Promise<PatientFile[]> {
+export type UpcomingBirthday = { patient: Patient; date: string; days_until: number; turning: number };
+
+// Birthdays from today through today + `days`, soonest first. Feb 29 is celebrated on Feb 28 in common years.
+export const patientsWithBirthdayIn = command("patients_with_birthday_in", async (days: number): Promise<UpcomingBirthday[]> => {
   const store = await getStore();
-  const createdAt = nowIso();
-  const newFiles: PatientFile[] = [];
-  for (const file of files) {
-    const dataUrl = await readFileAsDataUrl(file);
-    const entry: PatientFile = {
-      id: store.nextFileId++,
-      patient_id: patientId,
-      kind: "attachment",
-      filename: file.name,
-      created_at: createdAt,
-      path: dataUrl,
-      meta_json: null,
+  // Calendar days are counted as UTC midnights of the clinic's dates, so DST never shifts them.
+  const now = clinicParts(new Date());
+  const today = Date.UTC(now.year, now.month - 1, now.day);
+  const out: UpcomingBirthday[] = [];
+  for (const patient of store.patients) {
+    if (patient.archived_at) continue;
+    const birth = parseBirthDate(patient.birth_date);
+    if (!birth) continue;
+    let year = now.year;
+    const nextIn = (y: number) => {
+      const leap = new Date(Date.UTC(y, 1, 29)).getUTCMonth() === 1;
+      return Date.UTC(y, birth.month - 1, birth.month === 2 && birth.day === 29 && !leap ? 28 : birth.day);
     };
-    newFiles.push(entry);
-    store.files.unshift(entry);
+    let next = nextIn(year);
+    if (next < today) next = nextIn(++year);
+    const daysUntil = Math.round((next - today) / 86_400_000);
+    if (daysUntil > days) continue;
+    out.push({ patient: rowToPatient(patient), date: new Date(next).toISOString().slice(0, 10), days_until: daysUntil, turning: year - birth.year });
+  }
+  return out.sort((a, b) => a.days_until - b.days_until || a.patient.name.localeCompare(b.patient.name));
+});
+
+const CUSTOM_KEY_RE = /^[a-z][a-z0-9_]{0,39}$/;
+
+function validateCustomValue(def: CustomFieldDef, value: unknown): CustomFieldValue {
+  const ok =
+    (def.type === "text" && typeof value === "string" && value.trim() !== "") ||
+    (def.type === "number" && typeof value === "number" && Number.isFinite(value)) ||
+    (def.type === "date" && typeof value === "string" && parseBirthDate(value) !== null) ||
+    (def.type === "bool" && typeof value === "boolean");
+  if (!ok) throw new Error(t("invalid_field_value", { field: def.label || def.key }));
+  return typeof value === "string" ? value.trim() : (value as number | boolean);
+}
+
+function customValuesOf(store: Store, patientId: string): Record<string, CustomFieldValue> {
+  const out: Record<string, CustomFieldValue> = {};
+  for (const v of store.customValues) if (v.patient_id === patientId) out[v.key] = v.value;
+  return out;
+}
+
+export const listCustomFields = command("list_custom_fields", async (): Promise<CustomFieldDef[]> => {
+  return (await getStore()).customFields;
+});
+
+function cleanCustomFieldDef(def: CustomFieldDef): CustomFieldDef {
+  const key = String(def?.key ?? "").trim();
+  if (!CUSTOM_KEY_RE.test(key)) throw new Error(t("invalid_field_value", { field: "key" }));
+  if (!CUSTOM_FIELD_TYPES.includes(def.type)) throw new Error(t("invalid_field_value", { field: "type" }));
+  return { key, label: String(def.label ?? "").trim() || key, type: def.type, required: Boolean(def.required) };
+}
+
+function putCustomField(s: Store, clean: CustomFieldDef) {
+  const idx = s.customFields.findIndex((f) => f.key === clean.key);
+  if (idx !== -1 && s.customFields[idx].type !== clean.type && s.customValues.some((v) => v.key === clean.key)) {
+    throw new Error(t("custom_field_in_use", { key: clean.key }));
+  }
+  if (idx === -1) s.customFields.push(clean);
+  else s.customFields[idx] = clean;
+}
+
+// Creates or redefines a field. Changing the type of a field in use is refused so stored values stay valid.
+export const defineCustomField = command("define_custom_field", async (def: CustomFieldDef): Promise<CustomFieldDef> => {
+  const clean = cleanCustomFieldDef(def);
+  return commit((s) => {
+    putCustomField(s, clean);
+    return clean;
+  });
+});
+
+// Drops the definition and every stored value for it.
+export const deleteCustomField = command("delete_custom_field", async (key: string): Promise<void> => {
+  await commit((s) => {
+    s.customFields = s.customFields.filter((f) => f.key !== key);
+    s.customValues = s.customValues.filter((v) => v.key !== key);
+  });
+});
+
+const SETTINGS_FILE_FORMAT = "naju-settings/1";
+
+export type SettingsFile = {
+  format: typeof SETTINGS_FILE_FORMAT;
+  exported_at: string;
+  settings: Partial<Settings>;
+  custom_fields: CustomFieldDef[];
+};
+
+export type SettingsImportSummary = { applied: SettingKey[]; unknown: string[]; custom_fields: number };
+
+/**
+ * Configuration for setting up another install: every user-settable key plus the custom-field
+ * definitions. Keys without a validator (last_backup_at, the encryption salt and check) describe
+ * this install's data and stay behind.
+ */
+export const exportSettings = command("export_settings", async (filename?: string): Promise<SettingsFile> => {
+  const store = await getStore();
+  const settings: Partial<Settings> = {};
+  for (const key of Object.keys(store.settings) as SettingKey[]) {
+    if (SETTING_VALIDATORS[key]) (settings as Record<string, unknown>)[key] = store.settings[key];
+  }
+  const out: SettingsFile = { format: SETTINGS_FILE_FORMAT, exported_at: nowIso(), settings, custom_fields: store.customFields };
+  downloadTextFile(filename || `naju-ajustes-${clinicDateIso()}.json`, "application/json", JSON.stringify(out, null, 2));
+  return out;
+});
+
+// All or nothing: every value is validated as setSetting would before anything is written. Unknown
+// keys (from a newer version, or typos) are skipped and reported; custom fields are merged by key.
+export const importSettings = command("import_settings", async (file: File): Promise<SettingsImportSummary> => {
+  let parsed: SettingsFile;
+  try {
+    parsed = JSON.parse(await file.text());
+  } catch {
+    throw new Error(t("invalid_settings_file"));
+  }
+  if (parsed?.format !== SETTINGS_FILE_FORMAT || !parsed.settings || typeof parsed.settings !== "object") throw new Error(t("invalid_settings_file"));
+
+  const clean: Partial<Record<SettingKey, unknown>> = {};
+  const unknown: string[] = [];
+  for (const [key, value] of Object.entries(parsed.settings)) {
+    const validate = isSettingKey(key) ? (SETTING_VALIDATORS[key] as ((v: unknown) => unknown) | undefined) : undefined;
+    if (!validate) unknown.push(key);
+    else clean[key as SettingKey] = validate(value);
+  }
+  const fields = (Array.isArray(parsed.custom_fields) ? parsed.custom_fields : []).map(cleanCustomFieldDef);
+
+  const summary = await commit((s) => {
+    for (const f of fields) putCustomField(s, f);
+    s.settings = { ...s.settings, ...clean };
+    return { applied: Object.keys(clean) as SettingKey[], unknown, custom_fields: fields.length };
+  });
+  await applySettingEffects(await getStore(), clean as Partial<Settings>);
+  return summary;
+});
+
+export const getCustomValues = command("get_custom_values", async (patientId: string): Promise<Record<string, CustomFieldValue>> => {
+  const store = await getStore();
+  if (!store.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+  return customValuesOf(store, patientId);
+});
+
+// `null` clears the value, which required fields don't allow.
+export const setCustomValue = command("set_custom_value", async (patientId: string, key: string, value: CustomFieldValue | null): Promise<Record<string, CustomFieldValue>> => {
+  return commit((s) => {
+    if (!s.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+    const def = s.customFields.find((f) => f.key === key);
+    if (!def) throw new Error(t("unknown_fields", { fields: key }));
+    s.customValues = s.customValues.filter((v) => !(v.patient_id === patientId && v.key === key));
+    if (value === null) {
+      if (def.required) throw new Error(t("invalid_field_value", { field: def.label }));
+    } else {
+      s.customValues.push({ patient_id: patientId, key, value: validateCustomValue(def, value) });
+    }
+    return customValuesOf(s, patientId);
+  });
+});
+
+export type InsurerUsage = { name: string; count: number };
+
+export const listInsurers = command("list_insurers", async (): Promise<InsurerUsage[]> => {
+  const store = await getStore();
+  const counts = new Map<string, number>();
+  for (const p of store.patients) {
+    if (p.insurer?.trim()) counts.set(insurerKey(p.insurer), (counts.get(insurerKey(p.insurer)) ?? 0) + 1);
   }
+  return store.insurers
+    .map((name) => ({ name, count: counts.get(insurerKey(name)) ?? 0 }))
+    .sort((a, b) => b.count - a.count || a.name.localeCompare(b.name));
+});
+
+// Rewrites every case-insensitive match of `oldName` on patients to `newName`. Returns patients touched.
+export const renameInsurer = command("rename_insurer", async (oldName: string, newName: string): Promise<number> => {
+  const store = await getStore();
+  const target = newName.trim();
+  if (!target) throw new Error(t("insurer_name_required"));
+  const now = nowIso();
+  let changed = 0;
+  store.patients = store.patients.map((p) => {
+    if (!p.insurer || insurerKey(p.insurer) !== insurerKey(oldName) || p.insurer === target) return p;
+    changed++;
+    return { ...p, insurer: target, search_norm: patientSearchNorm({ name: p.name, insurer: target }), updated_at: now };
+  });
+  store.insurers = addInsurerName(
+    store.insurers.filter((n) => insurerKey(n) !== insurerKey(oldName)),
+    target
+  );
   await persistStore(store);
-  return newFiles;
+  return changed;
+});
+
+export type ProviderInput = { name: string; specialty?: string | null };
+
+export type ProviderWithCaseload = Provider & { patient_count: number };
+
+function cleanProviderName(name: unknown) {
+  const clean = String(name ?? "").trim();
+  if (!clean) throw new Error(t("provider_name_required"));
+  return clean;
 }
 
-export async function listPatientFiles(patientId: string): Promise<PatientFile[]> {
+export const createProvider = command("create_provider", async (input: ProviderInput): Promise<Provider> => {
+  const name = cleanProviderName(input.name);
+  return commit((s) => {
+    const iso = nowIso();
+    const provider: Provider = { id: s.nextProviderId++, name, specialty: input.specialty?.trim() || null, active: true, created_at: iso, updated_at: iso };
+    s.providers.push(provider);
+    return provider;
+  });
+});
+
+export const updateProvider = command(
+  "update_provider",
+  async (providerId: number, changes: Partial<ProviderInput> & { active?: boolean }): Promise<Provider> => {
+    return commit((s) => {
+      const i = s.providers.findIndex((p) => p.id === providerId);
+      if (i === -1) throw new Error(t("provider_not_found"));
+      const updated: Provider = { ...s.providers[i], updated_at: nowIso() };
+      if (changes.name !== undefined) updated.name = cleanProviderName(changes.name);
+      if (changes.specialty !== undefined) updated.specialty = changes.specialty?.trim() || null;
+      if (changes.active !== undefined) updated.active = Boolean(changes.active);
+      s.providers[i] = updated;
+      return updated;
+    });
+  },
+);
+
+/** Alphabetical, with how many non-archived patients each one has. */
+export const listProviders = command("list_providers", async (includeInactive = false): Promise<ProviderWithCaseload[]> => {
   const store = await getStore();
-  return store.files.filter((f) => f.patient_id === patientId);
+  const counts = new Map<number, number>();
+  for (const p of store.patients) if (p.provider_id !== null && !p.archived_at) counts.set(p.provider_id, (counts.get(p.provider_id) ?? 0) + 1);
+  return store.providers
+    .filter((p) => includeInactive || p.active)
+    .map((p) => ({ ...p, patient_count: counts.get(p.id) ?? 0 }))
+    .sort((a, b) => a.name.localeCompare(b.name));
+});
+
+// Like ON DELETE SET NULL: the provider's patients become unassigned. Returns how many were.
+export const deleteProvider = command("delete_provider", async (providerId: number): Promise<number> => {
+  return commit((s) => {
+    if (!s.providers.some((p) => p.id === providerId)) throw new Error(t("provider_not_found"));
+    s.providers = s.providers.filter((p) => p.id !== providerId);
+    let unassigned = 0;
+    s.patients = s.patients.map((p) => {
+      if (p.provider_id !== providerId) return p;
+      unassigned++;
+      return { ...p, provider_id: null, updated_at: nowIso() };
+    });
+    return unassigned;
+  });
+});
+
+/**
+ * Assigns the patient to `providerId`, or unassigns with null. A reassignment also writes an
+ * audit entry carrying the previous provider, which the command's own entry doesn't have.
+ */
+export const assignProvider = command("assign_provider", async (patientId: string, providerId: number | null): Promise<Patient> => {
+  const { patient, previous } = await commit((s) => {
+    const i = s.patients.findIndex((p) => p.id === patientId);
+    if (i === -1) throw new Error(t("patient_not_found"));
+    if (providerId !== null && !s.providers.some((p) => p.id === providerId && p.active)) throw new Error(t("provider_not_found"));
+    const current = s.patients[i];
+    if (current.provider_id === providerId) return { patient: current, previous: current.provider_id };
+    s.patients[i] = { ...current, provider_id: providerId, updated_at: nowIso() };
+    return { patient: s.patients[i], previous: current.provider_id };
+  });
+  if (previous !== null && previous !== providerId) audit("reassign_provider", [patientId, previous, providerId ?? "none"], patientId);
+  return rowToPatient(patient);
+});
+
+async function setArchived(patientId: string, archived: boolean): Promise<Patient> {
+  return commit((s) => {
+    const i = s.patients.findIndex((p) => p.id === patientId);
+    if (i === -1) throw new Error(t("patient_not_found"));
+    const iso = nowIso();
+    const updated: Patient = { ...s.patients[i], archived_at: archived ? s.patients[i].archived_at ?? iso : null, updated_at: iso };
+    s.patients[i] = updated;
+    return updated;
+  });
+}
+
+export const archivePatient = command("archive_patient", async (patientId: string): Promise<Patient> => {
+  return setArchived(patientId, true);
+});
+
+export const unarchivePatient = command("unarchive_patient", async (patientId: string): Promise<Patient> => {
+  return setArchived(patientId, false);
+});
+
+// Reciprocal of a relation label, as seen from the other patient. Gendered pairs default to the
+// masculine form; the UI offers it as a suggestion the user can change.
+const RECIPROCAL_RELATIONS: Record<string, string> = {
+  madre: "hijo",
+  padre: "hijo",
+  hijo: "padre",
+  hija: "madre",
+  hermano: "hermano",
+  hermana: "hermana",
+  abuelo: "nieto",
+  abuela: "nieto",
+  nieto: "abuelo",
+  nieta: "abuela",
+  pareja: "pareja",
+  tutor: "tutelado",
+  tutelado: "tutor",
+};
+
+export function suggestReciprocalRelation(relation: string): string | null {
+  return RECIPROCAL_RELATIONS[normText(relation)] ?? null;
 }
 
-export async function listAllFiles(): Promise<PatientFile[]> {
+export type RelatedPatient = { relation: string; patient: Patient };
+
+function addRelationRow(s: Store, patientId: string, relatedId: string, relation: string) {
+  if (s.patientRelations.some((r) => r.patient_id === patientId && r.related_id === relatedId)) throw new Error(t("relation_exists"));
+  s.patientRelations.push({ patient_id: patientId, related_id: relatedId, relation, created_at: nowIso() });
+}
+
+/**
+ * Records that `relatedId` is the `relation` of `patientId`. The reverse link is added too, labelled
+ * `reciprocal` or, when that's undefined, suggestReciprocalRelation(relation); pass null to skip it.
+ */
+export const addRelation = command("add_relation", async (patientId: string, relatedId: string, relation: string, reciprocal?: string | null): Promise<RelatedPatient[]> => {
+  const label = (relation ?? "").trim();
+  if (!label) throw new Error(t("invalid_field_value", { field: "relation" }));
+  if (patientId === relatedId) throw new Error(t("self_relation"));
+  const back = reciprocal === undefined ? suggestReciprocalRelation(label) : reciprocal?.trim() || null;
+  await commit((s) => {
+    if (!s.patients.some((p) => p.id === patientId) || !s.patients.some((p) => p.id === relatedId)) throw new Error(t("patient_not_found"));
+    addRelationRow(s, patientId, relatedId, label);
+    if (back && !s.patientRelations.some((r) => r.patient_id === relatedId && r.related_id === patientId)) addRelationRow(s, relatedId, patientId, back);
+  });
+  return listRelations(patientId);
+});
+
+// Removes the link in both directions.
+export const removeRelation = command("remove_relation", async (patientId: string, relatedId: string): Promise<void> => {
+  await commit((s) => {
+    s.patientRelations = s.patientRelations.filter(
+      (r) => !((r.patient_id === patientId && r.related_id === relatedId) || (r.patient_id === relatedId && r.related_id === patientId)),
+    );
+  });
+});
+
+export const listRelations = command("list_relations", async (patientId: string): Promise<RelatedPatient[]> => {
   const store = await getStore();
-  return store.files;
+  const byId = new Map(store.patients.map((p) => [p.id, p]));
+  return store.patientRelations
+    .filter((r) => r.patient_id === patientId && byId.has(r.related_id))
+    .map((r) => ({ relation: r.relation, patient: rowToPatient(byId.get(r.related_id) as Patient) }));
+});
+
+export const BULK_ACTIONS = ["add_tag", "remove_tag", "archive", "unarchive", "set_status"] as const;
+
+export type BulkAction = (typeof BULK_ACTIONS)[number];
+
+export type BulkResult = { patient_id: string; ok: boolean; error?: string };
+
+const MAX_TAG_LENGTH = 40;
+
+/**
+ * Applies one action to many patients in a single save. Unknown ids fail on their own and are
+ * reported; the rest still go through. The action/value pair is checked before anything changes.
+ */
+export const bulkApply = command("bulk_apply", async (patientIds: string[], action: string, value?: string | null): Promise<BulkResult[]> => {
+  if (!(BULK_ACTIONS as readonly string[]).includes(action)) throw new Error(t("unknown_bulk_action", { action }));
+  const store = await getStore();
+  const needsValue = action === "add_tag" || action === "remove_tag" || action === "set_status";
+  const clean = (value ?? "").trim();
+  if (needsValue && (!clean || clean.length > MAX_TAG_LENGTH)) throw new Error(t("invalid_field_value", { field: "value" }));
+  if (!needsValue && value != null) throw new Error(t("invalid_field_value", { field: "value" }));
+  if (action === "set_status") validateStatus(store, clean);
+
+  return commit((s) => {
+    const iso = nowIso();
+    return patientIds.map((patientId): BulkResult => {
+      const i = s.patients.findIndex((p) => p.id === patientId);
+      if (i === -1) return { patient_id: patientId, ok: false, error: t("patient_not_found") };
+      const p = s.patients[i];
+      const updated: Patient = { ...p, updated_at: iso };
+      if (action === "add_tag") updated.tags = p.tags.includes(clean) ? p.tags : [...p.tags, clean];
+      else if (action === "remove_tag") updated.tags = p.tags.filter((x) => x !== clean);
+      else if (action === "archive") updated.archived_at = p.archived_at ?? iso;
+      else if (action === "unarchive") updated.archived_at = null;
+      else updated.status = clean;
+      s.patients[i] = updated;
+      return { patient_id: patientId, ok: true };
+    });
+  });
+});
+
+export const setAutoArchiveAfterDays = command("set_auto_archive_after_days", async (days: number | null): Promise<number | null> => {
+  return (await setSetting("auto_archive_after_days", days)) as number | null;
+});
+
+export type RetentionResult = { dry_run: boolean; archived: number; patient_ids: string[] };
+
+// Latest of the patient's own edits, files and appointments.
+function lastActivity(store: Store, patient: Patient) {
+  let last = patient.updated_at;
+  for (const f of store.files) if (f.patient_id === patient.id && f.created_at > last) last = f.created_at;
+  for (const a of store.appointments) if (a.patient_id === patient.id && a.start_iso > last) last = a.start_iso;
+  return last;
 }
 
-export async function createMentalExam(patientId: string, payload: any): Promise<PatientFile> {
+/**
+ * Archives (never deletes) patients idle for longer than settings.auto_archive_after_days.
+ * Already-archived patients and anyone with a future appointment are skipped, so reruns are
+ * no-ops; `dryRun` returns the same result without writing.
+ */
+export const runRetention = command("run_retention", async (dryRun = false): Promise<RetentionResult> => {
   const store = await getStore();
+  const days = store.settings.auto_archive_after_days;
+  if (!days) return { dry_run: dryRun, archived: 0, patient_ids: [] };
+  const now = nowIso();
+  const cutoff = new Date(Date.now() - days * 86_400_000).toISOString();
+  const upcoming = new Set(store.appointments.filter((a) => a.start_iso > now).map((a) => a.patient_id));
+  const ids = store.patients
+    .filter((p) => !p.archived_at && !upcoming.has(p.id) && lastActivity(store, p) < cutoff)
+    .map((p) => p.id);
+  if (!dryRun && ids.length) {
+    await commit((s) => {
+      const set = new Set(ids);
+      // updated_at is left alone: archiving isn't activity.
+      s.patients = s.patients.map((p) => (set.has(p.id) && !p.archived_at ? { ...p, archived_at: now } : p));
+    });
+  }
+  return { dry_run: dryRun, archived: ids.length, patient_ids: ids };
+});
+
+export type DeletePreview = {
+  patient: Patient;
+  files: number; // attachments and photos
+  exams: number;
+  notes: number;
+  trashed_files: number;
+  appointments: number;
+  relations: number;
+  total_bytes: number;
+  on_disk_files: number;
+  removes_disk_files: boolean; // deletePatient only drops records; on-disk assets stay (see scanOrphanFiles)
+};
+
+/** What deletePatient(patientId) would remove, for the confirmation dialog. Changes nothing. */
+export const deletePatientPreview = command("delete_patient_preview", async (patientId: string): Promise<DeletePreview> => {
+  const store = await getStore();
+  const patient = store.patients.find((p) => p.id === patientId);
+  if (!patient) throw new Error(t("patient_not_found"));
+  const files = store.files.filter((f) => f.patient_id === patientId);
+  const trashed = store.trash.filter((x) => x.file.patient_id === patientId);
+  const sizes = await Promise.all([...files.map((f) => f.path), ...trashed.map((x) => x.file.path)].map(storedSize));
+  return {
+    patient: rowToPatient(patient),
+    files: files.filter((f) => f.kind === "attachment" || f.kind === "photo").length,
+    exams: files.filter((f) => f.kind === "exam").length,
+    notes: files.filter((f) => f.kind === "note").length,
+    trashed_files: trashed.length,
+    appointments: store.appointments.filter((a) => a.patient_id === patientId).length,
+    relations: store.patientRelations.filter((r) => r.patient_id === patientId || r.related_id === patientId).length,
+    total_bytes: sizes.reduce((sum, n) => sum + n, 0),
+    on_disk_files: files.filter((f) => f.path.startsWith("/__naju_asset/")).length,
+    removes_disk_files: false,
+  };
+});
+
+export const deletePatient = command("delete_patient", async (patientId: string): Promise<void> => {
+  const store = await getStore();
+  store.patients = store.patients.filter((p) => p.id !== patientId);
+  store.files = store.files.filter((f) => f.patient_id !== patientId);
+  store.trash = store.trash.filter((t) => t.file.patient_id !== patientId);
+  store.appointments = store.appointments.filter((a) => a.patient_id !== patientId);
+  store.customValues = store.customValues.filter((v) => v.patient_id !== patientId);
+  store.patientHistory = store.patientHistory.filter((r) => r.patient_id !== patientId);
+  store.patientRelations = store.patientRelations.filter((r) => r.patient_id !== patientId && r.related_id !== patientId);
+  store.patientLinks = store.patientLinks.filter((l) => l.patient_id !== patientId);
+  await persistStore(store);
+});
+
+// Detects the image format from the file's magic bytes; the extension and MIME type are not trusted.
+async function sniffImageType(file: Blob): Promise<"png" | "jpeg" | "gif" | "webp" | "bmp" | null> {
+  const b = new Uint8Array(await file.slice(0, 12).arrayBuffer());
+  const ascii = (from: number, to: number) => String.fromCharCode(...b.subarray(from, to));
+  if (b.length >= 8 && b[0] === 0x89 && ascii(1, 4) === "PNG" && b[4] === 0x0d && b[5] === 0x0a) return "png";
+  if (b.length >= 3 && b[0] === 0xff && b[1] === 0xd8 && b[2] === 0xff) return "jpeg";
+  if (ascii(0, 4) === "GIF8") return "gif";
+  if (ascii(0, 4) === "RIFF" && ascii(8, 12) === "WEBP") return "webp";
+  if (ascii(0, 2) === "BM") return "bmp";
+  return null;
+}
+
+// Every upload is kept as a kind="photo" file; photo_path points at the primary one.
+export const setPatientPhoto = command("set_patient_photo", async (patientId: string, file: File): Promise<Patient> => {
+  const store = await getStore();
+  if (!store.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+  if (!(await sniffImageType(file))) throw new Error(t("photo_not_image", { name: file.name }));
+  await checkFreeSpace(file.size);
   const createdAt = nowIso();
-  const filename = `examen-${createdAt.slice(0, 10)}.json`;
-  const json = JSON.stringify(payload, null, 2);
-  const dataUrl = `data:application/json;charset=utf-8,${encodeURIComponent(json)}`;
-  const entry: PatientFile = {
-    id: store.nextFileId++,
+  const draft: NewFile = { ...(await uploadedFileEntry(patientId, file, file.name, createdAt)), kind: "photo" };
+  return commit((s) => {
+    const i = s.patients.findIndex((p) => p.id === patientId);
+    if (i === -1) throw new Error(t("patient_not_found"));
+    const current = s.patients[i];
+    const drafts: NewFile[] = [];
+    // A photo set before the gallery existed lives only on photo_path: keep it in the history.
+    if (current.photo_path && !s.files.some((f) => f.patient_id === patientId && f.kind === "photo" && f.path === current.photo_path)) {
+      drafts.push({ patient_id: patientId, kind: "photo", filename: "foto-anterior", created_at: current.updated_at, path: current.photo_path, meta_json: null });
+    }
+    drafts.push(draft);
+    const added = addFiles(s, drafts);
+    const updated: Patient = { ...current, photo_path: added[added.length - 1].path, updated_at: createdAt };
+    s.patients[i] = updated;
+    return updated;
+  });
+});
+
+export type PatientPhoto = PatientFile & { primary: boolean };
+
+export const listPatientPhotos = command("list_patient_photos", async (patientId: string): Promise<PatientPhoto[]> => {
+  const store = await getStore();
+  const patient = store.patients.find((p) => p.id === patientId);
+  if (!patient) throw new Error(t("patient_not_found"));
+  return store.files
+    .filter((f) => f.patient_id === patientId && f.kind === "photo")
+    .sort((a, b) => b.created_at.localeCompare(a.created_at) || b.id - a.id)
+    .map((f) => ({ ...f, primary: f.path === patient.photo_path }));
+});
+
+export const setPrimaryPhoto = command("set_primary_photo", async (patientId: string, fileId: number): Promise<Patient> => {
+  return commit((s) => {
+    const i = s.patients.findIndex((p) => p.id === patientId);
+    if (i === -1) throw new Error(t("patient_not_found"));
+    const photo = s.files.find((f) => f.id === fileId && f.patient_id === patientId && f.kind === "photo");
+    if (!photo) throw new Error(t("file_not_found"));
+    const updated: Patient = { ...s.patients[i], photo_path: photo.path, updated_at: nowIso() };
+    s.patients[i] = updated;
+    return updated;
+  });
+});
+
+// Back to the default avatar. The photo stays in the gallery unless `trashFile`, which sends it to the trash.
+export const removePatientPhoto = command("remove_patient_photo", async (patientId: string, trashFile = false): Promise<Patient> => {
+  const store = await getStore();
+  const previous = store.patients.find((p) => p.id === patientId)?.photo_path ?? null;
+  const trashedPath = trashFile && previous && store.files.some((f) => f.patient_id === patientId && f.kind === "photo" && f.path === previous)
+    ? await moveToTrash(previous)
+    : null;
+  const updated = await commit(
+    (s) => {
+      const i = s.patients.findIndex((p) => p.id === patientId);
+      if (i === -1) throw new Error(t("patient_not_found"));
+      const iso = nowIso();
+      if (trashFile && previous) {
+        const idx = s.files.findIndex((f) => f.patient_id === patientId && f.kind === "photo" && f.path === previous);
+        if (idx !== -1) {
+          const [file] = s.files.splice(idx, 1);
+          s.trash.unshift({ file: { ...file, path: trashedPath ?? file.path }, original_path: file.path, deleted_at: iso });
+        }
+      }
+      const patient: Patient = { ...s.patients[i], photo_path: null, updated_at: iso };
+      s.patients[i] = patient;
+      return patient;
+    },
+    async () => {
+      if (trashedPath && previous) await trashRequest({ action: "restore", path: trashedPath, target: previous }).catch(() => null);
+    },
+  );
+  return rowToPatient(updated);
+});
+
+async function sha256Hex(bytes: Uint8Array): Promise<string> {
+  const digest = await crypto.subtle.digest("SHA-256", bytes);
+  return Array.from(new Uint8Array(digest), (b) => b.toString(16).padStart(2, "0")).join("");
+}
+
+function dataUrlToBytes(url: string): Uint8Array {
+  const comma = url.indexOf(",");
+  const header = url.slice(0, comma);
+  const body = url.slice(comma + 1);
+  if (header.endsWith(";base64")) {
+    const bin = atob(body);
+    const out = new Uint8Array(bin.length);
+    for (let i = 0; i < bin.length; i++) out[i] = bin.charCodeAt(i);
+    return out;
+  }
+  return new TextEncoder().encode(decodeURIComponent(body));
+}
+
+// Bytes behind a stored path: inline data URLs or dev assets. External links return null.
+async function storedFileBytes(path: string): Promise<Uint8Array | null> {
+  if (path.startsWith("data:")) return dataUrlToBytes(path);
+  if (!path.startsWith("/__naju_asset/")) return null;
+  const res = await fetch(path, { cache: "no-store" });
+  if (!res.ok) throw new Error(t("file_not_found"));
+  return new Uint8Array(await res.arrayBuffer());
+}
+
+// Formats that are compressed already; gzip would only cost time.
+const COMPRESSED_EXTENSIONS = [
+  "jpg", "jpeg", "png", "gif", "webp", "heic", "avif", "zip", "gz", "7z", "rar", "docx", "xlsx", "pptx", "odt", "mp3", "mp4", "m4a", "mov",
+];
+
+// Below this saving the original is kept: not worth a decompression on every read.
+const MIN_COMPRESSION_GAIN = 0.1;
+
+async function gzipBytes(bytes: Uint8Array) {
+  return new Uint8Array(await new Response(new Blob([bytes]).stream().pipeThrough(new CompressionStream("gzip"))).arrayBuffer());
+}
+
+async function gunzipBytes(bytes: Uint8Array) {
+  return new Uint8Array(await new Response(new Blob([bytes]).stream().pipeThrough(new DecompressionStream("gzip"))).arrayBuffer());
+}
+
+function isCompressedFile(file: PatientFile) {
+  return parseJsonOrNull(file.meta_json)?.compressed === true;
+}
+
+// The file's original content: stored bytes, gunzipped when it was stored compressed.
+async function fileContentBytes(file: PatientFile): Promise<Uint8Array | null> {
+  const bytes = await storedFileBytes(file.path);
+  return bytes && isCompressedFile(file) ? gunzipBytes(bytes) : bytes;
+}
+
+/**
+ * With `compress`, compressible files are stored gzipped (application/gzip, meta `compressed`
+ * with the original mime and size) when that saves at least MIN_COMPRESSION_GAIN. Like encrypted
+ * files, the hash is of the stored bytes so verifyPatientFiles still works.
+ */
+async function uploadedFileEntry(patientId: string, file: File, filename: string, createdAt: string, compress = false): Promise<NewFile> {
+  if (compress && !COMPRESSED_EXTENSIONS.includes(fileExt(file.name))) {
+    const original = new Uint8Array(await file.arrayBuffer());
+    const packed = await gzipBytes(original);
+    if (packed.length <= original.length * (1 - MIN_COMPRESSION_GAIN)) {
+      return {
+        patient_id: patientId,
+        kind: "attachment",
+        filename,
+        created_at: createdAt,
+        path: `data:application/gzip;base64,${bytesToBase64(packed)}`,
+        meta_json: JSON.stringify({ compressed: true, mime: file.type || MIME_BY_EXT[fileExt(file.name)] || "application/octet-stream", original_size: original.length }),
+        sha256: await sha256Hex(packed),
+      };
+    }
+  }
+  const dataUrl = await readFileAsDataUrl(file);
+  return {
     patient_id: patientId,
-    kind: "exam",
+    kind: "attachment",
     filename,
     created_at: createdAt,
     path: dataUrl,
-    meta_json: json,
+    meta_json: null,
+    sha256: await sha256Hex(new Uint8Array(await file.arrayBuffer())),
+  };
+}
+
+const FILENAME_PLACEHOLDERS = ["doc", "name", "date", "time", "orig", "n"] as const;
+
+function validateFilenameTemplate(template: string) {
+  const unknown = Array.from(template.matchAll(/\{([^}]*)\}/g), (m) => m[1]).filter(
+    (k) => !(FILENAME_PLACEHOLDERS as readonly string[]).includes(k)
+  );
+  if (unknown.length) throw new Error(t("unknown_placeholders", { names: unknown.join(", ") }));
+}
+
+// Expands e.g. "{doc}_{date}_{orig}"; the result is sanitized and keeps the source extension.
+function expandFilenameTemplate(template: string, patient: Patient, file: File, n: number, createdAt: string) {
+  const ext = fileExt(file.name);
+  const base = ext ? file.name.slice(0, -(ext.length + 1)) : file.name;
+  const values: Record<string, string> = {
+    doc: patient.doc_number ?? "",
+    name: patient.name,
+    date: clinicIso(new Date(createdAt)).slice(0, 10),
+    time: clinicIso(new Date(createdAt)).slice(11, 19).replace(/:/g, ""),
+    orig: base,
+    n: String(n),
+  };
+  const expanded = sanitizeSegment(stripAccents(template.replace(/\{(\w+)\}/g, (_m, k: string) => values[k] ?? "")));
+  if (!expanded) return file.name;
+  return ext ? `${expanded}.${ext}` : expanded;
+}
+
+export const setImportFilenameTemplate = command("set_import_filename_template", async (template: string): Promise<string> => {
+  return (await setSetting("import_filename_template", template)) as string;
+});
+
+export type ImportFailure = { source: string; reason: string };
+
+// Every source ends up in exactly one of `imported` / `failed`; one bad file doesn't stop the batch.
+export type ImportResult = {
+  imported: PatientFile[];
+  failed: ImportFailure[];
+  warning: string | null;
+  saved_bytes: number; // disk saved by settings.compress_attachments
+};
+
+// Bytes a batch of drafts saves against the originals (only compressed drafts differ).
+function compressionSavings(drafts: NewFile[]) {
+  return drafts.reduce((sum, d) => {
+    const meta = parseJsonOrNull(d.meta_json);
+    return meta?.compressed === true ? sum + Math.max(0, Number(meta.original_size) - dataUrlToBytes(d.path).length) : sum;
+  }, 0);
+}
+
+// Checks settings.allowed_import_extensions (case-insensitive); a refused file is added to `failed`.
+function importAllowed(store: Store, file: File, source: string, failed: ImportFailure[]) {
+  const allowed = store.settings.allowed_import_extensions;
+  const ext = fileExt(file.name);
+  if (!allowed.length || allowed.includes(ext)) return true;
+  failed.push({ source, reason: t("extension_not_allowed", { ext: ext || "—", allowed: allowed.join(", ") }) });
+  return false;
+}
+
+function failureReason(e: any) {
+  return String(e?.message || e || t("file_read_failed"));
+}
+
+// Advisory only: a big jump usually means a batch went to the wrong patient.
+function fileCountWarning(store: Store, patientId: string) {
+  const count = store.files.filter((f) => f.patient_id === patientId).length;
+  const max = store.settings.max_files_warning;
+  return max > 0 && count > max ? t("too_many_files", { count, max }) : null;
+}
+
+export const setMaxFilesWarning = command("set_max_files_warning", async (max: number): Promise<number> => {
+  return (await setSetting("max_files_warning", max)) as number;
+});
+
+export const patientFileCount = command("patient_file_count", async (patientId: string): Promise<number> => {
+  const store = await getStore();
+  if (!store.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+  return store.files.filter((f) => f.patient_id === patientId).length;
+});
+
+// Key for encrypted attachments; only in memory, gone on reload until unlockEncryption runs again.
+let encryptionKey: CryptoKey | null = null;
+
+const ENCRYPTION_CHECK_TEXT = "naju";
+
+function isEncryptedFile(file: PatientFile) {
+  return parseJsonOrNull(file.meta_json)?.encrypted === true;
+}
+
+/**
+ * Unlocks encrypted attachments for this session. The first call sets the passphrase (stores a
+ * salt and a check value); later calls must use the same passphrase.
+ */
+export const unlockEncryption = command("unlock_encryption", async (passphrase: string): Promise<void> => {
+  if (!passphrase) throw new Error(t("wrong_passphrase"));
+  const store = await getStore();
+  const { encryption_salt, encryption_check } = store.settings;
+  if (!encryption_salt || !encryption_check) {
+    // Exempt from the read-only check in command() only because unlocking keeps the key in memory.
+    if (isReadOnlyMode()) throw new Error(t("read_only_mode"));
+    const salt = randomBytes(16);
+    const key = await deriveKey(passphrase, salt);
+    const check = await encryptBytes(key, new TextEncoder().encode(ENCRYPTION_CHECK_TEXT));
+    store.settings = {
+      ...store.settings,
+      encryption_salt: bytesToBase64(salt),
+      encryption_check: JSON.stringify({ nonce: bytesToBase64(check.nonce), data: bytesToBase64(check.data) }),
+    };
+    await persistStore(store);
+    encryptionKey = key;
+    return;
+  }
+  const key = await deriveKey(passphrase, base64ToBytes(encryption_salt));
+  try {
+    const check = JSON.parse(encryption_check);
+    await decryptBytes(key, base64ToBytes(check.nonce), base64ToBytes(check.data));
+  } catch {
+    throw new Error(t("wrong_passphrase"));
+  }
+  encryptionKey = key;
+});
+
+export const lockEncryption = command("lock_encryption", async (): Promise<void> => {
+  encryptionKey = null;
+});
+
+// Ciphertext is stored like any other inline file; the hash is of the ciphertext so verifyPatientFiles still works.
+async function encryptedFileEntry(patientId: string, file: File, filename: string, createdAt: string, key: CryptoKey): Promise<NewFile> {
+  const { nonce, data } = await encryptBytes(key, new Uint8Array(await file.arrayBuffer()));
+  return {
+    patient_id: patientId,
+    kind: "attachment",
+    filename,
+    created_at: createdAt,
+    path: `data:application/octet-stream;base64,${bytesToBase64(data)}`,
+    meta_json: JSON.stringify({ encrypted: true, nonce: bytesToBase64(nonce), mime: file.type || "application/octet-stream" }),
+    sha256: await sha256Hex(data),
+  };
+}
+
+export type DecryptedFile = { url: string; filename: string; mime: string };
+
+// Plaintext of an encrypted file with the session key.
+async function decryptedFileBytes(file: PatientFile): Promise<Uint8Array> {
+  const meta = parseJsonOrNull(file.meta_json);
+  if (meta?.encrypted !== true) throw new Error(t("not_encrypted"));
+  if (!encryptionKey) throw new Error(t("encryption_locked"));
+  const data = await storedFileBytes(file.path);
+  if (!data) throw new Error(t("file_not_found"));
+  try {
+    return await decryptBytes(encryptionKey, base64ToBytes(String(meta.nonce)), data);
+  } catch {
+    throw new Error(t("decrypt_failed"));
+  }
+}
+
+function decryptedFileUrl(file: PatientFile, plain: Uint8Array): DecryptedFile {
+  const mime = String(parseJsonOrNull(file.meta_json)?.mime || "application/octet-stream");
+  return { url: URL.createObjectURL(new Blob([plain], { type: mime })), filename: file.filename, mime };
+}
+
+// Decrypts into a blob: URL (the browser's temp storage); call URL.revokeObjectURL when done with it.
+export const readEncryptedFile = command("read_encrypted_file", async (fileId: number): Promise<DecryptedFile> => {
+  const file = (await getStore()).files.find((f) => f.id === fileId);
+  if (!file) throw new Error(t("file_not_found"));
+  return decryptedFileUrl(file, await decryptedFileBytes(file));
+});
+
+/**
+ * A blob: URL (the browser's temp storage) with the file's original content, for previewing or
+ * opening a file stored compressed or encrypted. Revoke it with URL.revokeObjectURL when done.
+ */
+export const readStoredFile = command("read_stored_file", async (fileId: number): Promise<DecryptedFile> => {
+  const file = readableFile(await getStore(), fileId);
+  if (isEncryptedFile(file)) return decryptedFileUrl(file, await decryptedFileBytes(file));
+  const bytes = await fileContentBytes(file);
+  if (!bytes) throw new Error(t("file_not_found"));
+  const mime = String(parseJsonOrNull(file.meta_json)?.mime || MIME_BY_EXT[fileExt(file.filename)] || "application/octet-stream");
+  return { url: URL.createObjectURL(new Blob([bytes], { type: mime })), filename: file.filename, mime };
+});
+
+export type ImportOptions = { encrypt?: boolean; appointment_id?: number | null };
+
+// The visit a new file is being filed under must be one of the same patient's appointments.
+function assertAppointmentOfPatient(store: Store, appointmentId: number, patientId: string) {
+  const appointment = store.appointments.find((a) => a.id === appointmentId);
+  if (!appointment) throw new Error(t("appointment_not_found"));
+  if (appointment.patient_id !== patientId) throw new Error(t("appointment_not_of_patient"));
+}
+
+function withAppointment<F extends { appointment_id?: number }>(file: F, appointmentId: number | null | undefined): F {
+  const copy = { ...file };
+  if (appointmentId == null) delete copy.appointment_id;
+  else copy.appointment_id = appointmentId;
+  return copy;
+}
+
+export const importFiles = command("import_files", async (patientId: string, files: File[], options: ImportOptions = {}): Promise<ImportResult> => {
+  const store = await getStore();
+  const patient = store.patients.find((p) => p.id === patientId);
+  if (!patient) throw new Error(t("patient_not_found"));
+  const key = options.encrypt ? encryptionKey : null;
+  if (options.encrypt && !key) throw new Error(t("encryption_locked"));
+  if (options.appointment_id != null) assertAppointmentOfPatient(store, options.appointment_id, patientId);
+  const failed: ImportFailure[] = [];
+  const allowed = files.filter((file) => importAllowed(store, file, file.name, failed));
+  await checkFreeSpace(allowed.reduce((sum, f) => sum + f.size, 0));
+  const createdAt = nowIso();
+  const template = store.settings.import_filename_template;
+  const drafts: NewFile[] = [];
+  for (const file of allowed) {
+    try {
+      const filename = template ? expandFilenameTemplate(template, patient, file, drafts.length + 1, createdAt) : file.name;
+      const draft = key
+        ? await encryptedFileEntry(patientId, file, filename, createdAt, key)
+        : await uploadedFileEntry(patientId, file, filename, createdAt, store.settings.compress_attachments);
+      drafts.push(withAppointment(draft, options.appointment_id));
+    } catch (e) {
+      failed.push({ source: file.name, reason: failureReason(e) });
+    }
+  }
+  if (!drafts.length) return { imported: [], failed, warning: null, saved_bytes: 0 };
+  return commit((s) => {
+    if (!s.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+    if (options.appointment_id != null) assertAppointmentOfPatient(s, options.appointment_id, patientId);
+    const added = addFiles(s, drafts);
+    return { imported: added, failed, warning: fileCountWarning(s, patientId), saved_bytes: compressionSavings(drafts) };
+  });
+});
+
+const MAX_URL_DOWNLOAD_BYTES = 50 * 1024 * 1024;
+
+export type DownloadProgress = (received: number, total: number | null) => void;
+
+/**
+ * Imports a referral link (http/https) through the local server, which enforces the size cap and
+ * timeout. The file then goes through importFiles like any picked file.
+ */
+export const importFromUrl = command("import_from_url", async (patientId: string, url: string, onProgress?: DownloadProgress): Promise<PatientFile> => {
+  let parsed: URL;
+  try {
+    parsed = new URL(url.trim());
+  } catch {
+    throw new Error(t("invalid_url"));
+  }
+  if (parsed.protocol !== "http:" && parsed.protocol !== "https:") throw new Error(t("invalid_url"));
+  if (!(await getStore()).patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+
+  let res: Response;
+  try {
+    res = await fetch(`${FETCH_URL_ENDPOINT}?url=${encodeURIComponent(parsed.href)}&max=${MAX_URL_DOWNLOAD_BYTES}`, { cache: "no-store" });
+  } catch {
+    throw new Error(t("local_server_required"));
+  }
+  if (!res.ok || !res.body) {
+    const j = await res.json().catch(() => null);
+    throw new Error(t("download_failed", { reason: j ? serverError(j).message : String(res.status) }));
+  }
+  const total = Number(res.headers.get("content-length")) || null;
+  const chunks: Uint8Array[] = [];
+  let received = 0;
+  try {
+    const reader = res.body.getReader();
+    for (;;) {
+      const { done, value } = await reader.read();
+      if (done) break;
+      chunks.push(value);
+      received += value.length;
+      onProgress?.(received, total);
+    }
+  } catch {
+    throw new Error(t("download_incomplete"));
+  }
+  if (total !== null && received !== total) throw new Error(t("download_incomplete"));
+
+  const suggested = safeDecode(res.headers.get("x-naju-filename") || "");
+  const dot = suggested.lastIndexOf(".");
+  const base = sanitizeSegment(stripAccents(dot > 0 ? suggested.slice(0, dot) : suggested)) || "descarga";
+  const ext = dot > 0 ? sanitizeSegment(suggested.slice(dot + 1)) : "";
+  const type = res.headers.get("content-type") || "application/octet-stream";
+  const file = new File(chunks, ext ? `${base}.${ext}` : base, { type });
+  const { imported, failed } = await importFiles(patientId, [file]);
+  if (!imported.length) throw new Error(failed[0]?.reason ?? t("file_read_failed"));
+  return imported[0];
+});
+
+const MIME_BY_EXT: Record<string, string> = {
+  pdf: "application/pdf",
+  txt: "text/plain",
+  json: "application/json",
+  csv: "text/csv",
+  html: "text/html",
+  webm: "audio/webm",
+  mp3: "audio/mpeg",
+  wav: "audio/wav",
+};
+
+// "informe.pdf" -> "informe_2.pdf" -> "informe_3.pdf" until it doesn't clash with the patient's files.
+function uniqueFilename(taken: Set<string>, filename: string) {
+  if (!taken.has(filename.toLowerCase())) return filename;
+  const dot = filename.lastIndexOf(".");
+  const base = dot > 0 ? filename.slice(0, dot) : filename;
+  const ext = dot > 0 ? filename.slice(dot) : "";
+  let n = 2;
+  while (taken.has(`${base}_${n}${ext}`.toLowerCase())) n++;
+  return `${base}_${n}${ext}`;
+}
+
+/**
+ * For content that only exists in memory (webcam capture, generated report). `base64Data` may be
+ * bare base64 or a full data URL; photos are checked by magic bytes like setPatientPhoto.
+ */
+export const saveFileFromBytes = command("save_file_from_bytes", async (
+  patientId: string,
+  filename: string,
+  base64Data: string,
+  kind: FileKind = "attachment",
+  appointmentId: number | null = null
+): Promise<PatientFile> => {
+  if (!FILE_KINDS.includes(kind)) throw new Error(t("invalid_field_value", { field: "kind" }));
+  const store = await getStore();
+  if (!store.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+  if (appointmentId !== null) assertAppointmentOfPatient(store, appointmentId, patientId);
+
+  let bytes: Uint8Array;
+  try {
+    bytes = base64ToBytes(base64Data.slice(base64Data.indexOf(",") + 1).replace(/\s+/g, ""));
+  } catch {
+    throw new Error(t("invalid_base64"));
+  }
+  await checkFreeSpace(bytes.length);
+
+  const image = await sniffImageType(new Blob([bytes]));
+  if (kind === "photo" && !image) throw new Error(t("not_an_image"));
+  const ext = image ? (image === "jpeg" ? "jpg" : image) : fileExt(filename);
+  const mime = image ? `image/${image}` : MIME_BY_EXT[ext] || "application/octet-stream";
+  const dot = filename.lastIndexOf(".");
+  const base = sanitizeSegment(stripAccents(dot > 0 ? filename.slice(0, dot) : filename)) || "archivo";
+  const createdAt = nowIso();
+  const draft: NewFile = {
+    patient_id: patientId,
+    kind,
+    filename: ext ? `${base}.${ext}` : base,
+    created_at: createdAt,
+    path: await readFileAsDataUrl(new Blob([bytes], { type: mime })),
+    meta_json: null,
+    sha256: await sha256Hex(bytes),
+  };
+
+  return commit((s) => {
+    if (!s.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+    if (appointmentId !== null) assertAppointmentOfPatient(s, appointmentId, patientId);
+    const taken = new Set(s.files.filter((f) => f.patient_id === patientId).map((f) => f.filename.toLowerCase()));
+    return addFiles(s, [withAppointment({ ...draft, filename: uniqueFilename(taken, draft.filename) }, appointmentId)])[0];
+  });
+});
+
+export type ImportProgress = { done: number; total: number; filename: string };
+
+function fileExt(name: string) {
+  const dot = name.lastIndexOf(".");
+  return dot > 0 ? name.slice(dot + 1).toLowerCase() : "";
+}
+
+function sanitizeSegment(input: string) {
+  return input
+    .trim()
+    .replace(/[^\w.\-]+/g, "_")
+    .replace(/_+/g, "_")
+    .replace(/^_+|_+$/g, "");
+}
+
+// Expects the File list of an <input webkitdirectory>; webkitRelativePath is "<root>/<sub>/.../<name>".
+export const importDirectory = command("import_directory", async (
+  patientId: string,
+  files: File[],
+  recursive = true,
+  extensions?: string[] | null,
+  onProgress?: (p: ImportProgress) => void
+): Promise<ImportResult> => {
+  const store = await getStore();
+  if (!store.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+
+  const exts = (extensions ?? []).map((e) => e.trim().replace(/^\./, "").toLowerCase()).filter(Boolean);
+  const failed: ImportFailure[] = [];
+  // `extensions` narrows this one import silently; files outside the settings allowlist are reported.
+  const picked = files
+    .map((file) => ({ file, parts: ((file as any).webkitRelativePath || file.name).split("/").filter(Boolean) as string[] }))
+    .filter(({ file, parts }) => {
+      if (!recursive && parts.length > 2) return false;
+      if (exts.length && !exts.includes(fileExt(file.name))) return false;
+      return importAllowed(store, file, parts.join("/"), failed);
+    });
+  await checkFreeSpace(picked.reduce((sum, { file }) => sum + file.size, 0));
+
+  const createdAt = nowIso();
+  const drafts: NewFile[] = [];
+  for (const [i, { file, parts }] of picked.entries()) {
+    const prefix = parts.slice(1, -1).map(sanitizeSegment).filter(Boolean);
+    const filename = [...prefix, file.name].join("_");
+    try {
+      drafts.push(await uploadedFileEntry(patientId, file, filename, createdAt, store.settings.compress_attachments));
+    } catch (e) {
+      failed.push({ source: parts.join("/"), reason: failureReason(e) });
+    }
+    onProgress?.({ done: i + 1, total: picked.length, filename });
+  }
+  if (!drafts.length) return { imported: [], failed, warning: null, saved_bytes: 0 };
+  return commit((s) => {
+    if (!s.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+    const added = addFiles(s, drafts);
+    return { imported: added, failed, warning: fileCountWarning(s, patientId), saved_bytes: compressionSavings(drafts) };
+  });
+});
+
+export type ListFilesOptions = {
+  include_archived?: boolean;
+  sort?: "newest" | "manual"; // manual: sort_order first (unordered files last), then oldest first
+};
+
+function manualOrder(a: PatientFile, b: PatientFile) {
+  const ao = a.sort_order ?? Number.MAX_SAFE_INTEGER;
+  const bo = b.sort_order ?? Number.MAX_SAFE_INTEGER;
+  return ao - bo || a.created_at.localeCompare(b.created_at);
+}
+
+// Pinned files lead whichever order was asked for.
+export const listPatientFiles = command("list_patient_files", async (patientId: string, options: ListFilesOptions = {}): Promise<PatientFile[]> => {
+  const store = await getStore();
+  const files = store.files.filter((f) => f.patient_id === patientId && (options.include_archived || !f.archived));
+  if (options.sort === "manual") files.sort(manualOrder);
+  return files.sort((a, b) => Number(Boolean(b.pinned)) - Number(Boolean(a.pinned)));
+});
+
+export type FileMatch = { file: PatientFile; score: number }; // 0 = the filename contains the whole query
+
+// "ECO_abdomen-2023.pdf" -> "eco abdomen 2023": separators become spaces so words line up with the query.
+function fileSearchNorm(filename: string) {
+  return normText(filename.replace(/\.[^.]+$/, "").replace(/[_\-.]+/g, " "));
+}
+
+/**
+ * Fuzzy filename search within one patient, with the same word matching as the patient search.
+ * Whole-query matches come first, then near misses by edit distance; newest first on ties.
+ */
+export const searchPatientFiles = command(
+  "search_patient_files",
+  async (patientId: string, query: string, includeArchived = false): Promise<FileMatch[]> => {
+    const store = await getStore();
+    if (!store.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+    const q = normText(String(query ?? "").replace(/[_\-.]+/g, " "));
+    if (!q) return [];
+    const matches: FileMatch[] = [];
+    for (const file of store.files) {
+      if (file.patient_id !== patientId || (file.archived && !includeArchived)) continue;
+      const name = fileSearchNorm(file.filename);
+      const score = name.includes(q) ? 0 : fuzzyNameScore(q, name);
+      if (score !== null) matches.push({ file, score });
+    }
+    return matches.sort((a, b) => a.score - b.score || b.file.created_at.localeCompare(a.file.created_at));
+  },
+);
+
+// Numbers the given files 1..n in that order (drag-to-reorder). Every id must belong to the patient.
+export const reorderFiles = command("reorder_files", async (patientId: string, orderedIds: number[]): Promise<PatientFile[]> => {
+  return commit((s) => {
+    if (!s.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+    const byId = new Map(s.files.filter((f) => f.patient_id === patientId).map((f) => [f.id, f]));
+    const foreign = orderedIds.filter((id) => !byId.has(id));
+    if (foreign.length) throw new Error(t("files_not_of_patient", { ids: foreign.join(", ") }));
+    if (new Set(orderedIds).size !== orderedIds.length) throw new Error(t("invalid_field_value", { field: "ordered_ids" }));
+    const position = new Map(orderedIds.map((id, i) => [id, i + 1]));
+    s.files = s.files.map((f) => (position.has(f.id) && f.patient_id === patientId ? { ...f, sort_order: position.get(f.id) } : f));
+    return s.files.filter((f) => f.patient_id === patientId && !f.archived).sort(manualOrder);
+  });
+});
+
+async function setFileArchived(fileId: number, archived: boolean): Promise<PatientFile> {
+  return commit((s) => {
+    const idx = s.files.findIndex((f) => f.id === fileId);
+    if (idx === -1) throw new Error(t("file_not_found"));
+    const { archived: _was, ...rest } = s.files[idx];
+    const updated: PatientFile = archived ? { ...rest, archived: true } : rest;
+    s.files[idx] = updated;
+    return updated;
+  });
+}
+
+export const archiveFile = command("archive_file", async (fileId: number): Promise<PatientFile> => {
+  return setFileArchived(fileId, true);
+});
+
+export const unarchiveFile = command("unarchive_file", async (fileId: number): Promise<PatientFile> => {
+  return setFileArchived(fileId, false);
+});
+
+const MAX_PINNED_FILES = 3;
+
+async function setFilePinned(fileId: number, pinned: boolean): Promise<PatientFile> {
+  return commit((s) => {
+    const idx = s.files.findIndex((f) => f.id === fileId);
+    if (idx === -1) throw new Error(t("file_not_found"));
+    const file = s.files[idx];
+    if (pinned && !file.pinned && s.files.filter((f) => f.patient_id === file.patient_id && f.pinned).length >= MAX_PINNED_FILES) {
+      throw new Error(t("too_many_pins", { max: MAX_PINNED_FILES }));
+    }
+    const { pinned: _was, ...rest } = file;
+    const updated: PatientFile = pinned ? { ...rest, pinned: true } : rest;
+    s.files[idx] = updated;
+    return updated;
+  });
+}
+
+export const pinFile = command("pin_file", async (fileId: number): Promise<PatientFile> => {
+  return setFilePinned(fileId, true);
+});
+
+export const unpinFile = command("unpin_file", async (fileId: number): Promise<PatientFile> => {
+  return setFilePinned(fileId, false);
+});
+
+const FILE_LABEL_MAX = 40;
+const HEX_COLOR_RE = /^#(?:[0-9a-f]{3}|[0-9a-f]{6})$/i;
+
+// Empty label or null color clears it. Colors are stored lowercase so the UI can compare them.
+export const setFileLabel = command("set_file_label", async (fileId: number, label: string | null, color: string | null): Promise<PatientFile> => {
+  const cleanLabel = String(label ?? "").trim();
+  if (cleanLabel.length > FILE_LABEL_MAX) throw new Error(t("invalid_field_value", { field: "label" }));
+  const cleanColor = String(color ?? "").trim().toLowerCase();
+  if (cleanColor && !HEX_COLOR_RE.test(cleanColor)) throw new Error(t("invalid_color", { color: String(color) }));
+  return commit((s) => {
+    const idx = s.files.findIndex((f) => f.id === fileId);
+    if (idx === -1) throw new Error(t("file_not_found"));
+    const { label: _label, color: _color, ...rest } = s.files[idx];
+    const updated: PatientFile = { ...rest, ...(cleanLabel ? { label: cleanLabel } : {}), ...(cleanColor ? { color: cleanColor } : {}) };
+    s.files[idx] = updated;
+    return updated;
+  });
+});
+
+export const listAllFiles = command("list_all_files", async (): Promise<PatientFile[]> => {
+  const store = await getStore();
+  return store.files;
+});
+
+export type AllFilesQuery = {
+  kind?: FileKind | null;
+  from?: string | null; // ISO date or datetime, inclusive
+  to?: string | null; // ISO date or datetime; a bare date includes that whole day
+  limit?: number;
+  offset?: number;
+};
+
+export type FileRow = PatientFile & { patient_name: string; url: string | null };
+
+export type FilePage = { items: FileRow[]; total: number };
+
+/** Reporting view of files across all patients, newest first, with the patient name joined in. */
+export const listAllFilesPage = command("list_all_files_page", async (query: AllFilesQuery = {}): Promise<FilePage> => {
+  const store = await getStore();
+  const names = new Map(store.patients.map((p) => [p.id, p.name]));
+  const from = query.from || "";
+  const to = query.to ? (query.to.length === 10 ? `${query.to}T\uffff` : query.to) : "";
+  const matching = store.files
+    .filter((f) => (!query.kind || f.kind === query.kind) && (!from || f.created_at >= from) && (!to || f.created_at <= to))
+    .sort((a, b) => b.created_at.localeCompare(a.created_at));
+  const offset = Math.max(0, Math.floor(query.offset ?? 0));
+  const limit = Math.min(500, Math.max(1, Math.floor(query.limit ?? 100)));
+  const items = matching.slice(offset, offset + limit).map((f) => ({
+    ...f,
+    patient_name: names.get(f.patient_id) ?? "",
+    // Inline files have no location; on-disk ones only ever resolve under /__naju_asset/.
+    url: f.path.startsWith("/__naju_asset/") ? new URL(f.path, window.location.origin).href : null,
+  }));
+  return { items, total: matching.length };
+});
+
+// Assets live flat under assets/<patientId>/ whatever their kind, so only the record changes on disk.
+// Turning an image into a photo can also make it the patient's profile photo; demoting the current
+// profile photo clears it.
+export const setFileKind = command("set_file_kind", async (fileId: number, newKind: FileKind, useAsProfilePhoto = false): Promise<PatientFile> => {
+  if (!FILE_KINDS.includes(newKind)) throw new Error(t("invalid_field_value", { field: "kind" }));
+  return commit((s) => {
+    const idx = s.files.findIndex((f) => f.id === fileId);
+    if (idx === -1) throw new Error(t("file_not_found"));
+    const file = s.files[idx];
+    if (newKind === "photo" && !isImageFile(file)) throw new Error(t("not_an_image"));
+    const updated: PatientFile = { ...file, kind: newKind };
+    s.files[idx] = updated;
+
+    const pIdx = s.patients.findIndex((p) => p.id === file.patient_id);
+    if (pIdx !== -1) {
+      const patient = s.patients[pIdx];
+      if (newKind === "photo" && useAsProfilePhoto) {
+        s.patients[pIdx] = { ...patient, photo_path: file.path, updated_at: nowIso() };
+      } else if (newKind !== "photo" && file.kind === "photo" && patient.photo_path === file.path) {
+        s.patients[pIdx] = { ...patient, photo_path: null, updated_at: nowIso() };
+      }
+    }
+    return updated;
+  });
+});
+
+// On-disk assets move in and out of assets/<patientId>/.trash/ through /__naju_trash; inline files
+// only change in the store. Returns the new path, or null when there was nothing on disk to move.
+async function trashRequest(body: { action: "move" | "restore" | "purge"; path: string; target?: string }): Promise<string | null> {
+  let j: any = null;
+  try {
+    const res = await fetch(TRASH_ENDPOINT, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(body),
+    });
+    j = await res.json();
+  } catch {
+    throw new Error(t("local_server_required"));
+  }
+  if (!j?.ok) throw serverError(j);
+  return typeof j.path === "string" ? j.path : null;
+}
+
+function moveToTrash(path: string): Promise<string | null> {
+  return /^\/__naju_asset\/[^/]+\/[^/]+$/.test(path) ? trashRequest({ action: "move", path }) : Promise.resolve(null);
+}
+
+function isInAssetTrash(entry: TrashedFile): boolean {
+  return /^\/__naju_asset\/[^/]+\/\.trash\/[^/]+$/.test(entry.file.path);
+}
+
+// Deleted files go to the trash first; emptyTrash() drops them for good after the retention window.
+export const deleteFile = command("delete_file", async (fileId: number): Promise<TrashedFile> => {
+  const store = await getStore();
+  const current = store.files.find((f) => f.id === fileId);
+  if (!current) throw new Error(t("file_not_found"));
+  const trashedPath = await moveToTrash(current.path);
+  return commit(
+    (s) => {
+      const idx = s.files.findIndex((f) => f.id === fileId);
+      if (idx === -1) throw new Error(t("file_not_found"));
+      const [file] = s.files.splice(idx, 1);
+      const entry: TrashedFile = { file: { ...file, path: trashedPath ?? file.path }, original_path: file.path, deleted_at: nowIso() };
+      s.trash.unshift(entry);
+      // Deleting the primary photo promotes the newest remaining one.
+      const pIdx = file.kind === "photo" ? s.patients.findIndex((p) => p.id === file.patient_id && p.photo_path === file.path) : -1;
+      if (pIdx !== -1) {
+        const next = s.files
+          .filter((f) => f.patient_id === file.patient_id && f.kind === "photo")
+          .sort((a, b) => b.created_at.localeCompare(a.created_at))[0];
+        s.patients[pIdx] = { ...s.patients[pIdx], photo_path: next?.path ?? null, updated_at: entry.deleted_at };
+      }
+      return entry;
+    },
+    async () => {
+      if (trashedPath) await trashRequest({ action: "restore", path: trashedPath, target: current.path }).catch(() => null);
+    },
+  );
+});
+
+export const listTrash = command("list_trash", async (patientId?: string): Promise<TrashedFile[]> => {
+  const store = await getStore();
+  return patientId ? store.trash.filter((t) => t.file.patient_id === patientId) : store.trash;
+});
+
+export const restoreTrashedFile = command("restore_trashed_file", async (fileId: number): Promise<PatientFile> => {
+  const store = await getStore();
+  const entry = store.trash.find((t) => t.file.id === fileId);
+  if (!entry) throw new Error(t("file_not_in_trash"));
+  if (!store.patients.some((p) => p.id === entry.file.patient_id)) throw new Error(t("patient_not_found"));
+  const moved = isInAssetTrash(entry);
+  if (moved) await trashRequest({ action: "restore", path: entry.file.path, target: entry.original_path });
+  return commit(
+    (s) => {
+      const idx = s.trash.findIndex((t) => t.file.id === fileId);
+      if (idx === -1) throw new Error(t("file_not_in_trash"));
+      const [current] = s.trash.splice(idx, 1);
+      const restored: PatientFile = { ...current.file, path: current.original_path };
+      s.files.unshift(restored);
+      return restored;
+    },
+    async () => {
+      if (moved) await trashRequest({ action: "move", path: entry.original_path }).catch(() => null);
+    },
+  );
+});
+
+// Entries past the window are dropped from the store first; their files are deleted from disk only
+// once that is saved, so a failed save never leaves an entry pointing at nothing.
+export const emptyTrash = command("empty_trash", async (olderThanDays = 30): Promise<number> => {
+  const cutoff = Date.now() - Math.max(0, olderThanDays) * 86_400_000;
+  const expired = (e: TrashedFile) => {
+    const at = Date.parse(e.deleted_at);
+    return !Number.isNaN(at) && at <= cutoff;
+  };
+  if (!(await getStore()).trash.some(expired)) return 0;
+  const removed = await commit((s) => {
+    const gone = s.trash.filter(expired);
+    s.trash = s.trash.filter((e) => !expired(e));
+    return gone;
+  });
+  await Promise.all(removed.filter(isInAssetTrash).map((e) => trashRequest({ action: "purge", path: e.file.path }).catch(() => null)));
+  return removed.length;
+});
+
+export type FileIntegrity = {
+  file_id: number;
+  filename: string;
+  status: "ok" | "missing" | "corrupt" | "unhashed";
+};
+
+// WebCrypto has no incremental digest, so each file is hashed whole, one at a time.
+export const verifyPatientFiles = command("verify_patient_files", async (patientId: string): Promise<FileIntegrity[]> => {
+  const store = await getStore();
+  if (!store.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+  const out: FileIntegrity[] = [];
+  for (const file of store.files) {
+    if (file.patient_id !== patientId) continue;
+    let bytes: Uint8Array | null;
+    try {
+      bytes = await storedFileBytes(file.path);
+    } catch {
+      out.push({ file_id: file.id, filename: file.filename, status: "missing" });
+      continue;
+    }
+    if (!bytes) continue;
+    let status: FileIntegrity["status"] = "unhashed";
+    if (file.sha256) status = (await sha256Hex(bytes)) === file.sha256 ? "ok" : "corrupt";
+    out.push({ file_id: file.id, filename: file.filename, status });
+  }
+  return out;
+});
+
+export type OrphanFile = { patient_id: string; filename: string; path: string; size: number };
+
+function safeDecode(path: string) {
+  try {
+    return decodeURIComponent(path);
+  } catch {
+    return path;
+  }
+}
+
+// Files sitting in a patient folder that no file, trash entry or photo points at (copied in by hand, failed import).
+export const scanOrphanFiles = command("scan_orphan_files", async (patientId?: string | null): Promise<OrphanFile[]> => {
+  let j: any = null;
+  try {
+    const res = await fetch(`${SCAN_ASSETS_ENDPOINT}${patientId ? `?patientId=${encodeURIComponent(patientId)}` : ""}`, { cache: "no-store" });
+    j = await res.json();
+  } catch {
+    throw new Error(t("local_server_required"));
+  }
+  if (!j?.ok) throw serverError(j);
+  const store = await getStore();
+  const known = new Set<string>();
+  for (const f of store.files) known.add(safeDecode(f.path));
+  for (const e of store.trash) known.add(safeDecode(e.original_path)).add(safeDecode(e.file.path));
+  for (const p of store.patients) if (p.photo_path) known.add(safeDecode(p.photo_path));
+  return (j.files as OrphanFile[]).filter((f) => !known.has(safeDecode(f.path)));
+});
+
+// Starts tracking an orphan found by scanOrphanFiles as an attachment of the folder's patient.
+export const adoptOrphanFile = command("adopt_orphan_file", async (path: string): Promise<PatientFile> => {
+  const m = /^\/__naju_asset\/([^/]+)\/([^/]+)$/.exec(path);
+  if (!m || safeDecode(path).split("/").includes("..")) throw new Error(t("not_on_disk"));
+  const patientId = safeDecode(m[1]);
+  const bytes = await storedFileBytes(path);
+  const draft: NewFile = {
+    patient_id: patientId,
+    kind: "attachment",
+    filename: safeDecode(m[2]),
+    created_at: nowIso(),
+    path,
+    meta_json: JSON.stringify({ adopted: true }),
+    sha256: bytes ? await sha256Hex(bytes) : null,
+  };
+  return commit((s) => {
+    if (!s.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+    if (s.files.some((f) => safeDecode(f.path) === safeDecode(path))) throw new Error(t("already_tracked"));
+    return addFiles(s, [draft])[0];
+  });
+});
+
+export type RelinkResult = { file: PatientFile; hash_matches: boolean | null };
+
+/**
+ * Recovery for a "missing" file from verifyPatientFiles: stores `replacement` as the file's
+ * content. On-disk assets are rewritten in place (same /__naju_asset path), inline files get a new
+ * data URL. `hash_matches` is null when the original was never hashed. A file stored compressed
+ * gets the replacement stored plain, so its compression meta is dropped and the old hash (of the
+ * gzip bytes) can't be compared. An encrypted file stays encrypted: the replacement is encrypted
+ * with the session key under a fresh nonce, so this needs encryption unlocked.
+ */
+export const relinkFile = command("relink_file", async (fileId: number, replacement: File): Promise<RelinkResult> => {
+  const store = await getStore();
+  const file = store.files.find((f) => f.id === fileId);
+  if (!file) throw new Error(t("file_not_found"));
+  const wasEncrypted = isEncryptedFile(file);
+  if (wasEncrypted && !encryptionKey) throw new Error(t("encryption_locked"));
+  await checkFreeSpace(replacement.size);
+  const bytes = new Uint8Array(await replacement.arrayBuffer());
+  let sha256 = await sha256Hex(bytes);
+  const wasCompressed = isCompressedFile(file);
+
+  let path: string;
+  let metaJson = wasCompressed ? null : file.meta_json;
+  const asset = /^\/__naju_asset\/([^/]+)\/([^/]+)$/.exec(file.path);
+  if (asset) {
+    let j: any = null;
+    try {
+      const res = await fetch("/__naju_asset", {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ patientId: file.patient_id, filename: decodeURIComponent(asset[2]), contentType: replacement.type, dataBase64: bytesToBase64(bytes) }),
+      });
+      j = await res.json();
+    } catch {
+      throw new Error(t("local_server_required"));
+    }
+    if (!j?.ok || typeof j.path !== "string") throw serverError(j);
+    path = j.path;
+  } else if (wasEncrypted && encryptionKey) {
+    const entry = await encryptedFileEntry(file.patient_id, replacement, file.filename, file.created_at, encryptionKey);
+    path = entry.path;
+    metaJson = entry.meta_json;
+    sha256 = entry.sha256 ?? sha256;
+  } else {
+    path = await readFileAsDataUrl(replacement);
+  }
+
+  return commit((s) => {
+    const idx = s.files.findIndex((f) => f.id === fileId);
+    if (idx === -1) throw new Error(t("file_not_found"));
+    const previous = s.files[idx];
+    const updated: PatientFile = { ...previous, path, sha256, meta_json: metaJson };
+    s.files[idx] = updated;
+    s.patients = s.patients.map((p) => (p.photo_path === previous.path ? { ...p, photo_path: path } : p));
+    for (const key of Array.from(thumbCache.keys())) if (key.startsWith(`${fileId}:`)) thumbCache.delete(key);
+    return { file: updated, hash_matches: previous.sha256 && !wasCompressed && !wasEncrypted ? previous.sha256 === sha256 : null };
+  });
+});
+
+const MAX_CHUNK_BYTES = 4 * 1024 * 1024;
+
+export type FileChunk = {
+  offset: number;
+  length: number;
+  total: number;
+  eof: boolean;
+  data_base64: string;
+};
+
+function bytesToBase64(bytes: Uint8Array) {
+  let bin = "";
+  for (let i = 0; i < bytes.length; i += 0x8000) bin += String.fromCharCode(...bytes.subarray(i, i + 0x8000));
+  return btoa(bin);
+}
+
+function base64ToBytes(b64: string) {
+  const bin = atob(b64);
+  const out = new Uint8Array(bin.length);
+  for (let i = 0; i < bin.length; i++) out[i] = bin.charCodeAt(i);
+  return out;
+}
+
+// Only data URLs and files under /__naju_asset/ can be read; ".." never reaches the server.
+function readableFile(store: Store, fileId: number) {
+  const file = store.files.find((f) => f.id === fileId);
+  if (!file) throw new Error(t("file_not_found"));
+  if (file.path.startsWith("data:")) return file;
+  if (!file.path.startsWith("/__naju_asset/") || decodeURIComponent(file.path).split("/").includes("..")) throw new Error(t("not_on_disk"));
+  return file;
+}
+
+// The original size; a compressed file reports what it unpacks to.
+export const fileSize = command("file_size", async (fileId: number): Promise<number> => {
+  const file = readableFile(await getStore(), fileId);
+  if (isCompressedFile(file)) return Number(parseJsonOrNull(file.meta_json)?.original_size) || 0;
+  if (file.path.startsWith("data:")) return dataUrlToBytes(file.path).length;
+  const res = await fetch(file.path, { method: "HEAD", cache: "no-store" });
+  if (!res.ok) throw new Error(t("file_not_found"));
+  return Number(res.headers.get("Content-Length") || 0);
+});
+
+// Lets the UI page through a large PDF/text file instead of pulling it whole; `length` is capped at 4 MB.
+export const readFileChunk = command("read_file_chunk", async (fileId: number, offset: number, length: number): Promise<FileChunk> => {
+  if (!Number.isInteger(offset) || !Number.isInteger(length) || offset < 0 || length <= 0 || length > MAX_CHUNK_BYTES) {
+    throw new Error(t("invalid_chunk_range", { max: MAX_CHUNK_BYTES }));
+  }
+  const file = readableFile(await getStore(), fileId);
+
+  // Compressed files are paged through their unpacked content, so callers never see gzip.
+  if (file.path.startsWith("data:")) {
+    const bytes = isCompressedFile(file) ? await gunzipBytes(dataUrlToBytes(file.path)) : dataUrlToBytes(file.path);
+    if (offset > bytes.length) throw new Error(t("invalid_chunk_range", { max: MAX_CHUNK_BYTES }));
+    const slice = bytes.subarray(offset, offset + length);
+    return { offset, length: slice.length, total: bytes.length, eof: offset + slice.length >= bytes.length, data_base64: bytesToBase64(slice) };
+  }
+
+  const res = await fetch(file.path, { cache: "no-store", headers: { Range: `bytes=${offset}-${offset + length - 1}` } });
+  if (res.status === 416) {
+    // Reading exactly at the end is a valid (empty) last chunk; past it is a caller error.
+    const total = Number((res.headers.get("Content-Range") || "").split("/")[1] || 0);
+    if (offset !== total) throw new Error(t("invalid_chunk_range", { max: MAX_CHUNK_BYTES }));
+    return { offset, length: 0, total, eof: true, data_base64: "" };
+  }
+  if (!res.ok) throw new Error(t("file_not_found"));
+  const slice = new Uint8Array(await res.arrayBuffer());
+  // A server that ignores Range answers 200 with the whole file.
+  const total = res.status === 206 ? Number((res.headers.get("Content-Range") || "").split("/")[1] || 0) : slice.length;
+  const data = res.status === 206 ? slice : slice.subarray(offset, offset + length);
+  return { offset, length: data.length, total, eof: offset + data.length >= total, data_base64: bytesToBase64(data) };
+});
+
+// Thumbnails are rendered once per (file, size) and kept for the session, up to THUMB_CACHE_MAX
+// entries; past that the least recently used ones are dropped (a Map iterates in insertion order,
+// and a hit re-inserts its key).
+const THUMB_CACHE_MAX = 500;
+const thumbCache = new Map<string, string>();
+
+function cachedThumb(key: string): string | undefined {
+  const hit = thumbCache.get(key);
+  if (hit !== undefined) {
+    thumbCache.delete(key);
+    thumbCache.set(key, hit);
+  }
+  return hit;
+}
+
+function cacheThumb(key: string, value: string) {
+  thumbCache.delete(key);
+  thumbCache.set(key, value);
+  while (thumbCache.size > THUMB_CACHE_MAX) thumbCache.delete(thumbCache.keys().next().value as string);
+}
+
+function isImageFile(file: PatientFile) {
+  if (isEncryptedFile(file)) return false;
+  return file.path.startsWith("data:image/") || /\.(png|jpg|jpeg|webp|gif)$/i.test(file.filename);
+}
+
+function loadImage(src: string): Promise<HTMLImageElement> {
+  return new Promise((resolve, reject) => {
+    const img = new Image();
+    img.onload = () => resolve(img);
+    img.onerror = () => reject(new Error(t("image_read_failed")));
+    img.src = src;
+  });
+}
+
+async function renderThumbnail(src: string, maxDim: number): Promise<string> {
+  const img = await loadImage(src);
+  const scale = Math.min(1, maxDim / Math.max(img.naturalWidth || 1, img.naturalHeight || 1));
+  const canvas = document.createElement("canvas");
+  canvas.width = Math.max(1, Math.round((img.naturalWidth || 1) * scale));
+  canvas.height = Math.max(1, Math.round((img.naturalHeight || 1) * scale));
+  const ctx = canvas.getContext("2d");
+  if (!ctx) throw new Error(t("thumbnail_failed"));
+  ctx.drawImage(img, 0, 0, canvas.width, canvas.height);
+  return canvas.toDataURL("image/jpeg", 0.82);
+}
+
+async function thumbnailFor(file: PatientFile, maxDim: number): Promise<string> {
+  const key = `${file.id}:${maxDim}`;
+  const cached = cachedThumb(key);
+  if (cached) return cached;
+  const thumb = await renderThumbnail(file.path, maxDim);
+  cacheThumb(key, thumb);
+  return thumb;
+}
+
+export const getThumbnail = command("get_thumbnail", async (fileId: number, maxDim = 256): Promise<string> => {
+  const store = await getStore();
+  const file = store.files.find((f) => f.id === fileId);
+  if (!file) throw new Error(t("file_not_found"));
+  if (!isImageFile(file)) throw new Error(t("not_an_image"));
+  return thumbnailFor(file, maxDim);
+});
+
+export const getThumbnails = command("get_thumbnails", async (patientId: string, maxDim = 256): Promise<Record<number, string>> => {
+  const store = await getStore();
+  if (!store.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+  const out: Record<number, string> = {};
+  for (const file of store.files) {
+    if (file.patient_id !== patientId || !isImageFile(file)) continue;
+    try {
+      out[file.id] = await thumbnailFor(file, maxDim);
+    } catch {
+      // unreadable image: leave it out of the map
+    }
+  }
+  return out;
+});
+
+function initialsOf(name: string) {
+  const words = name.trim().split(/\s+/).filter(Boolean);
+  const letters = words.length > 1 ? [words[0], words[words.length - 1]] : words;
+  return letters.map((w) => w[0].toUpperCase()).join("") || "?";
+}
+
+// FNV-1a over the id, so a patient keeps the same color across sessions and machines.
+function avatarHue(id: string) {
+  let h = 0x811c9dc5;
+  for (let i = 0; i < id.length; i++) h = Math.imul(h ^ id.charCodeAt(i), 0x01000193);
+  return (h >>> 0) % 360;
+}
+
+function renderInitialsAvatar(id: string, name: string, size: number) {
+  const canvas = document.createElement("canvas");
+  canvas.width = size;
+  canvas.height = size;
+  const ctx = canvas.getContext("2d");
+  if (!ctx) throw new Error(t("thumbnail_failed"));
+  ctx.fillStyle = `hsl(${avatarHue(id)}, 55%, 45%)`;
+  ctx.fillRect(0, 0, size, size);
+  ctx.fillStyle = "#fff";
+  ctx.font = `600 ${Math.round(size * 0.42)}px system-ui, sans-serif`;
+  ctx.textAlign = "center";
+  ctx.textBaseline = "middle";
+  ctx.fillText(initialsOf(name), size / 2, size / 2 + size * 0.02);
+  return canvas.toDataURL("image/png");
+}
+
+// Data URL for list rows: the primary photo's thumbnail, or a colored initials tile when there is none.
+export const patientAvatar = command("patient_avatar", async (patientId: string, size = 64): Promise<string> => {
+  const store = await getStore();
+  const patient = store.patients.find((p) => p.id === patientId);
+  if (!patient) throw new Error(t("patient_not_found"));
+  const dim = Math.max(16, Math.min(512, Math.round(size)));
+  // updated_at changes with the name or photo, so stale avatars are never served.
+  const key = `avatar:${patient.id}:${dim}:${patient.updated_at}`;
+  const cached = cachedThumb(key);
+  if (cached) return cached;
+  let avatar: string | null = null;
+  if (patient.photo_path) {
+    const photo = store.files.find((f) => f.patient_id === patientId && f.kind === "photo" && f.path === patient.photo_path);
+    try {
+      avatar = photo ? await thumbnailFor(photo, dim) : await renderThumbnail(patient.photo_path, dim);
+    } catch {
+      // unreadable photo: fall back to initials
+    }
+  }
+  avatar ??= renderInitialsAvatar(patient.id, patient.name, dim);
+  cacheThumb(key, avatar);
+  return avatar;
+});
+
+// 62 x 29 mm at 300 dpi, the common continuous-label size on desktop label printers.
+const LABEL_WIDTH_PX = 696;
+const LABEL_HEIGHT_PX = 271;
+// 3px is 0.25 mm at 300 dpi, above the ~0.19 mm narrowest bar front-desk scanners read reliably.
+const LABEL_MIN_MODULE_PX = 3;
+
+export type PatientLabel = { data_url: string; filename: string; width_px: number; height_px: number };
+
+/**
+ * PNG label with name, document, birth date and a Code 128 barcode of the patient code (short enough
+ * to print with scannable bars; findPatientByCode resolves it). With
+ * `download` the PNG is also saved through the browser so it can be sent to the label printer.
+ */
+export const renderPatientLabel = command("render_patient_label", async (patientId: string, download = false): Promise<PatientLabel> => {
+  const patient = (await getStore()).patients.find((p) => p.id === patientId);
+  if (!patient) throw new Error(t("patient_not_found"));
+  const code = formatPatientCode(patient.patient_code);
+  const bars = code128B(code);
+  if (!bars) throw new Error(t("label_failed"));
+
+  const canvas = document.createElement("canvas");
+  canvas.width = LABEL_WIDTH_PX;
+  canvas.height = LABEL_HEIGHT_PX;
+  const ctx = canvas.getContext("2d");
+  if (!ctx) throw new Error(t("label_failed"));
+  const margin = 20;
+  ctx.fillStyle = "#fff";
+  ctx.fillRect(0, 0, canvas.width, canvas.height);
+  ctx.fillStyle = "#000";
+  ctx.textBaseline = "top";
+  ctx.font = "bold 40px system-ui, sans-serif";
+  ctx.fillText(patient.name, margin, margin, canvas.width - 2 * margin);
+  ctx.font = "28px system-ui, sans-serif";
+  const doc = [patient.doc_type, patient.doc_number].filter(Boolean).join(" ") || "—";
+  ctx.fillText(`${doc}   ·   ${patient.birth_date ?? "—"}`, margin, margin + 52, canvas.width - 2 * margin);
+
+  // Whole-pixel modules keep the bars crisp; 10-module quiet zone on each side.
+  const modules = bars.reduce((sum, w) => sum + w, 0) + 20;
+  const unit = Math.floor((canvas.width - 2 * margin) / modules);
+  if (unit < LABEL_MIN_MODULE_PX) throw new Error(t("label_failed"));
+  const barTop = margin + 100;
+  const barHeight = canvas.height - barTop - margin - 26;
+  let x = Math.round((canvas.width - modules * unit) / 2) + 10 * unit;
+  bars.forEach((w, i) => {
+    if (i % 2 === 0) ctx.fillRect(x, barTop, w * unit, barHeight);
+    x += w * unit;
+  });
+  ctx.font = "20px ui-monospace, monospace";
+  ctx.textAlign = "center";
+  ctx.fillText(code, canvas.width / 2, barTop + barHeight + 4);
+
+  const dataUrl = canvas.toDataURL("image/png");
+  const filename = `etiqueta-${sanitizeSegment(stripAccents(patient.name)) || patient.id}.png`;
+  if (download) downloadDataUrl(filename, dataUrl);
+  return { data_url: dataUrl, filename, width_px: canvas.width, height_px: canvas.height };
+});
+
+export type ContactSheet = { data_url: string; filename: string; photos: number };
+
+const SHEET_CELL_PX = 320;
+const SHEET_CAPTION_PX = 36;
+const SHEET_MAX_COLUMNS = 4;
+
+/**
+ * All of a patient's photos in one PNG grid, oldest first, each captioned with its capture date.
+ * A single photo gives a one-cell sheet; no photos is an error.
+ */
+export const exportContactSheet = command("export_contact_sheet", async (patientId: string, download = true): Promise<ContactSheet> => {
+  const store = await getStore();
+  const patient = store.patients.find((p) => p.id === patientId);
+  if (!patient) throw new Error(t("patient_not_found"));
+  const photos = store.files
+    .filter((f) => f.patient_id === patientId && f.kind === "photo" && isImageFile(f))
+    .sort((a, b) => a.created_at.localeCompare(b.created_at));
+  if (!photos.length) throw new Error(t("no_photos"));
+
+  const columns = Math.min(SHEET_MAX_COLUMNS, photos.length);
+  const rows = Math.ceil(photos.length / columns);
+  const header = 56;
+  const canvas = document.createElement("canvas");
+  canvas.width = columns * SHEET_CELL_PX;
+  canvas.height = header + rows * (SHEET_CELL_PX + SHEET_CAPTION_PX);
+  const ctx = canvas.getContext("2d");
+  if (!ctx) throw new Error(t("thumbnail_failed"));
+  ctx.fillStyle = "#fff";
+  ctx.fillRect(0, 0, canvas.width, canvas.height);
+  ctx.fillStyle = "#000";
+  ctx.textBaseline = "top";
+  ctx.font = "bold 24px system-ui, sans-serif";
+  ctx.fillText(patient.name, 16, 16, canvas.width - 32);
+
+  ctx.font = "16px system-ui, sans-serif";
+  ctx.textAlign = "center";
+  for (let i = 0; i < photos.length; i++) {
+    const x = (i % columns) * SHEET_CELL_PX;
+    const y = header + Math.floor(i / columns) * (SHEET_CELL_PX + SHEET_CAPTION_PX);
+    const caption = clinicDateIso(new Date(photos[i].created_at));
+    try {
+      // A photo that can't be decoded leaves its cell blank but keeps the caption.
+      const img = await loadImage(photos[i].path);
+      const inner = SHEET_CELL_PX - 16;
+      const scale = Math.min(inner / (img.naturalWidth || 1), inner / (img.naturalHeight || 1));
+      const w = (img.naturalWidth || 1) * scale;
+      const h = (img.naturalHeight || 1) * scale;
+      ctx.drawImage(img, x + (SHEET_CELL_PX - w) / 2, y + (SHEET_CELL_PX - h) / 2, w, h);
+    } catch {
+      ctx.strokeRect(x + 8, y + 8, SHEET_CELL_PX - 16, SHEET_CELL_PX - 16);
+    }
+    ctx.fillText(caption, x + SHEET_CELL_PX / 2, y + SHEET_CELL_PX + 8);
+  }
+
+  const dataUrl = canvas.toDataURL("image/png");
+  const filename = `fotos-${sanitizeSegment(stripAccents(patient.name)) || patient.id}.png`;
+  if (download) downloadDataUrl(filename, dataUrl);
+  return { data_url: dataUrl, filename, photos: photos.length };
+});
+
+export type ThumbnailRebuild = { rebuilt: number; skipped: number; failed: number; removed_stale: number };
+
+/**
+ * Drops cached thumbnails (for one patient, or all) and renders them again at `maxDim`, reporting
+ * progress per file. Cached entries whose file is gone are removed. Yields between files so the
+ * UI stays responsive on large stores.
+ */
+export const rebuildThumbnails = command("rebuild_thumbnails", async (
+  patientId?: string | null,
+  maxDim = 256,
+  onProgress?: (p: ImportProgress) => void
+): Promise<ThumbnailRebuild> => {
+  const store = await getStore();
+  if (patientId && !store.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+  const files = store.files.filter((f) => !patientId || f.patient_id === patientId);
+  const live = new Set(store.files.map((f) => String(f.id)));
+  const scope = new Set(files.map((f) => String(f.id)));
+  const summary: ThumbnailRebuild = { rebuilt: 0, skipped: 0, failed: 0, removed_stale: 0 };
+
+  for (const key of Array.from(thumbCache.keys())) {
+    const id = key.split(":")[0];
+    if (!/^\d+$/.test(id)) continue; // avatars are keyed by patient and expire on their own
+    if (!live.has(id)) {
+      thumbCache.delete(key);
+      summary.removed_stale++;
+    } else if (scope.has(id)) {
+      thumbCache.delete(key);
+    }
+  }
+
+  let done = 0;
+  for (const file of files) {
+    if (!isImageFile(file)) {
+      summary.skipped++;
+    } else {
+      try {
+        await thumbnailFor(file, maxDim);
+        summary.rebuilt++;
+      } catch {
+        summary.failed++;
+      }
+    }
+    onProgress?.({ done: ++done, total: files.length, filename: file.filename });
+    await sleep(0);
+  }
+  return summary;
+});
+
+export type CreatedExam = {
+  file: PatientFile;
+  payload: any; // exactly what was persisted (round-tripped through JSON)
+};
+
+export const EXAM_TYPES = ["examen_mental", "evolucion", "consentimiento", "admision", "valoracion", "epicrisis"] as const;
+
+export type ExamType = (typeof EXAM_TYPES)[number];
+
+// Exams written before exam types existed carry no `type` and are mental exams.
+function examTypeOf(file: PatientFile): string {
+  const meta = parseJsonOrNull(file.meta_json);
+  return typeof meta?.type === "string" ? meta.type : "examen_mental";
+}
+
+async function examEntry(patientId: string, payload: any): Promise<NewFile> {
+  const createdAt = nowIso();
+  const prefix = !payload?.type || payload.type === "examen_mental" ? "examen" : String(payload.type);
+  const filename = `${prefix}-${clinicDateIso(new Date(createdAt))}.json`;
+  const json = JSON.stringify(payload, null, 2);
+  const dataUrl = `data:application/json;charset=utf-8,${encodeURIComponent(json)}`;
+  return {
+    patient_id: patientId,
+    kind: "exam",
+    filename,
+    created_at: createdAt,
+    path: dataUrl,
+    meta_json: json,
+    sha256: await sha256Hex(new TextEncoder().encode(json)),
+  };
+}
+
+export const createExam = command("create_exam", async (patientId: string, examType: string, payload: any): Promise<CreatedExam> => {
+  if (!(EXAM_TYPES as readonly string[]).includes(examType)) throw new Error(t("unknown_exam_type", { type: examType }));
+  const draft = await examEntry(patientId, { ...payload, type: examType });
+  const [file] = await commit((s) => {
+    if (!s.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+    return addFiles(s, [draft]);
+  });
+  return { file, payload: JSON.parse(draft.meta_json as string) };
+});
+
+export const createMentalExam = command("create_mental_exam", async (patientId: string, payload: any): Promise<CreatedExam> => {
+  return createExam(patientId, "examen_mental", payload);
+});
+
+// Every NAJU exam is dated; the rest of the payload is free-form.
+const REQUIRED_EXAM_FIELDS = ["fecha"];
+
+// Leaf paths of a JSON value in dot notation; arrays count as leaves.
+function jsonLeafPaths(value: any, prefix = ""): string[] {
+  if (!value || typeof value !== "object" || Array.isArray(value)) return prefix ? [prefix] : [];
+  return Object.keys(value).flatMap((k) => jsonLeafPaths(value[k], prefix ? `${prefix}.${k}` : k));
+}
+
+function getJsonPath(value: any, path: string): unknown {
+  return path.split(".").reduce<any>((v, k) => (v && typeof v === "object" ? v[k] : undefined), value);
+}
+
+function setJsonPath(target: Record<string, any>, path: string, value: unknown) {
+  const keys = path.split(".");
+  let node = target;
+  for (const k of keys.slice(0, -1)) node = node[k] && typeof node[k] === "object" ? node[k] : (node[k] = {});
+  node[keys[keys.length - 1]] = value;
+}
+
+export type ExamJsonImport = { exam: CreatedExam; unmapped_fields: string[] };
+
+/**
+ * Imports an exam written by another system. `mapping` is { naju_field: "source.path" } in dot
+ * notation on both sides; source fields the mapping doesn't use come back in `unmapped_fields`.
+ */
+export const importExamJson = command("import_exam_json", async (patientId: string, source: File, mapping: Record<string, string>, examType: string = "examen_mental"): Promise<ExamJsonImport> => {
+  const raw = parseJsonOrNull(await source.text());
+  if (!raw || typeof raw !== "object" || Array.isArray(raw)) throw new Error(t("invalid_exam_json"));
+  const payload: Record<string, any> = {};
+  for (const [target, from] of Object.entries(mapping)) {
+    const value = getJsonPath(raw, from);
+    if (value !== undefined) setJsonPath(payload, target, value);
+  }
+  const missing = REQUIRED_EXAM_FIELDS.filter((f) => getJsonPath(payload, f) === undefined || getJsonPath(payload, f) === "");
+  if (missing.length) throw new Error(t("exam_missing_fields", { fields: missing.join(", ") }));
+
+  const used = Object.values(mapping);
+  const unmapped = jsonLeafPaths(raw).filter((leaf) => !used.some((u) => leaf === u || leaf.startsWith(`${u}.`)));
+  const exam = await createExam(patientId, examType, { ...payload, imported_from: source.name });
+  return { exam, unmapped_fields: unmapped };
+});
+
+export const listExams = command("list_exams", async (patientId: string, examType?: string | null): Promise<PatientFile[]> => {
+  const store = await getStore();
+  return store.files.filter((f) => f.patient_id === patientId && f.kind === "exam" && (!examType || examTypeOf(f) === examType));
+});
+
+export type ExamMatch = {
+  patient_id: string;
+  patient_name: string;
+  file_id: number;
+  exam_type: string;
+  created_at: string;
+  snippet: string;
+};
+
+const EXAM_SEARCH_LIMIT = 100;
+const SNIPPET_RADIUS = 40;
+
+// Inline data (signature images) is skipped: base64 would match random short queries.
+function jsonStrings(value: any): string[] {
+  if (typeof value === "string") return value.startsWith("data:") ? [] : [value];
+  if (!value || typeof value !== "object") return [];
+  return Object.values(value).flatMap(jsonStrings);
+}
+
+// Exams and notes keep their payload in meta_json; older ones may only have it at `path`, read one at a time.
+async function examPayload(file: PatientFile) {
+  if (file.meta_json) return parseJsonOrNull(file.meta_json);
+  try {
+    const res = await fetch(file.path);
+    return res.ok ? parseJsonOrNull(await res.text()) : null;
+  } catch {
+    return null;
+  }
+}
+
+// stripAccents keeps NFC text the same length, so an index found in normalized text is valid in the original.
+function snippetAround(text: string, index: number, length: number) {
+  const start = Math.max(0, index - SNIPPET_RADIUS);
+  const end = Math.min(text.length, index + length + SNIPPET_RADIUS);
+  const body = text.slice(start, end).replace(/\s+/g, " ").trim();
+  return `${start > 0 ? "…" : ""}${body}${end < text.length ? "…" : ""}`;
+}
+
+// First string in the file's JSON payload mentioning `q` (already normText'ed), as a snippet.
+async function jsonFileSnippet(file: PatientFile, q: string): Promise<string | null> {
+  for (const text of jsonStrings(await examPayload(file))) {
+    const nfc = text.normalize("NFC");
+    const index = stripAccents(nfc).toLowerCase().indexOf(q);
+    if (index !== -1) return snippetAround(nfc, index, q.length);
+  }
+  return null;
+}
+
+/** Exams across all patients whose text mentions `query` (accent/case-insensitive), newest first. */
+export const searchExams = command("search_exams", async (query: string, examType?: string | null, limit = EXAM_SEARCH_LIMIT): Promise<ExamMatch[]> => {
+  const q = normText(query);
+  if (!q) return [];
+  const store = await getStore();
+  const names = new Map(store.patients.map((p) => [p.id, p.name]));
+  const exams = store.files
+    .filter((f) => f.kind === "exam" && names.has(f.patient_id) && (!examType || examTypeOf(f) === examType))
+    .sort((a, b) => b.created_at.localeCompare(a.created_at));
+
+  const matches: ExamMatch[] = [];
+  for (const file of exams) {
+    if (matches.length >= limit) break;
+    const snippet = await jsonFileSnippet(file, q);
+    if (snippet === null) continue;
+    matches.push({
+      patient_id: file.patient_id,
+      patient_name: names.get(file.patient_id) ?? "",
+      file_id: file.id,
+      exam_type: examTypeOf(file),
+      created_at: file.created_at,
+      snippet,
+    });
+  }
+  return matches;
+});
+
+export type NoteMatch = Omit<ExamMatch, "exam_type">;
+
+/** Follow-up notes mentioning `query`, across all patients or just one, newest first. */
+export const searchNotes = command("search_notes", async (query: string, patientId?: string | null, limit = EXAM_SEARCH_LIMIT): Promise<NoteMatch[]> => {
+  const q = normText(query);
+  if (!q) return [];
+  const store = await getStore();
+  const names = new Map(store.patients.map((p) => [p.id, p.name]));
+  const notes = store.files
+    .filter((f) => f.kind === "note" && names.has(f.patient_id) && (!patientId || f.patient_id === patientId))
+    .sort((a, b) => b.created_at.localeCompare(a.created_at));
+
+  const matches: NoteMatch[] = [];
+  for (const file of notes) {
+    if (matches.length >= limit) break;
+    const snippet = await jsonFileSnippet(file, q);
+    if (snippet === null) continue;
+    matches.push({ patient_id: file.patient_id, patient_name: names.get(file.patient_id) ?? "", file_id: file.id, created_at: file.created_at, snippet });
+  }
+  return matches;
+});
+
+const MAX_SIGNATURE_BYTES = 512 * 1024;
+
+/**
+ * Signs an exam: the PNG is kept inline in the exam payload as `signature_path` (a data URL, like
+ * the exam itself), so it travels with the exam into exports and printouts.
+ */
+export const attachExamSignature = command("attach_exam_signature", async (fileId: number, signatureBase64: string): Promise<PatientFile> => {
+  let bytes: Uint8Array;
+  try {
+    bytes = base64ToBytes(signatureBase64.slice(signatureBase64.indexOf(",") + 1).replace(/\s+/g, ""));
+  } catch {
+    throw new Error(t("invalid_base64"));
+  }
+  if (bytes.length > MAX_SIGNATURE_BYTES || (await sniffImageType(new Blob([bytes]))) !== "png") throw new Error(t("signature_not_png"));
+  const signature = await readFileAsDataUrl(new Blob([bytes], { type: "image/png" }));
+
+  const store = await getStore();
+  const exam = store.files.find((f) => f.id === fileId && f.kind === "exam");
+  if (!exam) throw new Error(t("exam_not_found"));
+  const payload = parseJsonOrNull(exam.meta_json);
+  if (!payload || typeof payload !== "object") throw new Error(t("exam_unreadable"));
+  const json = JSON.stringify({ ...payload, signature_path: signature, signed_at: nowIso() }, null, 2);
+  const sha256 = await sha256Hex(new TextEncoder().encode(json));
+
+  return commit((s) => {
+    const idx = s.files.findIndex((f) => f.id === fileId);
+    if (idx === -1) throw new Error(t("exam_not_found"));
+    const updated: PatientFile = {
+      ...s.files[idx],
+      meta_json: json,
+      path: `data:application/json;charset=utf-8,${encodeURIComponent(json)}`,
+      sha256,
+    };
+    s.files[idx] = updated;
+    return updated;
+  });
+});
+
+/** The exam's signature as an image URL, or null when it isn't signed. */
+export const getExamSignature = command("get_exam_signature", async (fileId: number): Promise<string | null> => {
+  const exam = (await getStore()).files.find((f) => f.id === fileId && f.kind === "exam");
+  if (!exam) throw new Error(t("exam_not_found"));
+  const signature = parseJsonOrNull(exam.meta_json)?.signature_path;
+  return typeof signature === "string" ? signature : null;
+});
+
+// Copies an exam as the starting point of a new one: date stamped today, snapshot pointing at the target patient.
+export const duplicateMentalExam = command("duplicate_mental_exam", async (sourceFileId: number, targetPatientId: string): Promise<PatientFile> => {
+  const store = await getStore();
+  const source = store.files.find((f) => f.id === sourceFileId && f.kind === "exam");
+  if (!source) throw new Error(t("exam_not_found"));
+  const target = store.patients.find((p) => p.id === targetPatientId);
+  if (!target) throw new Error(t("patient_not_found"));
+  const payload = parseJsonOrNull(source.meta_json);
+  if (!payload || typeof payload !== "object") throw new Error(t("exam_unreadable"));
+
+  const copy = {
+    ...payload,
+    fecha: clinicDateIso(),
+    duplicated_from: sourceFileId,
+    signature_path: undefined, // a copy is a new, unsigned exam
+    signed_at: undefined,
+    patient_snapshot: { id: target.id, name: target.name, doc_type: target.doc_type, doc_number: target.doc_number },
   };
-  store.files.unshift(entry);
-  await persistStore(store);
-  return entry;
-}
+  const draft = await examEntry(target.id, copy);
+  const [file] = await commit((s) => addFiles(s, [draft]));
+  return file;
+});
 
-export async function createPatientNote(patientId: string, payload: any): Promise<PatientFile> {
+export const createPatientNote = command("create_patient_note", async (patientId: string, payload: any, appointmentId: number | null = null): Promise<PatientFile> => {
   const store = await getStore();
+  if (appointmentId !== null) assertAppointmentOfPatient(store, appointmentId, patientId);
   const createdAt = nowIso();
-  const filename = `nota-${createdAt.slice(0, 10)}.json`;
+  const filename = `nota-${clinicDateIso(new Date(createdAt))}.json`;
   const json = JSON.stringify(payload, null, 2);
   const dataUrl = `data:application/json;charset=utf-8,${encodeURIComponent(json)}`;
   const entry: PatientFile = {
@@ -347,16 +3644,18 @@ export async function createPatientNote(patientId: string, payload: any): Promis
     created_at: createdAt,
     path: dataUrl,
     meta_json: json,
+    sha256: await sha256Hex(new TextEncoder().encode(json)),
+    ...(appointmentId !== null ? { appointment_id: appointmentId } : {}),
   };
   store.files.unshift(entry);
   await persistStore(store);
   return entry;
-}
+});
 
-export async function setPatientDriveFolder(patientId: string, folderId: string | null): Promise<Patient> {
+export const setPatientDriveFolder = command("set_patient_drive_folder", async (patientId: string, folderId: string | null): Promise<Patient> => {
   const store = await getStore();
   const idx = store.patients.findIndex((p) => p.id === patientId);
-  if (idx === -1) throw new Error("Paciente no encontrado");
+  if (idx === -1) throw new Error(t("patient_not_found"));
   const current = store.patients[idx];
   const updated: Patient = {
     ...current,
@@ -366,14 +3665,14 @@ export async function setPatientDriveFolder(patientId: string, folderId: string
   store.patients[idx] = updated;
   await persistStore(store);
   return updated;
-}
+});
 
-export async function createAttachmentLink(
+export const createAttachmentLink = command("create_attachment_link", async (
   patientId: string,
   filename: string,
   url: string,
   meta?: any
-): Promise<PatientFile> {
+): Promise<PatientFile> => {
   const store = await getStore();
   const createdAt = nowIso();
   const entry: PatientFile = {
@@ -388,8 +3687,115 @@ export async function createAttachmentLink(
   store.files.unshift(entry);
   await persistStore(store);
   return entry;
-}
+});
+
+
+export type PatientPath = { name: "data" | "store" | "root" | "marker" | "by_name"; path: string | null; exists: boolean };
+
+// Informational: every file of a patient sits flat in assets/<id>/ ("root"); the marker and by-name link come from folder markers.
+export const patientPaths = command("patient_paths", async (patientId: string): Promise<PatientPath[]> => {
+  let j: any = null;
+  try {
+    const res = await fetch(`${PATHS_ENDPOINT}?patientId=${encodeURIComponent(patientId)}`, { cache: "no-store" });
+    j = await res.json();
+  } catch {
+    throw new Error(t("local_server_required"));
+  }
+  if (!j?.ok) throw serverError(j);
+  return j.paths as PatientPath[];
+});
+
+/** Copies a stored file to `destPath` (a folder or a full file path) and returns where it was written. */
+export const exportFile = command("export_file", async (fileId: number, destPath: string): Promise<string> => {
+  const store = await getStore();
+  const file = readableFile(store, fileId);
+  const body: Record<string, string> = { dest: destPath, filename: file.filename };
+  // The copy leaves NAJU, so it must open on its own: encrypted files go out decrypted (or not at all while locked).
+  if (isEncryptedFile(file)) {
+    body.data = bytesToBase64(await decryptedFileBytes(file));
+  } else if (isCompressedFile(file)) {
+    body.data = bytesToBase64((await fileContentBytes(file)) ?? new Uint8Array());
+  } else if (file.path.startsWith("data:")) {
+    const res = await fetch(file.path);
+    body.data = bytesToBase64(new Uint8Array(await res.arrayBuffer()));
+  } else {
+    body.path = file.path;
+  }
+  let j: any = null;
+  try {
+    const res = await fetch(EXPORT_FILE_ENDPOINT, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(body),
+    });
+    j = await res.json();
+  } catch {
+    throw new Error(t("local_server_required"));
+  }
+  if (!j?.ok) throw serverError(j);
+  return String(j.path);
+});
+
+export const addPatientLink = command("add_patient_link", async (patientId: string, label: string, targetPath: string): Promise<PatientLink> => {
+  const cleanLabel = (label ?? "").trim();
+  const target = (targetPath ?? "").trim();
+  if (!cleanLabel) throw new Error(t("invalid_field_value", { field: "label" }));
+  // Absolute Windows (C:\, \\server\share) or POSIX path; the server checks it exists when opened.
+  if (!/^(?:[a-zA-Z]:[\\/]|\\\\|\/)/.test(target)) throw new Error(t("invalid_field_value", { field: "target_path" }));
+  return commit((s) => {
+    if (!s.patients.some((p) => p.id === patientId)) throw new Error(t("patient_not_found"));
+    const link: PatientLink = { id: s.nextLinkId++, patient_id: patientId, label: cleanLabel, target_path: target, created_at: nowIso() };
+    s.patientLinks.push(link);
+    return link;
+  });
+});
+
+export const listPatientLinks = command("list_patient_links", async (patientId: string): Promise<PatientLink[]> => {
+  const store = await getStore();
+  return store.patientLinks.filter((l) => l.patient_id === patientId);
+});
+
+// Forgets the link only; the target is never touched.
+export const removePatientLink = command("remove_patient_link", async (linkId: number): Promise<void> => {
+  await commit((s) => {
+    if (!s.patientLinks.some((l) => l.id === linkId)) throw new Error(t("link_not_found"));
+    s.patientLinks = s.patientLinks.filter((l) => l.id !== linkId);
+  });
+});
+
+export const openPatientLink = command("open_patient_link", async (linkId: number): Promise<void> => {
+  const link = (await getStore()).patientLinks.find((l) => l.id === linkId);
+  if (!link) throw new Error(t("link_not_found"));
+  let j: any = null;
+  try {
+    const res = await fetch(OPEN_PATH_ENDPOINT, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ path: link.target_path }),
+    });
+    j = await res.json();
+  } catch {
+    throw new Error(t("local_server_required"));
+  }
+  if (!j?.ok) throw serverError(j);
+});
 
+// Shows a file stored on disk (e.g. /__naju_asset/...) in Explorer/Finder. Inline data URLs have no file to show.
+export const revealInExplorer = command("reveal_in_explorer", async (path: string): Promise<void> => {
+  if (!path || path.startsWith("data:")) throw new Error(t("not_on_disk"));
+  let j: any = null;
+  try {
+    const res = await fetch(REVEAL_ENDPOINT, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ path }),
+    });
+    j = await res.json();
+  } catch {
+    throw new Error(t("local_server_required"));
+  }
+  if (!j?.ok) throw serverError(j);
+});
 
 function sortByStartIso(a: Appointment, b: Appointment) {
   const ta = Date.parse(a.start_iso || "");
@@ -397,18 +3803,86 @@ function sortByStartIso(a: Appointment, b: Appointment) {
   return (Number.isNaN(ta) ? 0 : ta) - (Number.isNaN(tb) ? 0 : tb);
 }
 
-export async function listAppointments(): Promise<Appointment[]> {
+export const listAppointments = command("list_appointments", async (): Promise<Appointment[]> => {
   const store = await getStore();
   return (store.appointments || []).slice().sort(sortByStartIso);
-}
+});
 
-export async function listAppointmentsForPatient(patientId: string): Promise<Appointment[]> {
+export const listAppointmentsForPatient = command("list_appointments_for_patient", async (patientId: string): Promise<Appointment[]> => {
   const store = await getStore();
   return (store.appointments || []).filter((a) => a.patient_id === patientId).slice().sort(sortByStartIso);
+});
+
+export type IcalExport = { filename: string; count: number };
+
+// Appointments starting on local days `from`..`to` (inclusive, YYYY-MM-DD), saved as an .ics download.
+export const exportAppointmentsIcal = command("export_appointments_ical", async (from: string, to: string, reminderMinutes: number | null = 30): Promise<IcalExport> => {
+  const isDay = (d: string) => /^\d{4}-\d{2}-\d{2}$/.test(d) && !Number.isNaN(Date.parse(`${d}T00:00:00Z`));
+  if (!isDay(from) || !isDay(to) || to < from) throw new Error(t("invalid_date_range"));
+  // Whole days on the clinic's calendar: from its midnight on `from` to its midnight after `to`.
+  const dayAfter = new Date(Date.parse(`${to}T00:00:00Z`) + 86_400_000).toISOString().slice(0, 10);
+  const start = clinicInstant(from, "00:00");
+  const end = clinicInstant(dayAfter, "00:00");
+  const store = await getStore();
+  const inRange = store.appointments.filter((a) => {
+    const at = Date.parse(a.start_iso);
+    return at >= start.getTime() && at < end.getTime();
+  });
+  const names = Object.fromEntries(store.patients.map((p) => [p.id, p.name]));
+  const filename = `naju-citas-${from}_${to}.ics`;
+  downloadTextFile(filename, "text/calendar;charset=utf-8", appointmentsToIcs(inRange, names, reminderMinutes));
+  return { filename, count: inRange.length };
+});
+
+export type FreeSlot = { start: string; end: string }; // RFC 3339 with the clinic's offset
+
+/**
+ * Open ranges on `date` (YYYY-MM-DD, clinic calendar) at least `slotMinutes` long, between the
+ * working hours (settings.working_hours unless given). Overlapping and back-to-back appointments
+ * are merged into one busy block; on today, time already past isn't offered. Read-only.
+ */
+export const findFreeSlots = command("find_free_slots", async (date: string, slotMinutes = 30, workingHours?: WorkingHours): Promise<FreeSlot[]> => {
+  if (!/^\d{4}-\d{2}-\d{2}$/.test(date) || !(slotMinutes > 0)) throw new Error(t("invalid_date_range"));
+  const store = await getStore();
+  const hours = workingHours ? validateWorkingHours(workingHours) : store.settings.working_hours;
+  const dayStart = Math.max(clinicInstant(date, hours.start).getTime(), Date.now());
+  const dayEnd = clinicInstant(date, hours.end).getTime();
+  const busy = store.appointments
+    .map((a) => [Date.parse(a.start_iso), Date.parse(a.end_iso)] as [number, number])
+    .filter(([s, e]) => !Number.isNaN(s) && !Number.isNaN(e) && s < dayEnd && e > dayStart)
+    .sort((a, b) => a[0] - b[0]);
+
+  const slots: FreeSlot[] = [];
+  const minMs = slotMinutes * 60_000;
+  let cursor = dayStart;
+  for (const [s, e] of [...busy, [dayEnd, dayEnd] as [number, number]]) {
+    if (s - cursor >= minMs) slots.push({ start: clinicIso(new Date(cursor)), end: clinicIso(new Date(s)) });
+    cursor = Math.max(cursor, e);
+  }
+  return slots;
+});
+
+// Touching appointments (one ends as the next starts) don't conflict.
+function overlapping(store: Store, startIso: string, endIso: string, exceptId?: number) {
+  const start = Date.parse(startIso);
+  const end = Date.parse(endIso);
+  return store.appointments.filter((a) => a.id !== exceptId && Date.parse(a.start_iso) < end && Date.parse(a.end_iso) > start);
+}
+
+// There's one agenda, so any overlap is a double booking. Runs right before the write, with no await in between.
+function assertNoOverlap(store: Store, startIso: string, endIso: string, exceptId?: number) {
+  const clashes = overlapping(store, startIso, endIso, exceptId);
+  if (!clashes.length) return;
+  const list = clashes
+    .sort(sortByStartIso)
+    .map((a) => `${a.title} (${clinicIso(new Date(a.start_iso)).slice(0, 16).replace("T", " ")}–${clinicIso(new Date(a.end_iso)).slice(11, 16)})`)
+    .join(", ");
+  throw new Error(t("appointment_overlap", { list }));
 }
 
-export async function createAppointment(input: AppointmentInput): Promise<Appointment> {
+export const createAppointment = command("create_appointment", async (input: AppointmentInput, allowOverlap = false): Promise<Appointment> => {
   const store = await getStore();
+  if (!allowOverlap) assertNoOverlap(store, input.start_iso, input.end_iso);
   const now = nowIso();
   const entry: Appointment = {
     id: store.nextAppointmentId++,
@@ -423,12 +3897,12 @@ export async function createAppointment(input: AppointmentInput): Promise<Appoin
   store.appointments.unshift(entry);
   await persistStore(store);
   return entry;
-}
+});
 
-export async function updateAppointment(appointmentId: number, patch: Partial<AppointmentInput>): Promise<Appointment> {
+export const updateAppointment = command("update_appointment", async (appointmentId: number, patch: Partial<AppointmentInput>, allowOverlap = false): Promise<Appointment> => {
   const store = await getStore();
   const idx = (store.appointments || []).findIndex((a) => a.id === appointmentId);
-  if (idx === -1) throw new Error("Cita no encontrada");
+  if (idx === -1) throw new Error(t("appointment_not_found"));
   const cur = store.appointments[idx];
   const updated: Appointment = {
     ...cur,
@@ -439,31 +3913,846 @@ export async function updateAppointment(appointmentId: number, patch: Partial<Ap
     notes: patch.notes !== undefined ? (patch.notes === null ? null : String(patch.notes)) : cur.notes,
     updated_at: nowIso(),
   };
+  if (!allowOverlap && (updated.start_iso !== cur.start_iso || updated.end_iso !== cur.end_iso)) assertNoOverlap(store, updated.start_iso, updated.end_iso, appointmentId);
   store.appointments[idx] = updated;
   await persistStore(store);
   return updated;
-}
+});
 
-export async function deleteAppointment(appointmentId: number): Promise<void> {
+// Files filed under the visit stay with the patient, just no longer linked to it.
+export const deleteAppointment = command("delete_appointment", async (appointmentId: number): Promise<void> => {
   const store = await getStore();
   store.appointments = (store.appointments || []).filter((a) => a.id !== appointmentId);
+  store.files = store.files.map((f) => (f.appointment_id === appointmentId ? withAppointment(f, null) : f));
   await persistStore(store);
+});
+
+export const setFollowupFlag = command("set_followup_flag", async (appointmentId: number, value: boolean): Promise<Appointment> => {
+  return commit((s) => {
+    const idx = s.appointments.findIndex((a) => a.id === appointmentId);
+    if (idx === -1) throw new Error(t("appointment_not_found"));
+    const { needs_followup: _was, ...rest } = s.appointments[idx];
+    s.appointments[idx] = value ? { ...rest, needs_followup: true, updated_at: nowIso() } : { ...rest, updated_at: nowIso() };
+    return s.appointments[idx];
+  });
+});
+
+export type PendingFollowup = { appointment: Appointment; patient: Patient };
+
+/**
+ * Call-back worklist: flagged appointments that already ended and have no later appointment for
+ * the same patient. Archived patients are left out. Longest waiting first.
+ */
+export const appointmentsPendingFollowup = command("appointments_pending_followup", async (): Promise<PendingFollowup[]> => {
+  const store = await getStore();
+  const now = nowIso();
+  const latestStart = new Map<string, string>();
+  for (const a of store.appointments) {
+    if (a.start_iso > (latestStart.get(a.patient_id) ?? "")) latestStart.set(a.patient_id, a.start_iso);
+  }
+  const patients = new Map(store.patients.filter((p) => !p.archived_at).map((p) => [p.id, p]));
+  const out: PendingFollowup[] = [];
+  for (const a of store.appointments) {
+    const patient = patients.get(a.patient_id);
+    if (!a.needs_followup || !patient || a.end_iso > now) continue;
+    if ((latestStart.get(a.patient_id) ?? "") > a.start_iso) continue;
+    out.push({ appointment: a, patient: rowToPatient(patient) });
+  }
+  return out.sort((x, y) => x.appointment.start_iso.localeCompare(y.appointment.start_iso));
+});
+
+/** "Documents from this visit", newest first. */
+export const listAppointmentFiles = command("list_appointment_files", async (appointmentId: number): Promise<PatientFile[]> => {
+  const store = await getStore();
+  if (!store.appointments.some((a) => a.id === appointmentId)) throw new Error(t("appointment_not_found"));
+  return store.files.filter((f) => f.appointment_id === appointmentId && !f.archived);
+});
+
+// Files an existing file under a visit of the same patient, or unlinks it with null.
+export const setFileAppointment = command("set_file_appointment", async (fileId: number, appointmentId: number | null): Promise<PatientFile> => {
+  return commit((s) => {
+    const idx = s.files.findIndex((f) => f.id === fileId);
+    if (idx === -1) throw new Error(t("file_not_found"));
+    if (appointmentId !== null) assertAppointmentOfPatient(s, appointmentId, s.files[idx].patient_id);
+    s.files[idx] = withAppointment(s.files[idx], appointmentId);
+    return s.files[idx];
+  });
+});
+
+
+
+function parseJsonOrNull(raw: string | null) {
+  if (!raw) return null;
+  try {
+    return JSON.parse(raw);
+  } catch {
+    return null;
+  }
+}
+
+export type PatientSummary = {
+  patient: Patient;
+  file_count: number;
+  archived_file_count: number;
+  exam_count: number;
+  last_exam_at: string | null;
+  last_appointment: Appointment | null; // most recent one that has already started
+  next_appointment: Appointment | null;
+  storage_bytes: number;
+};
+
+// Data URLs are measured in place; on-disk assets cost one HEAD each (unreachable ones count as 0).
+async function storedSize(path: string): Promise<number> {
+  if (path.startsWith("data:")) {
+    const comma = path.indexOf(",");
+    const body = path.slice(comma + 1);
+    if (!path.slice(0, comma).endsWith(";base64")) return new TextEncoder().encode(decodeURIComponent(body)).length;
+    return Math.floor((body.length * 3) / 4) - (body.endsWith("==") ? 2 : body.endsWith("=") ? 1 : 0);
+  }
+  if (!path.startsWith("/__naju_asset/")) return 0;
+  try {
+    const res = await fetch(path, { method: "HEAD", cache: "no-store" });
+    return res.ok ? Number(res.headers.get("Content-Length") || 0) : 0;
+  } catch {
+    return 0;
+  }
 }
 
+// Everything the patient header needs in one call.
+export const patientSummary = command("patient_summary", async (patientId: string): Promise<PatientSummary> => {
+  const store = await getStore();
+  const patient = store.patients.find((p) => p.id === patientId);
+  if (!patient) throw new Error(t("patient_not_found"));
+  const files = store.files.filter((f) => f.patient_id === patientId);
+  const exams = files.filter((f) => f.kind === "exam");
+  const now = nowIso();
+  let last: Appointment | null = null;
+  let next: Appointment | null = null;
+  for (const a of store.appointments) {
+    if (a.patient_id !== patientId) continue;
+    if (a.start_iso <= now) {
+      if (!last || a.start_iso > last.start_iso) last = a;
+    } else if (!next || a.start_iso < next.start_iso) {
+      next = a;
+    }
+  }
+  const sizes = await Promise.all(files.map((f) => storedSize(f.path)));
+  return {
+    patient: rowToPatient(patient),
+    file_count: files.length,
+    archived_file_count: files.filter((f) => f.archived).length,
+    exam_count: exams.length,
+    last_exam_at: exams.reduce<string | null>((max, f) => (!max || f.created_at > max ? f.created_at : max), null),
+    last_appointment: last,
+    next_appointment: next,
+    storage_bytes: sizes.reduce((sum, n) => sum + n, 0),
+  };
+});
 
+// File metadata for exports: inline data URLs are left out, asset paths are kept both relative and absolute.
+function exportedFileRef(file: PatientFile) {
+  const inline = file.path.startsWith("data:");
+  return {
+    id: file.id,
+    kind: file.kind,
+    filename: file.filename,
+    created_at: file.created_at,
+    sha256: file.sha256 ?? null,
+    stored_inline: inline,
+    path: inline ? null : file.path,
+    url: inline ? null : new URL(file.path, window.location.origin).href,
+  };
+}
 
-export async function listErrorReports(): Promise<ErrorReport[]> {
+export const exportPatientJson = command("export_patient_json", async (patientId: string): Promise<any> => {
   const store = await getStore();
-  return (store.errorReports || []).slice().sort((a, b) => (b.created_at || "").localeCompare(a.created_at || ""));
+  const patient = store.patients.find((p) => p.id === patientId);
+  if (!patient) throw new Error(t("patient_not_found"));
+
+  const files = store.files.filter((f) => f.patient_id === patientId);
+  const appointments = store.appointments.filter((a) => a.patient_id === patientId).slice().sort(sortByStartIso);
+  // search_norm is an internal search index (rebuilt when a row is loaded), not patient data.
+  const { photo_path, search_norm: _searchNorm, ...demographics } = rowToPatient(patient);
+
+  const timeline = [
+    ...files.map((f) => ({
+      at: f.created_at,
+      type: f.kind,
+      file: exportedFileRef(f),
+      payload: f.kind === "exam" || f.kind === "note" ? parseJsonOrNull(f.meta_json) : undefined,
+    })),
+    ...appointments.map((a) => ({ at: a.start_iso, type: "appointment", appointment: a })),
+  ].sort((a, b) => (Date.parse(a.at) || 0) - (Date.parse(b.at) || 0));
+
+  return {
+    format: "naju-patient/1",
+    exported_at: nowIso(),
+    patient: { ...demographics, has_photo: Boolean(photo_path) },
+    custom_fields: customValuesOf(store, patientId),
+    timeline,
+  };
+});
+
+type PatientsBundle = {
+  format: "naju-bundle/1";
+  exported_at: string;
+  patients: Patient[];
+  files: PatientFile[];
+  appointments: Appointment[];
+};
+
+export const NDJSON_TABLES = ["patients", "files", "exams", "appointments"] as const;
+
+export type NdjsonTable = (typeof NDJSON_TABLES)[number];
+
+// Normalized header -> patient field. Headers are matched after normText, so accents and case don't matter.
+const CSV_HEADER_ALIASES: Record<string, PatientInputKey> = {
+  nombre: "name",
+  "nombre completo": "name",
+  paciente: "name",
+  name: "name",
+  "tipo documento": "doc_type",
+  "tipo de documento": "doc_type",
+  "tipo doc": "doc_type",
+  documento: "doc_number",
+  "numero documento": "doc_number",
+  "numero de documento": "doc_number",
+  cedula: "doc_number",
+  identificacion: "doc_number",
+  aseguradora: "insurer",
+  eps: "insurer",
+  "fecha nacimiento": "birth_date",
+  "fecha de nacimiento": "birth_date",
+  nacimiento: "birth_date",
+  sexo: "sex",
+  telefono: "phone",
+  celular: "phone",
+  movil: "phone",
+  correo: "email",
+  "correo electronico": "email",
+  email: "email",
+  direccion: "address",
+  "contacto emergencia": "emergency_contact",
+  "contacto de emergencia": "emergency_contact",
+  notas: "notes",
+  observaciones: "notes",
+};
+
+const CSV_PREVIEW_ROWS = 5;
+
+export type CsvInspection = {
+  encoding: "utf-8" | "windows-1252";
+  delimiter: CsvDelimiter;
+  columns: { header: string; field: PatientInputKey | null }[]; // field: best guess, null if none
+  rows: string[][]; // first few data rows, for the mapping step
+};
+
+/** Reads a CSV's header and first rows so the UI can confirm the column mapping before importing. */
+export const inspectCsv = command("inspect_csv", async (source: File): Promise<CsvInspection> => {
+  let bytes: Uint8Array;
+  try {
+    bytes = new Uint8Array(await source.slice(0, 256 * 1024).arrayBuffer());
+  } catch {
+    throw new Error(t("file_read_failed"));
+  }
+  const { text, encoding } = decodeCsvBytes(bytes);
+  const delimiter = detectDelimiter(text.split(/\r?\n/, 1)[0] ?? "");
+  const [header, ...rows] = parseCsv(text, delimiter, CSV_PREVIEW_ROWS + 1);
+  if (!header || header.every((h) => !h.trim())) throw new Error(t("csv_unreadable"));
+  const used = new Set<PatientInputKey>();
+  const columns = header.map((h) => {
+    const guess = CSV_HEADER_ALIASES[normText(h).replace(/[_.-]+/g, " ")] ?? null;
+    const field = guess && !used.has(guess) ? guess : null;
+    if (field) used.add(field);
+    return { header: h.trim(), field };
+  });
+  return { encoding, delimiter, columns, rows };
+});
+
+export type MonthlyReport = {
+  filename: string;
+  new_patients: number;
+  appointments_done: number; // already started by the time the report runs
+  appointments_upcoming: number;
+  exams_by_type: Record<string, number>;
+  files_imported: number; // attachments and photos
+};
+
+const MONTH_NAMES = ["Enero", "Febrero", "Marzo", "Abril", "Mayo", "Junio", "Julio", "Agosto", "Septiembre", "Octubre", "Noviembre", "Diciembre"];
+
+/** One-page PDF of the month's activity (clinic calendar), downloaded as `filename`. */
+export const monthlyReportPdf = command("monthly_report_pdf", async (year: number, month: number, filename?: string): Promise<MonthlyReport> => {
+  if (!Number.isInteger(year) || !Number.isInteger(month) || month < 1 || month > 12) throw new Error(t("invalid_date_range"));
+  const ym = `${year}-${String(month).padStart(2, "0")}`;
+  const inMonth = (iso: string) => clinicDateIso(new Date(iso)).slice(0, 7) === ym;
+  const store = await getStore();
+  const now = nowIso();
+  const appointments = store.appointments.filter((a) => inMonth(a.start_iso));
+  const exams_by_type: Record<string, number> = {};
+  for (const f of store.files) if (f.kind === "exam" && inMonth(f.created_at)) exams_by_type[examTypeOf(f)] = (exams_by_type[examTypeOf(f)] ?? 0) + 1;
+  const name = filename || `naju-informe-${ym}.pdf`;
+  const report: MonthlyReport = {
+    filename: name,
+    new_patients: store.patients.filter((p) => inMonth(p.created_at)).length,
+    appointments_done: appointments.filter((a) => a.start_iso <= now).length,
+    appointments_upcoming: appointments.filter((a) => a.start_iso > now).length,
+    exams_by_type,
+    files_imported: store.files.filter((f) => (f.kind === "attachment" || f.kind === "photo") && inMonth(f.created_at)).length,
+  };
+
+  const page = new PdfPage();
+  let y = PDF_PAGE_HEIGHT - 60;
+  page.text(50, y, 20, `Informe de actividad · ${MONTH_NAMES[month - 1]} ${year}`, true);
+  y -= 18;
+  page.text(50, y, 9, `Generado ${clinicIso().slice(0, 16).replace("T", " ")}`);
+  const section = (title: string, rows: [string, number][]) => {
+    y -= 36;
+    page.text(50, y, 13, title, true);
+    const max = Math.max(1, ...rows.map(([, n]) => n));
+    for (const [label, n] of rows) {
+      y -= 20;
+      page.text(60, y, 10, label);
+      page.rect(250, y - 2, Math.max(2, (n / max) * 240), 11, 0.6);
+      page.text(500, y, 10, String(n), true);
+    }
+  };
+  section("Pacientes", [["Pacientes nuevos", report.new_patients]]);
+  section("Citas", [
+    ["Realizadas", report.appointments_done],
+    ["Programadas", report.appointments_upcoming],
+  ]);
+  const examRows = Object.entries(exams_by_type).sort((a, b) => b[1] - a[1]);
+  section("Exámenes", examRows.length ? examRows : [["Sin exámenes", 0]]);
+  section("Archivos", [["Adjuntos y fotos importados", report.files_imported]]);
+
+  downloadDataUrl(name, `data:application/pdf;base64,${bytesToBase64(page.toBytes())}`);
+  return report;
+});
+
+export type NdjsonExport = { filename: string; lines: number };
+
+// One row per table entry. Inline file contents (data URLs) are left out; exams carry their parsed payload instead.
+function ndjsonRows(store: Store, table: NdjsonTable): unknown[] {
+  switch (table) {
+    case "patients":
+      return store.patients.map(({ search_norm: _norm, ...p }) => rowToPatient(p as Patient));
+    case "files":
+      return store.files.map((f) => ({ ...f, path: f.path.startsWith("data:") ? null : f.path }));
+    case "exams":
+      return store.files
+        .filter((f) => f.kind === "exam")
+        .map((f) => ({ file_id: f.id, patient_id: f.patient_id, exam_type: examTypeOf(f), created_at: f.created_at, payload: parseJsonOrNull(f.meta_json) }));
+    case "appointments":
+      return store.appointments;
+  }
+}
+
+/** Newline-delimited JSON dump of one whitelisted table, for analytics pipelines. */
+export const exportNdjson = command("export_ndjson", async (table: string, filename?: string): Promise<NdjsonExport> => {
+  if (!(NDJSON_TABLES as readonly string[]).includes(table)) throw new Error(t("unknown_table", { table }));
+  const store = await getStore();
+  const lines = ndjsonRows(store, table as NdjsonTable).map((row) => `${JSON.stringify(row)}\n`);
+  const name = filename || `naju-${table}-${clinicDateIso()}.ndjson`;
+  downloadTextFile(name, "application/x-ndjson", lines);
+  return { filename: name, lines: lines.length };
+});
+
+export type BundleExportSummary = { filename: string; patients: number; files: number; appointments: number };
+
+// Self-contained: dev-server assets are inlined as data URLs so the bundle doesn't depend on this install.
+export const exportPatientsBundle = command("export_patients_bundle", async (ids: string[], filename?: string): Promise<BundleExportSummary> => {
+  const store = await getStore();
+  const wanted = new Set(ids);
+  const patients = store.patients.filter((p) => wanted.has(p.id));
+  if (!patients.length) throw new Error(t("patient_not_found"));
+
+  const files: PatientFile[] = [];
+  const inlined = new Map<string, string>();
+  for (const f of store.files) {
+    if (!wanted.has(f.patient_id)) continue;
+    let path = f.path;
+    if (path.startsWith("/__naju_asset/")) {
+      try {
+        const res = await fetch(path, { cache: "no-store" });
+        if (res.ok) path = await readFileAsDataUrl(await res.blob());
+      } catch {
+        // keep the relative path; the importer leaves such files out
+      }
+      inlined.set(f.path, path);
+    }
+    files.push({ ...f, path });
+  }
+
+  const bundle: PatientsBundle = {
+    format: "naju-bundle/1",
+    exported_at: nowIso(),
+    patients: patients.map((p) => (p.photo_path && inlined.has(p.photo_path) ? { ...p, photo_path: inlined.get(p.photo_path)! } : p)),
+    files,
+    appointments: store.appointments.filter((a) => wanted.has(a.patient_id)),
+  };
+  const name = filename || `naju-pacientes-${clinicDateIso()}.json`;
+  downloadTextFile(name, "application/json", JSON.stringify(bundle));
+  return { filename: name, patients: patients.length, files: files.length, appointments: bundle.appointments.length };
+});
+
+export type BundleImportSummary = {
+  imported_patients: number;
+  merged_patients: number;
+  imported_files: number;
+  skipped_files: number;
+  missing_files: number; // still pointing at the exporting install's /__naju_asset/ folder, so left out
+  imported_appointments: number;
+};
+
+// Patients whose doc_number already exists are merged into the local record; files already present
+// (same sha256) and identical appointments are skipped. New appointments must not overlap local ones
+// unless `allowOverlap`, as in createAppointment. A /__naju_asset/ path in the bundle names a file on
+// the other install (its export couldn't inline it), so that file is not imported.
+export const importPatientsBundle = command("import_patients_bundle", async (file: File, allowOverlap = false): Promise<BundleImportSummary> => {
+  let bundle: PatientsBundle;
+  try {
+    bundle = JSON.parse(await file.text());
+  } catch {
+    throw new Error(t("invalid_bundle"));
+  }
+  if (bundle?.format !== "naju-bundle/1" || !Array.isArray(bundle.patients)) throw new Error(t("invalid_bundle"));
+
+  return commit((s) => {
+    const summary: BundleImportSummary = { imported_patients: 0, merged_patients: 0, imported_files: 0, skipped_files: 0, missing_files: 0, imported_appointments: 0 };
+    const foreign = (path: string | null | undefined) => Boolean(path?.startsWith("/__naju_asset/"));
+    const idMap = new Map<string, string>();
+    const docKey = (d: string | null | undefined) => (d ?? "").trim().toLowerCase();
+
+    for (const p of bundle.patients) {
+      const existing = docKey(p.doc_number) ? s.patients.find((x) => docKey(x.doc_number) === docKey(p.doc_number)) : undefined;
+      if (existing) {
+        idMap.set(p.id, existing.id);
+        summary.merged_patients++;
+        continue;
+      }
+      const id = s.patients.some((x) => x.id === p.id) ? newId() : p.id;
+      idMap.set(p.id, id);
+      // Provider ids and patient codes belong to the store the bundle came from.
+      s.patients.unshift({
+        ...normalizePatient(p),
+        id,
+        patient_code: s.nextPatientCode++,
+        provider_id: null,
+        photo_path: foreign(p.photo_path) ? null : p.photo_path,
+        search_norm: patientSearchNorm(p),
+      });
+      addInsurerName(s.insurers, p.insurer);
+      summary.imported_patients++;
+    }
+
+    const drafts: NewFile[] = [];
+    // Appointments first so files linked to a visit can follow it to its new id.
+    const appointmentMap = new Map<number, number>();
+    for (const a of Array.isArray(bundle.appointments) ? bundle.appointments : []) {
+      const patientId = idMap.get(a.patient_id);
+      if (!patientId) continue;
+      const same = s.appointments.find((x) => x.patient_id === patientId && x.start_iso === a.start_iso && x.title === a.title);
+      if (same) {
+        appointmentMap.set(a.id, same.id);
+        continue;
+      }
+      if (!allowOverlap) assertNoOverlap(s, a.start_iso, a.end_iso);
+      const id = s.nextAppointmentId++;
+      appointmentMap.set(a.id, id);
+      s.appointments.unshift({ ...a, id, patient_id: patientId });
+      summary.imported_appointments++;
+    }
+
+    for (const f of Array.isArray(bundle.files) ? bundle.files : []) {
+      const patientId = idMap.get(f.patient_id);
+      if (!patientId) continue;
+      if (foreign(f.path)) {
+        summary.missing_files++;
+        continue;
+      }
+      const dup = f.sha256 && s.files.some((x) => x.patient_id === patientId && x.sha256 === f.sha256);
+      if (dup) {
+        summary.skipped_files++;
+        continue;
+      }
+      const { id: _oldId, appointment_id: oldAppointment, ...rest } = f;
+      const appointmentId = oldAppointment !== undefined ? appointmentMap.get(oldAppointment) : undefined;
+      drafts.push(appointmentId !== undefined ? { ...rest, patient_id: patientId, appointment_id: appointmentId } : { ...rest, patient_id: patientId });
+    }
+    summary.imported_files = addFiles(s, drafts).length;
+    return summary;
+  });
+});
+
+export const getBackupSettings = command("get_backup_settings", async (): Promise<BackupSettings> => {
+  const { auto_backup_enabled, auto_backup_dir, auto_backup_keep_count } = (await getStore()).settings;
+  return { auto_backup_enabled, auto_backup_dir, auto_backup_keep_count };
+});
+
+// Same rules as setSetting for each key; the keys in `patch` are saved together or not at all.
+export const setBackupSettings = command("set_backup_settings", async (patch: Partial<BackupSettings>): Promise<BackupSettings> => {
+  const values: Record<string, unknown> = {};
+  for (const key of ["auto_backup_enabled", "auto_backup_dir", "auto_backup_keep_count"] as const) {
+    if (patch[key] !== undefined) values[key] = patch[key];
+  }
+  await saveSettings(values);
+  const { auto_backup_enabled, auto_backup_dir, auto_backup_keep_count } = (await getStore()).settings;
+  return { auto_backup_enabled, auto_backup_dir, auto_backup_keep_count };
+});
+
+export type BackupResult = { path: string; at: string; pruned: number };
+
+let backupRunning = false;
+
+export const runBackup = command("run_backup", async (): Promise<BackupResult> => {
+  const store = await getStore();
+  await checkFreeSpace(JSON.stringify(store).length);
+  let j: any = null;
+  backupRunning = true;
+  try {
+    const res = await fetch(BACKUP_ENDPOINT, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ dir: store.settings.auto_backup_dir, keep: store.settings.auto_backup_keep_count }),
+    });
+    j = await res.json();
+  } catch {
+    throw new Error(t("backup_unavailable"));
+  } finally {
+    backupRunning = false;
+  }
+  if (!j?.ok) throw new Error(t("backup_failed", { reason: serverError(j).message }));
+  const at = nowIso();
+  store.settings = { ...store.settings, last_backup_at: at };
+  await persistStore(store);
+  return { path: String(j.path), at, pruned: Number(j.pruned) || 0 };
+});
+
+const AUTO_BACKUP_INTERVAL_MS = 24 * 60 * 60 * 1000;
+
+/**
+ * Startup hook: backs up when enabled and the last backup is older than a day.
+ * Never throws; the outcome is announced with a `naju:backup` window event.
+ */
+export async function autoBackupIfDue(): Promise<void> {
+  try {
+    const { settings } = await getStore();
+    if (!settings.auto_backup_enabled) return;
+    const last = settings.last_backup_at ? Date.parse(settings.last_backup_at) : NaN;
+    if (!Number.isNaN(last) && Date.now() - last < AUTO_BACKUP_INTERVAL_MS) return;
+    const result = await runBackup();
+    window.dispatchEvent(new CustomEvent("naju:backup", { detail: { ok: true, ...result } }));
+  } catch (e: any) {
+    window.dispatchEvent(new CustomEvent("naju:backup", { detail: { ok: false, error: String(e?.message || e) } }));
+  }
+}
+
+export type FolderRepair = { checked: number; repaired: number };
+
+export const repairPatientFolders = command("repair_patient_folders", async (): Promise<FolderRepair> => {
+  let j: any = null;
+  try {
+    const res = await fetch(REPAIR_FOLDERS_ENDPOINT, { method: "POST" });
+    j = await res.json();
+  } catch {
+    throw new Error(t("local_server_required"));
+  }
+  if (!j?.ok) throw serverError(j);
+  return { checked: Number(j.checked) || 0, repaired: Number(j.repaired) || 0 };
+});
+
+export type OrphanCleanup = { files: number; trashed_files: number; appointments: number };
+
+// Drops records that point at a patient that no longer exists.
+export const cleanOrphanRecords = command("clean_orphan_records", async (): Promise<OrphanCleanup> => {
+  return commit((s) => {
+    const ids = new Set(s.patients.map((p) => p.id));
+    const before = { files: s.files.length, trashed_files: s.trash.length, appointments: s.appointments.length };
+    s.files = s.files.filter((f) => ids.has(f.patient_id));
+    s.trash = s.trash.filter((x) => ids.has(x.file.patient_id));
+    s.appointments = s.appointments.filter((a) => ids.has(a.patient_id));
+    s.customValues = s.customValues.filter((v) => ids.has(v.patient_id));
+    s.patientHistory = s.patientHistory.filter((r) => ids.has(r.patient_id));
+    s.patientRelations = s.patientRelations.filter((r) => ids.has(r.patient_id) && ids.has(r.related_id));
+    s.patientLinks = s.patientLinks.filter((l) => ids.has(l.patient_id));
+    return {
+      files: before.files - s.files.length,
+      trashed_files: before.trashed_files - s.trash.length,
+      appointments: before.appointments - s.appointments.length,
+    };
+  });
+});
+
+export type StoreOptimization = { before_bytes: number; after_bytes: number; reclaimed_bytes: number };
+
+/**
+ * Rewrites the store from a clean round-trip: derived fields (search_norm, insurer lookup) are
+ * rebuilt from scratch and leftovers from older versions are dropped. Refused while a backup is
+ * copying store.json.
+ */
+export const optimizeStore = command("optimize_store", async (): Promise<StoreOptimization> => {
+  if (backupRunning) throw new Error(t("backup_in_progress"));
+  return commit((s) => {
+    const before = JSON.stringify(s).length;
+    const clean = normalizeStore(JSON.parse(JSON.stringify(s)));
+    clean.patients = clean.patients.map((p) => ({ ...p, search_norm: patientSearchNorm(p) }));
+    clean.insurers = clean.patients.reduce<string[]>((acc, p) => addInsurerName(acc, p.insurer), clean.insurers.slice());
+    Object.assign(s, clean);
+    thumbCache.clear();
+    const after = JSON.stringify(s).length;
+    return { before_bytes: before, after_bytes: after, reclaimed_bytes: Math.max(0, before - after) };
+  });
+});
+
+/**
+ * Rolling execution times of every command since startup (count, mean, p95, max), plus
+ * "load_store" for the store (re)loads, to see where latency actually goes.
+ */
+export const perfStats = command("perf_stats", async (): Promise<CommandTiming[]> => {
+  return timingStats();
+});
+
+export type StoreRecovery = {
+  action: "none" | "restored_backup" | "restored_local_copy";
+  restored_from: string | null; // backup folder, when one was used
+  quarantined: string | null; // where the damaged store.json was copied before being replaced
+};
+
+/**
+ * Guided recovery for a store.json that won't load (see getStoreProblem()). The damaged file is
+ * always kept aside first; then it's replaced by the newest valid backup or by the browser's own
+ * copy. A store that parses fine is left alone and just reloaded, which also clears a stale lock.
+ */
+export const recoverStore = command("recover_store", async (source: "backup" | "local_copy" = "backup"): Promise<StoreRecovery> => {
+  const local = localStorage.getItem(STORAGE_KEY) ? loadStoreFromLocalStorage() : null;
+  if (source === "local_copy" && !local) throw new Error(t("recovery_failed", { reason: t("no_local_copy") }));
+  let j: any;
+  try {
+    const res = await fetch(RECOVER_STORE_ENDPOINT, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ dir: local?.settings.auto_backup_dir ?? "", restore: source === "backup" }),
+    });
+    j = await res.json();
+  } catch {
+    throw new Error(t("local_server_required"));
+  }
+  if (!j?.ok) throw new Error(t("recovery_failed", { reason: serverError(j).message }));
+  let action: StoreRecovery["action"] = j.action === "restored_backup" ? "restored_backup" : "none";
+  if (source === "local_copy" && j.action !== "none" && local) {
+    await persistStore(local);
+    action = "restored_local_copy";
+  }
+  cachedStore = null;
+  await getStore();
+  return { action, restored_from: j.restored_from ?? null, quarantined: j.quarantined ?? null };
+});
+
+export type SearchIndexRebuild = { checked: number; updated: number };
+
+// Recomputes search_norm for every patient, e.g. after a bulk import or a change to normText.
+// Idempotent; updated_at is left alone since nothing the user typed changed.
+export const rebuildSearchIndex = command("rebuild_search_index", async (): Promise<SearchIndexRebuild> => {
+  return commit((s) => {
+    let updated = 0;
+    s.patients = s.patients.map((p) => {
+      const norm = patientSearchNorm(p);
+      if (p.search_norm === norm) return p;
+      updated++;
+      return { ...p, search_norm: norm };
+    });
+    return { checked: s.patients.length, updated };
+  });
+});
+
+export type MaintenanceOptions = { repairFolders?: boolean; cleanOrphans?: boolean; rebuildSearch?: boolean; optimize?: boolean };
+
+export type MaintenanceReport = {
+  folders: FolderRepair | null;
+  orphans: OrphanCleanup | null;
+  search: SearchIndexRebuild | null;
+  optimized: StoreOptimization | null;
+};
+
+export const runMaintenance = command("run_maintenance", async (opts: MaintenanceOptions = {}): Promise<MaintenanceReport> => {
+  const orphans = opts.cleanOrphans ? await cleanOrphanRecords() : null;
+  const folders = opts.repairFolders ? await repairPatientFolders() : null;
+  const search = opts.rebuildSearch ? await rebuildSearchIndex() : null;
+  // Last, so it also reclaims what the orphan cleanup just dropped.
+  const optimized = opts.optimize ? await optimizeStore() : null;
+  return { folders, orphans, search, optimized };
+});
+
+// A backup older than this (or none at all) is reported as overdue.
+const BACKUP_OVERDUE_DAYS = 7;
+
+export type Diagnostics = {
+  patients: number;
+  files: number;
+  trashed_files: number;
+  appointments: number;
+  error_reports: number;
+  store_bytes: number;
+  language: Lang;
+  last_backup_at: string | null;
+  backup_overdue: boolean;
+};
+
+export const diagnostics = command("diagnostics", async (): Promise<Diagnostics> => {
+  const store = await getStore();
+  const last = store.settings.last_backup_at ? Date.parse(store.settings.last_backup_at) : NaN;
+  return {
+    patients: store.patients.length,
+    files: store.files.length,
+    trashed_files: store.trash.length,
+    appointments: store.appointments.length,
+    error_reports: store.errorReports.length,
+    store_bytes: JSON.stringify(store).length,
+    language: getLang(),
+    last_backup_at: store.settings.last_backup_at,
+    backup_overdue: Number.isNaN(last) || Date.now() - last > BACKUP_OVERDUE_DAYS * 86_400_000,
+  };
+});
+
+export type AccessEvent = {
+  ts: string;
+  actor: string;
+  action: AuditAction;
+  command: string;
+  entity: string; // command name without its verb: "read_file_chunk" -> "file_chunk"
+  ids: (string | number)[];
+  patient_id: string | null;
+};
+
+export type AccessReportFilters = { actor?: string; patientId?: string };
+
+// What the numeric first argument of these commands refers to. Commands that take a patient id
+// name it directly; anything else (settings, reports, providers) is about no single patient.
+const AUDIT_ID_ARGS: Record<string, "file" | "appointment" | "link"> = {
+  read_encrypted_file: "file", read_stored_file: "file", archive_file: "file", unarchive_file: "file", pin_file: "file",
+  unpin_file: "file", set_file_label: "file", set_file_kind: "file", delete_file: "file", restore_trashed_file: "file",
+  relink_file: "file", file_size: "file", read_file_chunk: "file", get_thumbnail: "file", attach_exam_signature: "file",
+  get_exam_signature: "file", duplicate_mental_exam: "file", export_file: "file", set_file_appointment: "file",
+  update_appointment: "appointment", delete_appointment: "appointment", set_followup_flag: "appointment",
+  list_appointment_files: "appointment", remove_patient_link: "link", open_patient_link: "link",
+};
+
+// The patient an audit entry is about, from the store as it is before the command runs.
+function auditPatientId(cmd: string, args: unknown[]): string | null {
+  const store = cachedStore;
+  if (!store) return null;
+  const [first] = args;
+  const kind = AUDIT_ID_ARGS[cmd];
+  if (kind && typeof first === "number") {
+    if (kind === "file") return (store.files.find((f) => f.id === first) ?? store.trash.find((e) => e.file.id === first)?.file)?.patient_id ?? null;
+    if (kind === "appointment") return store.appointments.find((a) => a.id === first)?.patient_id ?? null;
+    return store.patientLinks.find((l) => l.id === first)?.patient_id ?? null;
+  }
+  return typeof first === "string" && store.patients.some((p) => p.id === first) ? first : null;
+}
+
+setAuditPatientResolver(auditPatientId);
+
+// `from`/`to` are inclusive YYYY-MM-DD days. The patient filter uses the patient_id each entry was
+// recorded with; entries written before that field existed match only when they logged the id itself.
+export const accessReport = command("access_report", async (from: string, to: string, filters?: AccessReportFilters): Promise<AccessEvent[]> => {
+  await flushLog();
+  let j: any = null;
+  try {
+    const res = await fetch(`${AUDIT_ENDPOINT}?from=${encodeURIComponent(from)}&to=${encodeURIComponent(to)}`, { cache: "no-store" });
+    j = await res.json();
+  } catch {
+    throw new Error(t("local_server_required"));
+  }
+  if (!j?.ok) throw serverError(j);
+
+  const events: AccessEvent[] = (Array.isArray(j.entries) ? j.entries : []).map((e: any): AccessEvent => ({
+    ts: String(e.ts),
+    actor: String(e.actor || ""),
+    action: e.action === "access" ? "access" : "mutation",
+    command: String(e.cmd || ""),
+    entity: String(e.cmd || "").split("_").slice(1).join("_"),
+    ids: Array.isArray(e.ids) ? e.ids : [],
+    patient_id: typeof e.patient_id === "string" ? e.patient_id : null,
+  }));
+  return events
+    .filter((e) => !filters?.actor || e.actor === filters.actor)
+    .filter((e) => {
+      if (!filters?.patientId) return true;
+      return e.patient_id ? e.patient_id === filters.patientId : e.ids.includes(filters.patientId);
+    })
+    .sort((a, b) => a.ts.localeCompare(b.ts));
+});
+
+export const exportAccessReportCsv = command("export_access_report_csv", async (from: string, to: string, filters?: AccessReportFilters): Promise<number> => {
+  const events = await accessReport(from, to, filters);
+  downloadTextFile(`naju-accesos-${from}_${to}.csv`, "text/csv;charset=utf-8", accessEventsToCsv(events));
+  return events.length;
+});
+
+export type DbStats = {
+  counts: Record<"patients" | "archived_patients" | "files" | "trashed_files" | "appointments" | "error_reports" | "custom_values", number>;
+  avg_files_per_patient: number;
+  top_patients_by_files: { patient_id: string; name: string; files: number }[];
+  store_bytes: number | null; // store.json on disk; null without the local server
+  tree_bytes: number | null; // the whole patients/ folder
+};
+
+const USAGE_CACHE_MS = 60_000;
+let usageCache: { at: number; store_bytes: number; tree_bytes: number } | null = null;
+
+// The folder walk is the slow part, so its result is reused for a minute.
+async function readDiskUsage() {
+  if (usageCache && Date.now() - usageCache.at < USAGE_CACHE_MS) return usageCache;
+  try {
+    const res = await fetch(USAGE_ENDPOINT, { cache: "no-store" });
+    const j = await res.json();
+    if (!j?.ok) return null;
+    usageCache = { at: Date.now(), store_bytes: Number(j.store_bytes) || 0, tree_bytes: Number(j.tree_bytes) || 0 };
+    return usageCache;
+  } catch {
+    return null;
+  }
 }
 
-export async function createErrorReport(input: ErrorReportInput): Promise<ErrorReport> {
+export const dbStats = command("db_stats", async (): Promise<DbStats> => {
+  const store = await getStore();
+  const perPatient = new Map<string, number>();
+  for (const f of store.files) perPatient.set(f.patient_id, (perPatient.get(f.patient_id) ?? 0) + 1);
+  const names = new Map(store.patients.map((p) => [p.id, p.name]));
+  const top = Array.from(perPatient, ([patient_id, files]) => ({ patient_id, name: names.get(patient_id) ?? "", files }))
+    .sort((a, b) => b.files - a.files)
+    .slice(0, 10);
+  const usage = await readDiskUsage();
+  return {
+    counts: {
+      patients: store.patients.length,
+      archived_patients: store.patients.filter((p) => p.archived_at).length,
+      files: store.files.length,
+      trashed_files: store.trash.length,
+      appointments: store.appointments.length,
+      error_reports: store.errorReports.length,
+      custom_values: store.customValues.length,
+    },
+    avg_files_per_patient: store.patients.length ? Math.round((store.files.length / store.patients.length) * 10) / 10 : 0,
+    top_patients_by_files: top,
+    store_bytes: usage?.store_bytes ?? null,
+    tree_bytes: usage?.tree_bytes ?? null,
+  };
+});
+
+export const listErrorReports = command("list_error_reports", async (): Promise<ErrorReport[]> => {
+  const store = await getStore();
+  return (store.errorReports || []).slice().sort((a, b) => (b.created_at || "").localeCompare(a.created_at || ""));
+});
+
+export const createErrorReport = command("create_error_report", async (input: ErrorReportInput): Promise<ErrorReport> => {
   const store = await getStore();
   const now = nowIso();
   const title = (input.title || "").trim();
   const description = (input.description || "").trim();
-  if (!title) throw new Error("Título requerido");
-  if (!description) throw new Error("Describe el error (qué pasó)");
+  if (!title) throw new Error(t("report_title_required"));
+  if (!description) throw new Error(t("report_description_required"));
 
   const report: ErrorReport = {
     id: store.nextErrorId++,
@@ -484,12 +4773,12 @@ export async function createErrorReport(input: ErrorReportInput): Promise<ErrorR
   store.errorReports.unshift(report);
   await persistStore(store);
   return report;
-}
+});
 
-export async function updateErrorReport(reportId: number, patch: Partial<ErrorReportInput>): Promise<ErrorReport> {
+export const updateErrorReport = command("update_error_report", async (reportId: number, patch: Partial<ErrorReportInput>): Promise<ErrorReport> => {
   const store = await getStore();
   const idx = (store.errorReports || []).findIndex((r) => r.id === reportId);
-  if (idx === -1) throw new Error("Reporte no encontrado");
+  if (idx === -1) throw new Error(t("report_not_found"));
   const cur = store.errorReports[idx];
   const updated: ErrorReport = {
     ...cur,
@@ -507,10 +4796,61 @@ export async function updateErrorReport(reportId: number, patch: Partial<ErrorRe
   store.errorReports[idx] = updated;
   await persistStore(store);
   return updated;
-}
+});
 
-export async function deleteErrorReport(reportId: number): Promise<void> {
+export const deleteErrorReport = command("delete_error_report", async (reportId: number): Promise<void> => {
   const store = await getStore();
   store.errorReports = (store.errorReports || []).filter((r) => r.id !== reportId);
   await persistStore(store);
-}
+});
+
+// Session-wide safety switch for demos and audits; not persisted, a reload starts writable.
+export const setReadOnly = command("set_read_only", async (enabled: boolean): Promise<boolean> => {
+  setReadOnlyMode(enabled === true);
+  return isReadOnlyMode();
+});
+
+export const isReadOnly = (): boolean => isReadOnlyMode();
+
+// Read-only commands the batch endpoint may run. Declared last so every command above exists.
+const BATCH_COMMANDS: Record<string, (...args: any[]) => Promise<unknown>> = {
+  get_patient: getPatient,
+  get_patient_by_code: getPatientByCode,
+  list_patients: listPatients,
+  list_patient_files: listPatientFiles,
+  search_patient_files: searchPatientFiles,
+  list_patient_photos: listPatientPhotos,
+  list_exams: listExams,
+  search_exams: searchExams,
+  search_notes: searchNotes,
+  list_appointments_for_patient: listAppointmentsForPatient,
+  list_insurers: listInsurers,
+  list_providers: listProviders,
+  list_trash: listTrash,
+  patient_summary: patientSummary,
+  get_thumbnails: getThumbnails,
+  diagnostics,
+};
+
+export type BatchRequest = { cmd: string; args?: unknown[] };
+
+export type BatchResult = { ok: true; result: unknown } | { ok: false; error: string };
+
+// Runs read-only commands in order against the one cached store; a failing entry doesn't fail the batch.
+export const batch = command("batch", async (requests: BatchRequest[]): Promise<BatchResult[]> => {
+  await getStore();
+  const out: BatchResult[] = [];
+  for (const req of requests) {
+    const fn = Object.prototype.hasOwnProperty.call(BATCH_COMMANDS, req.cmd) ? BATCH_COMMANDS[req.cmd] : undefined;
+    if (!fn) {
+      out.push({ ok: false, error: t("command_not_allowed", { cmd: String(req.cmd) }) });
+      continue;
+    }
+    try {
+      out.push({ ok: true, result: await fn(...(Array.isArray(req.args) ? req.args : [])) });
+    } catch (e: any) {
+      out.push({ ok: false, error: String(e?.message || e) });
+    }
+  }
+  return out;
+});
@@ -0,0 +1,30 @@
+// AES-GCM helpers for encrypted attachments. The key is derived from the practice passphrase with
+// PBKDF2-SHA256; each file gets its own random 96-bit nonce.
+
+const PBKDF2_ITERATIONS = 210_000;
+
+export function randomBytes(length: number) {
+  return crypto.getRandomValues(new Uint8Array(length));
+}
+
+export async function deriveKey(passphrase: string, salt: Uint8Array): Promise<CryptoKey> {
+  const material = await crypto.subtle.importKey("raw", new TextEncoder().encode(passphrase), "PBKDF2", false, ["deriveKey"]);
+  return crypto.subtle.deriveKey(
+    { name: "PBKDF2", salt, iterations: PBKDF2_ITERATIONS, hash: "SHA-256" },
+    material,
+    { name: "AES-GCM", length: 256 },
+    false,
+    ["encrypt", "decrypt"]
+  );
+}
+
+export async function encryptBytes(key: CryptoKey, plain: Uint8Array): Promise<{ nonce: Uint8Array; data: Uint8Array }> {
+  const nonce = randomBytes(12);
+  const data = new Uint8Array(await crypto.subtle.encrypt({ name: "AES-GCM", iv: nonce }, key, plain));
+  return { nonce, data };
+}
+
+/** Throws when the key is wrong or the data was tampered with (GCM tag mismatch). */
+export async function decryptBytes(key: CryptoKey, nonce: Uint8Array, data: Uint8Array): Promise<Uint8Array> {
+  return new Uint8Array(await crypto.subtle.decrypt({ name: "AES-GCM", iv: nonce }, key, data));
+}
@@ -0,0 +1,69 @@
+// CSV reading for imports. Spreadsheet exports from es-locale Excel use ";" because "," is the
+// decimal separator, so the delimiter is guessed from the header line rather than assumed.
+
+export type CsvDelimiter = "," | ";" | "\t";
+
+const DELIMITERS: CsvDelimiter[] = [",", ";", "\t"];
+
+/** The delimiter that splits the first line into the most fields (quoted sections ignored). */
+export function detectDelimiter(firstLine: string): CsvDelimiter {
+  const unquoted = firstLine.replace(/"[^"]*"/g, "");
+  let best: CsvDelimiter = ",";
+  let bestCount = 0;
+  for (const d of DELIMITERS) {
+    const count = unquoted.split(d).length - 1;
+    if (count > bestCount) {
+      best = d;
+      bestCount = count;
+    }
+  }
+  return best;
+}
+
+/** Bytes to text: UTF-8 when valid (BOM dropped), otherwise Windows-1252 as older Excel writes it. */
+export function decodeCsvBytes(bytes: Uint8Array): { text: string; encoding: "utf-8" | "windows-1252" } {
+  try {
+    // stream: a sample cut mid-character isn't treated as invalid UTF-8.
+    const text = new TextDecoder("utf-8", { fatal: true }).decode(bytes, { stream: true });
+    return { text: text.replace(/^\uFEFF/, ""), encoding: "utf-8" };
+  } catch {
+    return { text: new TextDecoder("windows-1252").decode(bytes), encoding: "windows-1252" };
+  }
+}
+
+/** RFC 4180 rows (quoted fields, "" escapes, CRLF). Stops after `maxRows` rows. */
+export function parseCsv(text: string, delimiter: CsvDelimiter, maxRows = Infinity): string[][] {
+  const rows: string[][] = [];
+  let row: string[] = [];
+  let field = "";
+  let quoted = false;
+  for (let i = 0; i < text.length && rows.length < maxRows; i++) {
+    const c = text[i];
+    if (quoted) {
+      if (c === '"' && text[i + 1] === '"') {
+        field += '"';
+        i++;
+      } else if (c === '"') {
+        quoted = false;
+      } else {
+        field += c;
+      }
+    } else if (c === '"') {
+      quoted = true;
+    } else if (c === delimiter) {
+      row.push(field);
+      field = "";
+    } else if (c === "\n" || c === "\r") {
+      if (c === "\r" && text[i + 1] === "\n") i++;
+      row.push(field);
+      field = "";
+      if (row.some((f) => f !== "")) rows.push(row);
+      row = [];
+    } else {
+      field += c;
+    }
+  }
+  row.push(field);
+  if (rows.length < maxRows && row.some((f) => f !== "")) rows.push(row);
+  return rows;
+}
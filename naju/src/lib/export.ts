@@ -1,4 +1,4 @@
-import type { Appointment } from "./api";
+import type { AccessEvent, Appointment } from "./api";
 
 function pad2(n: number) {
   return String(n).padStart(2, "0");
@@ -40,7 +40,8 @@ function foldLine(line: string) {
   return out.join("\r\n");
 }
 
-export function appointmentsToIcs(appointments: Appointment[], patientNameById: Record<string, string>) {
+// `reminderMinutes` adds a display VALARM that many minutes before each start.
+export function appointmentsToIcs(appointments: Appointment[], patientNameById: Record<string, string>, reminderMinutes?: number | null) {
   const lines: string[] = [];
   lines.push("BEGIN:VCALENDAR");
   lines.push("VERSION:2.0");
@@ -69,6 +70,13 @@ export function appointmentsToIcs(appointments: Appointment[], patientNameById:
     if (de) lines.push(`DTEND:${de}`);
     lines.push(`SUMMARY:${summary}`);
     lines.push(`DESCRIPTION:${description}`);
+    if (reminderMinutes && reminderMinutes > 0) {
+      lines.push("BEGIN:VALARM");
+      lines.push("ACTION:DISPLAY");
+      lines.push(`TRIGGER:-PT${Math.round(reminderMinutes)}M`);
+      lines.push(`DESCRIPTION:${summary}`);
+      lines.push("END:VALARM");
+    }
     lines.push("END:VEVENT");
   }
 
@@ -101,8 +109,17 @@ export function appointmentsToCsv(appointments: Appointment[], patientNameById:
   return [header, ...rows].join("\n");
 }
 
-export function downloadTextFile(filename: string, mime: string, content: string) {
-  const blob = new Blob([content], { type: mime });
+export function accessEventsToCsv(events: AccessEvent[]) {
+  const header = ["ts", "actor", "action", "entity", "command", "patient_id", "ids"].join(",");
+  const rows = events.map((e) =>
+    [csvEscape(e.ts), csvEscape(e.actor), csvEscape(e.action), csvEscape(e.entity), csvEscape(e.command), csvEscape(e.patient_id ?? ""), csvEscape(e.ids.join(" "))].join(",")
+  );
+  return [header, ...rows].join("\n");
+}
+
+// `content` may be split in parts (e.g. one per line) so large exports never build one giant string.
+export function downloadTextFile(filename: string, mime: string, content: string | string[]) {
+  const blob = new Blob(Array.isArray(content) ? content : [content], { type: mime });
   const url = URL.createObjectURL(blob);
   const a = document.createElement("a");
   a.href = url;
@@ -112,3 +129,12 @@ export function downloadTextFile(filename: string, mime: string, content: string
   a.remove();
   setTimeout(() => URL.revokeObjectURL(url), 1500);
 }
+
+export function downloadDataUrl(filename: string, dataUrl: string) {
+  const a = document.createElement("a");
+  a.href = dataUrl;
+  a.download = filename;
+  document.body.appendChild(a);
+  a.click();
+  a.remove();
+}
@@ -0,0 +1,238 @@
+// Command logging. Entries are buffered and shipped to the dev server, which appends them
+// to a daily file under patients/logs/. Only ids are logged, never names or free text.
+// Successful commands also go to the audit trail (patients/audit/), which is kept indefinitely.
+
+import { t } from "./messages";
+
+export type LogLevel = "info" | "error";
+
+type LogEntry = {
+  ts: string;
+  level: LogLevel;
+  cmd: string;
+  msg: string;
+  ids?: (string | number)[];
+  ms?: number;
+};
+
+const LOG_ENDPOINT = "/__naju_log";
+const AUDIT_ENDPOINT = "/__naju_audit";
+const FLUSH_DELAY_MS = 1500;
+const MAX_BUFFER = 500;
+
+export type AuditAction = "access" | "mutation";
+
+export type AuditEntry = {
+  ts: string;
+  cmd: string;
+  action: AuditAction;
+  ids: (string | number)[];
+  patient_id: string | null; // the patient the call was about, resolved before it ran
+  actor?: string; // stamped by the server
+};
+
+export type CommandTiming = { cmd: string; count: number; mean_ms: number; p95_ms: number; max_ms: number };
+
+// p95 comes from the last PERF_SAMPLES durations per command; count, mean and max cover the whole session.
+const PERF_SAMPLES = 200;
+
+type TimingAcc = { count: number; total: number; max: number; recent: number[] };
+
+const timings = new Map<string, TimingAcc>();
+
+let buffer: LogEntry[] = [];
+let auditBuffer: AuditEntry[] = [];
+let flushTimer: ReturnType<typeof setTimeout> | null = null;
+
+const ID_RE = /^(?:[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}|p_\d+_[0-9a-f]+)$/i;
+
+function safeIds(args: unknown[]) {
+  const ids: (string | number)[] = [];
+  for (const a of args) {
+    if (typeof a === "number" && Number.isFinite(a)) ids.push(a);
+    else if (typeof a === "string" && ID_RE.test(a)) ids.push(a);
+  }
+  return ids;
+}
+
+// Commands that only read the store, by exact name. Anything not listed counts as a mutation (refused
+// in read-only mode), so a new command is blocked until it's added here. Exports that download in the
+// browser are reads; export_file, reveal_in_explorer and open_patient_link write to disk or launch
+// programs through the local server, so they are not.
+const READ_COMMANDS = new Set([
+  "access_report", "age_distribution", "appointments_pending_followup", "db_stats", "delete_patient_preview", "diagnostics",
+  "duplicate_report", "export_access_report_csv", "export_appointments_ical", "export_contact_sheet", "export_ndjson",
+  "export_patient_json", "export_patients_bundle", "export_settings", "file_size", "find_free_slots", "find_patient_by_code",
+  "get_all_settings", "get_backup_settings", "get_custom_values", "get_exam_signature", "get_free_space", "get_language",
+  "get_patient", "get_patient_by_code", "get_setting", "get_thumbnail", "get_thumbnails", "inspect_csv", "list_all_files",
+  "list_all_files_page", "list_appointment_files", "list_appointments", "list_appointments_for_patient", "list_custom_fields",
+  "list_error_reports", "list_exams", "list_incomplete_patients", "list_insurers", "list_patient_files", "list_patient_links",
+  "list_patient_photos", "list_patient_revisions", "list_patients", "list_providers", "list_relations", "list_trash",
+  "monthly_report_pdf", "patient_avatar", "patient_file_count", "patient_paths", "patient_summary", "patients_index",
+  "patients_with_birthday_in", "perf_stats", "read_encrypted_file", "read_file_chunk", "read_stored_file", "render_patient_label",
+  "scan_encoding_issues", "scan_orphan_files", "search_exams", "search_notes", "validate_storage", "verify_patient_files",
+]);
+
+// Allowed in read-only mode although they aren't reads: the toggle itself, the batch runner (its
+// entries are checked one by one) and the encryption key, which only lives in memory (setting the
+// passphrase for the first time writes to the store, so unlock_encryption checks that itself).
+const READ_ONLY_EXEMPT = ["set_read_only", "batch", "unlock_encryption", "lock_encryption"];
+
+let readOnly = false;
+
+// Set by api.ts, which knows what each command's arguments refer to. Runs before the command, so a
+// delete still resolves to the patient it removed.
+let auditPatientOf: (cmd: string, args: unknown[]) => string | null = () => null;
+
+export function setAuditPatientResolver(fn: (cmd: string, args: unknown[]) => string | null) {
+  auditPatientOf = fn;
+}
+
+export function setReadOnlyMode(enabled: boolean) {
+  readOnly = enabled;
+}
+
+export function isReadOnlyMode() {
+  return readOnly;
+}
+
+function auditAction(cmd: string): AuditAction {
+  return READ_COMMANDS.has(cmd) ? "access" : "mutation";
+}
+
+function errText(e: any) {
+  if (!e) return "Error";
+  if (typeof e === "string") return e;
+  return String(e?.message || e);
+}
+
+export function log(level: LogLevel, cmd: string, msg: string, extra?: Pick<LogEntry, "ids" | "ms">) {
+  buffer.push({ ts: new Date().toISOString(), level, cmd, msg, ...extra });
+  if (buffer.length > MAX_BUFFER) buffer = buffer.slice(-MAX_BUFFER);
+  if (!flushTimer) flushTimer = setTimeout(() => void flushLog(), FLUSH_DELAY_MS);
+}
+
+// command() audits every successful call; api code only calls this for extra detail a call's args don't carry.
+export function audit(cmd: string, ids: (string | number)[], patientId: string | null = null) {
+  auditBuffer.push({ ts: new Date().toISOString(), cmd, action: auditAction(cmd), ids, patient_id: patientId });
+  if (auditBuffer.length > MAX_BUFFER) auditBuffer = auditBuffer.slice(-MAX_BUFFER);
+  if (!flushTimer) flushTimer = setTimeout(() => void flushLog(), FLUSH_DELAY_MS);
+}
+
+async function flushAudit() {
+  if (!auditBuffer.length) return;
+  const entries = auditBuffer;
+  auditBuffer = [];
+  try {
+    await fetch(AUDIT_ENDPOINT, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ entries }),
+    });
+  } catch {
+    // no dev server: audit entries are dropped
+  }
+}
+
+export async function flushLog() {
+  if (flushTimer) {
+    clearTimeout(flushTimer);
+    flushTimer = null;
+  }
+  await flushAudit();
+  if (!buffer.length) return;
+  const entries = buffer;
+  buffer = [];
+  try {
+    await fetch(LOG_ENDPOINT, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ entries }),
+    });
+  } catch {
+    // no dev server: logs are dropped
+  }
+}
+
+// Synchronous variant for pagehide: fetch() may be cancelled as the page goes away, a beacon is not.
+export function flushLogOnExit() {
+  if (flushTimer) {
+    clearTimeout(flushTimer);
+    flushTimer = null;
+  }
+  try {
+    if (auditBuffer.length) navigator.sendBeacon(AUDIT_ENDPOINT, new Blob([JSON.stringify({ entries: auditBuffer })], { type: "application/json" }));
+    if (buffer.length) navigator.sendBeacon(LOG_ENDPOINT, new Blob([JSON.stringify({ entries: buffer })], { type: "application/json" }));
+  } catch {
+    // no beacon support: logs are dropped
+  }
+  auditBuffer = [];
+  buffer = [];
+}
+
+/** Adds one duration under `name`; command() calls it for every run, failed ones included. */
+export function recordTiming(name: string, ms: number) {
+  let acc = timings.get(name);
+  if (!acc) timings.set(name, (acc = { count: 0, total: 0, max: 0, recent: [] }));
+  acc.count++;
+  acc.total += ms;
+  acc.max = Math.max(acc.max, ms);
+  acc.recent.push(ms);
+  if (acc.recent.length > PERF_SAMPLES) acc.recent.shift();
+}
+
+/** Per-command timings since the page loaded, slowest mean first. */
+export function timingStats(): CommandTiming[] {
+  const round = (n: number) => Math.round(n * 10) / 10;
+  return [...timings].map(([cmd, acc]) => {
+    const sorted = [...acc.recent].sort((a, b) => a - b);
+    const p95 = sorted[Math.min(sorted.length - 1, Math.ceil(sorted.length * 0.95) - 1)] ?? 0;
+    return { cmd, count: acc.count, mean_ms: round(acc.total / acc.count), p95_ms: round(p95), max_ms: round(acc.max) };
+  }).sort((a, b) => b.mean_ms - a.mean_ms);
+}
+
+export async function getLogPath(): Promise<string | null> {
+  try {
+    const res = await fetch(LOG_ENDPOINT, { cache: "no-store" });
+    if (!res.ok) return null;
+    const j = await res.json();
+    return typeof j?.path === "string" ? j.path : null;
+  } catch {
+    return null;
+  }
+}
+
+/**
+ * Wraps an api command so every call logs entry, exit (with duration) and errors. In read-only
+ * mode mutations are refused here, before they run, so no command can slip past the check.
+ */
+export function command<A extends unknown[], R>(name: string, fn: (...args: A) => Promise<R>) {
+  return async (...args: A): Promise<R> => {
+    if (readOnly && auditAction(name) === "mutation" && !READ_ONLY_EXEMPT.includes(name)) {
+      log("error", name, "read-only mode");
+      throw new Error(t("read_only_mode"));
+    }
+    const ids = safeIds(args);
+    let patientId: string | null = null;
+    try {
+      patientId = auditPatientOf(name, args);
+    } catch {
+      // an unresolved patient only leaves the audit entry without one
+    }
+    const started = performance.now();
+    log("info", name, "start", { ids });
+    try {
+      const out = await fn(...args);
+      const ms = performance.now() - started;
+      recordTiming(name, ms);
+      log("info", name, "ok", { ids, ms: Math.round(ms) });
+      audit(name, ids, patientId);
+      return out;
+    } catch (e) {
+      const ms = performance.now() - started;
+      recordTiming(name, ms);
+      log("error", name, errText(e), { ids, ms: Math.round(ms) });
+      throw e;
+    }
+  };
+}
@@ -0,0 +1,146 @@
+export type Lang = "es" | "en";
+
+export const LANGS: Lang[] = ["es", "en"];
+
+// User-facing error messages emitted by lib/api.ts. `{name}` placeholders are filled by t().
+const MESSAGES = {
+  file_read_failed: { es: "No se pudo leer el archivo", en: "Could not read the file" },
+  image_read_failed: { es: "No se pudo leer la imagen", en: "Could not read the image" },
+  thumbnail_failed: { es: "No se pudo generar la miniatura", en: "Could not generate the thumbnail" },
+  photo_not_image: { es: "\"{name}\" no es una imagen válida (PNG, JPG, GIF, WEBP o BMP)", en: "\"{name}\" is not a valid image (PNG, JPG, GIF, WEBP or BMP)" },
+  not_an_image: { es: "El archivo no es una imagen", en: "The file is not an image" },
+  name_required: { es: "El nombre es obligatorio", en: "Name is required" },
+  invalid_field_value: { es: "Valor inválido para {field}", en: "Invalid value for {field}" },
+  unknown_fields: { es: "Campos desconocidos: {fields}", en: "Unknown fields: {fields}" },
+  duplicate_doc: { es: "Ya existe un paciente con el documento {doc}", en: "A patient with document {doc} already exists" },
+  patient_not_found: { es: "Paciente no encontrado", en: "Patient not found" },
+  file_not_found: { es: "Archivo no encontrado", en: "File not found" },
+  file_not_in_trash: { es: "El archivo no está en la papelera", en: "The file is not in the trash" },
+  unknown_exam_type: { es: "Tipo de examen desconocido: {type}", en: "Unknown exam type: {type}" },
+  exam_not_found: { es: "Examen no encontrado", en: "Exam not found" },
+  exam_unreadable: { es: "No se pudo leer el contenido del examen", en: "Could not read the exam content" },
+  appointment_not_found: { es: "Cita no encontrada", en: "Appointment not found" },
+  report_not_found: { es: "Reporte no encontrado", en: "Report not found" },
+  report_title_required: { es: "Título requerido", en: "Title is required" },
+  report_description_required: { es: "Describe el error (qué pasó)", en: "Describe the error (what happened)" },
+  insurer_name_required: { es: "El nombre de la aseguradora es obligatorio", en: "Insurer name is required" },
+  backup_unavailable: { es: "El respaldo requiere el servidor local de NAJU", en: "Backups need the local NAJU server" },
+  backup_failed: { es: "No se pudo crear el respaldo: {reason}", en: "Backup failed: {reason}" },
+  not_on_disk: { es: "El archivo no está guardado en disco", en: "The file is not stored on disk" },
+  local_server_required: { es: "Esta acción requiere el servidor local de NAJU", en: "This action needs the local NAJU server" },
+  insufficient_space: { es: "Espacio insuficiente en disco (se necesitan ~{mb} MB)", en: "Not enough disk space (~{mb} MB needed)" },
+  invalid_bundle: { es: "El archivo no es un paquete de pacientes de NAJU", en: "The file is not a NAJU patients bundle" },
+  unknown_placeholders: { es: "Marcadores desconocidos en la plantilla: {names}", en: "Unknown template placeholders: {names}" },
+  invalid_contact_preference: { es: "Preferencia de contacto inválida: {value} (phone, email o none)", en: "Invalid contact preference: {value} (phone, email or none)" },
+  invalid_chunk_range: { es: "Rango de lectura inválido (máximo {max} bytes por bloque)", en: "Invalid read range (at most {max} bytes per chunk)" },
+  store_busy: { es: "Los datos están ocupados (¿respaldo en curso?). Intenta de nuevo en unos segundos", en: "The data file is busy (backup running?). Try again in a few seconds" },
+  invalid_base64: { es: "El contenido no es base64 válido", en: "The content is not valid base64" },
+  backup_in_progress: { es: "Hay un respaldo en curso; intenta cuando termine", en: "A backup is running; try again when it finishes" },
+  invalid_exam_json: { es: "El archivo no contiene un objeto JSON válido", en: "The file does not contain a valid JSON object" },
+  exam_missing_fields: { es: "Faltan campos obligatorios tras el mapeo: {fields}", en: "Required fields missing after mapping: {fields}" },
+  command_not_allowed: { es: "Comando no permitido en un lote: {cmd}", en: "Command not allowed in a batch: {cmd}" },
+  custom_field_in_use: { es: "El campo {key} ya tiene valores; no se puede cambiar su tipo", en: "Field {key} already has values; its type can't change" },
+  too_many_files: { es: "El paciente tiene {count} archivos (aviso a partir de {max}). ¿Se importó al paciente correcto?", en: "The patient has {count} files (warning above {max}). Was this imported into the right patient?" },
+  unknown_setting: { es: "Ajuste desconocido: {key}", en: "Unknown setting: {key}" },
+  setting_read_only: { es: "El ajuste {key} es de solo lectura", en: "Setting {key} is read-only" },
+  emergency_contact_required: { es: "El contacto de emergencia es obligatorio para menores de edad", en: "An emergency contact is required for minors" },
+  already_tracked: { es: "El archivo ya está registrado", en: "The file is already tracked" },
+  encryption_locked: { es: "Desbloquea el cifrado con la frase de acceso primero", en: "Unlock encryption with the passphrase first" },
+  wrong_passphrase: { es: "Frase de acceso incorrecta", en: "Wrong passphrase" },
+  not_encrypted: { es: "El archivo no está cifrado", en: "The file is not encrypted" },
+  decrypt_failed: { es: "No se pudo descifrar el archivo (¿dañado?)", en: "Could not decrypt the file (damaged?)" },
+  label_failed: { es: "No se pudo generar la etiqueta", en: "Could not render the label" },
+  invalid_patient_code: { es: "El código leído no es un código de paciente", en: "The scanned code is not a patient code" },
+  invalid_date_range: { es: "Rango de fechas inválido", en: "Invalid date range" },
+  invalid_status: { es: "Estado no válido: {status}", en: "Invalid status: {status}" },
+  nothing_to_undo: { es: "No hay cambios para deshacer", en: "Nothing to undo" },
+  storage_failed: { es: "La carpeta de datos no es utilizable ({path}): {reason}", en: "The data folder is not usable ({path}): {reason}" },
+  storage_not_writable: { es: "El navegador no permite guardar datos (¿almacenamiento lleno o modo privado?)", en: "The browser won't store data (storage full or private mode?)" },
+  storage_low: { es: "Queda poco espacio en disco ({mb} MB libres)", en: "Disk space is running low ({mb} MB free)" },
+  unknown_bulk_action: { es: "Acción masiva desconocida: {action}", en: "Unknown bulk action: {action}" },
+  unknown_table: { es: "Tabla no exportable: {table}", en: "Table can't be exported: {table}" },
+  files_not_of_patient: { es: "Archivos que no son de este paciente: {ids}", en: "Files that don't belong to this patient: {ids}" },
+  signature_not_png: { es: "La firma debe ser una imagen PNG (máximo 512 KB)", en: "The signature must be a PNG image (512 KB max)" },
+  self_relation: { es: "Un paciente no puede relacionarse consigo mismo", en: "A patient can't be related to themselves" },
+  relation_exists: { es: "Esa relación ya existe", en: "That relation already exists" },
+  csv_unreadable: { es: "No se pudo leer el CSV: no tiene encabezados", en: "Could not read the CSV: it has no header row" },
+  invalid_timezone: { es: "Zona horaria desconocida: {tz} (ej. America/Bogota)", en: "Unknown time zone: {tz} (e.g. America/Bogota)" },
+  encoding_not_fixable: { es: "El campo {field} no tiene un error de codificación reparable", en: "Field {field} has no repairable encoding error" },
+  read_only_mode: { es: "Modo solo lectura: no se pueden hacer cambios", en: "Read-only mode: changes are not allowed" },
+  invalid_url: { es: "Enlace no válido (solo http o https)", en: "Invalid link (http or https only)" },
+  download_failed: { es: "No se pudo descargar el archivo: {reason}", en: "Download failed: {reason}" },
+  download_incomplete: { es: "La descarga se interrumpió o supera el tamaño máximo", en: "The download was cut off or exceeds the size limit" },
+  appointment_overlap: { es: "La cita se cruza con: {list}", en: "The appointment overlaps with: {list}" },
+  no_photos: { es: "El paciente no tiene fotos", en: "The patient has no photos" },
+  link_not_found: { es: "Enlace no encontrado", en: "Link not found" },
+  provider_name_required: { es: "El nombre del profesional es obligatorio", en: "Provider name is required" },
+  provider_not_found: { es: "Profesional no encontrado o inactivo", en: "Provider not found or inactive" },
+  store_damaged: { es: "Los datos guardados están dañados y no se pueden abrir: {reason}", en: "The saved data is damaged and can't be opened: {reason}" },
+  recovery_failed: { es: "No se pudo recuperar los datos: {reason}", en: "Data recovery failed: {reason}" },
+  no_local_copy: { es: "este navegador no tiene una copia de los datos", en: "this browser has no copy of the data" },
+  extension_not_allowed: { es: "Tipo de archivo no permitido ({ext}); se aceptan: {allowed}", en: "File type not allowed ({ext}); accepted: {allowed}" },
+  invalid_settings_file: { es: "El archivo no es una exportación de ajustes de NAJU", en: "The file is not a NAJU settings export" },
+  too_many_pins: { es: "Solo se pueden fijar {max} archivos por paciente; desfija otro primero", en: "Only {max} files can be pinned per patient; unpin another first" },
+  appointment_not_of_patient: { es: "La cita es de otro paciente", en: "The appointment belongs to another patient" },
+  rename_reason_required: { es: "Indica el motivo del cambio de nombre", en: "Give a reason for the name change" },
+  invalid_color: { es: "Color no válido: {color} (usa #rgb o #rrggbb)", en: "Invalid color: {color} (use #rgb or #rrggbb)" },
+  method_not_allowed: { es: "Método no permitido", en: "Method not allowed" },
+  local_only: { es: "Solo permitido desde este PC", en: "Only allowed from this computer" },
+  path_outside_data: { es: "Ruta fuera de la carpeta de datos", en: "Path outside the data folder" },
+  destination_required: { es: "Destino requerido", en: "Destination required" },
+  too_many_same_name: { es: "Demasiados archivos con el mismo nombre", en: "Too many files with the same name" },
+  invalid_path: { es: "Ruta inválida", en: "Invalid path" },
+  file_name_taken: { es: "Ya existe un archivo con ese nombre", en: "A file with that name already exists" },
+  invalid_action: { es: "Acción inválida", en: "Invalid action" },
+  store_not_accessible: { es: "store.json no es accesible", en: "store.json is not accessible" },
+  path_not_absolute: { es: "La ruta debe ser absoluta", en: "The path must be absolute" },
+  path_unavailable: { es: "La ruta no existe o no está disponible", en: "The path does not exist or is not available" },
+  download_upstream_status: { es: "El servidor del enlace respondió {detail}", en: "The link's server answered {detail}" },
+  download_timeout: { es: "Tiempo de descarga agotado", en: "The download timed out" },
+  no_valid_backup: { es: "No hay respaldos válidos", en: "No valid backups found" },
+  payload_too_large: { es: "La solicitud es demasiado grande", en: "The request is too large" },
+  invalid_json: { es: "JSON inválido", en: "Invalid JSON" },
+  incomplete_payload: { es: "Faltan datos en la solicitud", en: "The request is missing data" },
+  invalid_file_name: { es: "Nombre de archivo inválido", en: "Invalid file name" },
+  server_error: { es: "Error del servidor local: {detail}", en: "Local server error: {detail}" },
+  unsupported_language: { es: "Idioma no soportado: {lang}", en: "Unsupported language: {lang}" },
+} as const;
+
+export type MessageId = keyof typeof MESSAGES;
+
+// The store's settings.language is the source of truth; this copy only covers startup, before the store loads.
+const LANG_KEY = "naju_lang";
+
+let current: Lang = readStoredLang();
+
+function readStoredLang(): Lang {
+  try {
+    const raw = localStorage.getItem(LANG_KEY);
+    return raw === "en" ? "en" : "es";
+  } catch {
+    return "es";
+  }
+}
+
+export function getLang(): Lang {
+  return current;
+}
+
+export function setLang(lang: Lang) {
+  current = lang;
+  try {
+    localStorage.setItem(LANG_KEY, lang);
+  } catch {
+    // ignore
+  }
+}
+
+export function isMessageId(id: string): id is MessageId {
+  return Object.prototype.hasOwnProperty.call(MESSAGES, id);
+}
+
+export function t(id: MessageId, params?: Record<string, string | number>) {
+  const text: string = MESSAGES[id][current];
+  if (!params) return text;
+  return text.replace(/\{(\w+)\}/g, (m, key) => (key in params ? String(params[key]) : m));
+}
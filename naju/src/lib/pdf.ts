@@ -0,0 +1,58 @@
+// Minimal single-page PDF writer: Helvetica text and filled rectangles, enough for printable
+// reports without a PDF dependency. Coordinates are points from the bottom-left of an A4 page.
+
+export const PDF_PAGE_WIDTH = 595;
+export const PDF_PAGE_HEIGHT = 842;
+
+// WinAnsiEncoding matches Latin-1 for the accented letters Spanish needs; anything else prints as "?".
+function winAnsi(input: string) {
+  let out = "";
+  for (const ch of input) {
+    const code = ch.charCodeAt(0);
+    out += code < 0x20 ? " " : code <= 0xff && !(code >= 0x80 && code < 0xa0) ? ch : "?";
+  }
+  return out.replace(/[\\()]/g, (c) => `\\${c}`);
+}
+
+function num(n: number) {
+  return String(Math.round(n * 100) / 100);
+}
+
+export class PdfPage {
+  private ops: string[] = [];
+
+  text(x: number, y: number, size: number, value: string, bold = false) {
+    this.ops.push(`BT /${bold ? "F2" : "F1"} ${num(size)} Tf ${num(x)} ${num(y)} Td (${winAnsi(value)}) Tj ET`);
+  }
+
+  /** `gray` is 0 (black) to 1 (white). */
+  rect(x: number, y: number, width: number, height: number, gray = 0) {
+    this.ops.push(`${num(gray)} g ${num(x)} ${num(y)} ${num(width)} ${num(height)} re f 0 g`);
+  }
+
+  /** The finished file. Every character is one byte, so string offsets are byte offsets. */
+  toBytes(): Uint8Array {
+    const content = this.ops.join("\n");
+    const objects = [
+      "<< /Type /Catalog /Pages 2 0 R >>",
+      "<< /Type /Pages /Kids [3 0 R] /Count 1 >>",
+      `<< /Type /Page /Parent 2 0 R /MediaBox [0 0 ${PDF_PAGE_WIDTH} ${PDF_PAGE_HEIGHT}] /Contents 4 0 R /Resources << /Font << /F1 5 0 R /F2 6 0 R >> >> >>`,
+      `<< /Length ${content.length} >>\nstream\n${content}\nendstream`,
+      "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>",
+      "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>",
+    ];
+    let out = "%PDF-1.4\n";
+    const offsets: number[] = [];
+    objects.forEach((body, i) => {
+      offsets.push(out.length);
+      out += `${i + 1} 0 obj\n${body}\nendobj\n`;
+    });
+    const xref = out.length;
+    out += `xref\n0 ${objects.length + 1}\n0000000000 65535 f \n`;
+    for (const o of offsets) out += `${String(o).padStart(10, "0")} 00000 n \n`;
+    out += `trailer\n<< /Size ${objects.length + 1} /Root 1 0 R >>\nstartxref\n${xref}\n%%EOF\n`;
+    const bytes = new Uint8Array(out.length);
+    for (let i = 0; i < out.length; i++) bytes[i] = out.charCodeAt(i);
+    return bytes;
+  }
+}
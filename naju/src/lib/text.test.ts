@@ -0,0 +1,18 @@
+import { describe, expect, it } from "vitest";
+import { normText, stripAccents } from "./text";
+
+describe("normText", () => {
+  it("folds case, accents and spacing", () => {
+    expect(normText("  José   PÉREZ ")).toBe("jose perez");
+    expect(normText("Núñez")).toBe(normText("nunez"));
+  });
+
+  it("treats composed and decomposed accents alike", () => {
+    expect(normText("Jos\u00e9")).toBe(normText("Jose\u0301"));
+  });
+
+  it("keeps characters that are not accented letters", () => {
+    expect(stripAccents("ñ-ç 42")).toBe("n-c 42");
+    expect(normText(null)).toBe("");
+  });
+});
@@ -0,0 +1,60 @@
+/** Removes diacritics (NFD + drop combining marks) without touching case: "Núñez" -> "Nunez". */
+export function stripAccents(input: string | null | undefined) {
+  return (input ?? "").normalize("NFD").replace(/[\u0300-\u036f]/g, "");
+}
+
+/**
+ * Search normalization: lowercase, accents stripped (NFD + drop combining marks), whitespace collapsed.
+ * "José  Pérez" and "jose perez" normalize to the same string.
+ */
+export function normText(input: string | null | undefined) {
+  return stripAccents(input)
+    .toLowerCase()
+    .replace(/\s+/g, " ")
+    .trim();
+}
+
+/** Edit distance (insert/delete/substitute). Gives up early and returns `max + 1` once it can't stay within `max`. */
+export function levenshtein(a: string, b: string, max = Infinity) {
+  if (Math.abs(a.length - b.length) > max) return max + 1;
+  let prev = Array.from({ length: b.length + 1 }, (_, j) => j);
+  for (let i = 1; i <= a.length; i++) {
+    const row = [i];
+    let best = i;
+    for (let j = 1; j <= b.length; j++) {
+      row[j] = Math.min(prev[j] + 1, row[j - 1] + 1, prev[j - 1] + (a[i - 1] === b[j - 1] ? 0 : 1));
+      if (row[j] < best) best = row[j];
+    }
+    if (best > max) return max + 1;
+    prev = row;
+  }
+  return prev[b.length];
+}
+
+// Windows-1252 characters for bytes 0x80–0x9F (where it differs from Latin-1); undefined bytes are absent.
+const CP1252_HIGH = "€\u0081‚ƒ„…†‡ˆ‰Š‹Œ\u008dŽ\u008f\u0090‘’“”•–—˜™š›œ\u009džŸ";
+
+/** UTF-8 read as Latin-1/Windows-1252: "JosÃ©", "PeÃ±a", "Ã‘". */
+export function looksLikeMojibake(input: string) {
+  return /[ÃÂ][\u0080-¿€‚ƒ„…†‡ˆ‰Š‹Œ‘’“”•–—˜™š›œžŸ]/.test(input);
+}
+
+/**
+ * Undoes one round of double encoding: the text is turned back into the Windows-1252 bytes it was
+ * mis-decoded from and decoded again as UTF-8. Returns null when that doesn't yield valid UTF-8.
+ */
+export function repairMojibake(input: string): string | null {
+  const bytes = new Uint8Array(input.length);
+  for (let i = 0; i < input.length; i++) {
+    const code = input.charCodeAt(i);
+    const high = CP1252_HIGH.indexOf(input[i]);
+    if (high !== -1) bytes[i] = 0x80 + high;
+    else if (code <= 0xff) bytes[i] = code;
+    else return null;
+  }
+  try {
+    return new TextDecoder("utf-8", { fatal: true }).decode(bytes);
+  } catch {
+    return null;
+  }
+}
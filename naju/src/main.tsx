@@ -1,9 +1,13 @@
 import React from "react";
 import ReactDOM from "react-dom/client";
 import App from "./App";
+import { autoBackupIfDue, flushOnExit } from "./lib/api";
 
 ReactDOM.createRoot(document.getElementById("root") as HTMLElement).render(
   <React.StrictMode>
     <App />
   </React.StrictMode>,
 );
+
+void autoBackupIfDue();
+window.addEventListener("pagehide", flushOnExit);
@@ -1,6 +1,7 @@
 import { defineConfig, type Plugin } from "vite";
 import react from "@vitejs/plugin-react";
 import fs from "node:fs/promises";
+import { constants as fsConstants } from "node:fs";
 import path from "node:path";
 import os from "node:os";
 import { execFile } from "node:child_process";
@@ -28,6 +29,10 @@ function najuStorePlugin(): Plugin {
   const storeDir = path.resolve(__dirname, "patients");
   const storeFile = path.join(storeDir, "store.json");
   const assetsDir = path.join(storeDir, "assets");
+  const logsDir = path.join(storeDir, "logs");
+  const auditDir = path.join(storeDir, "audit");
+  const defaultBackupDir = path.join(storeDir, "backups");
+  const LOG_KEEP_DAYS = 14;
   const defaultStore = { patients: [], files: [], appointments: [], nextFileId: 1, nextAppointmentId: 1 };
 
   // Repo root is the parent of /naju (where the .git folder lives).
@@ -42,6 +47,74 @@ function najuStorePlugin(): Plugin {
     await fs.mkdir(assetsDir, { recursive: true });
   }
 
+  function readJsonBody(req: any, limitBytes = 1024 * 1024): Promise<any> {
+    return new Promise((resolve, reject) => {
+      let body = "";
+      let size = 0;
+      req.on("data", (chunk: any) => {
+        size += chunk.length;
+        if (size > limitBytes) {
+          reject(new Error("payload_too_large"));
+          req.destroy();
+          return;
+        }
+        body += chunk.toString("utf8");
+      });
+      req.on("end", () => {
+        try {
+          resolve(JSON.parse(body || "{}"));
+        } catch {
+          reject(new Error("invalid_json"));
+        }
+      });
+      req.on("error", reject);
+    });
+  }
+
+  // Every endpoint answers a failure with `error` set to a message id from src/lib/messages.ts, which
+  // the client translates with serverError(); `detail` carries the raw cause ("server_error" for the unexpected ones).
+  function sendJson(res: any, status: number, payload: any) {
+    res.statusCode = status;
+    res.setHeader("Content-Type", "application/json; charset=utf-8");
+    res.setHeader("Cache-Control", "no-store");
+    res.end(JSON.stringify(payload));
+  }
+
+  async function readStoreJson(): Promise<any> {
+    try {
+      return JSON.parse(await fs.readFile(storeFile, "utf8"));
+    } catch {
+      return defaultStore;
+    }
+  }
+
+  // Newest naju-backup-* folder under `dir` whose store.json still parses, or null.
+  async function latestValidBackup(dir: string): Promise<string | null> {
+    const names = await fs.readdir(dir).catch(() => [] as string[]);
+    for (const n of names.filter((n) => n.startsWith("naju-backup-")).sort().reverse()) {
+      try {
+        JSON.parse(await fs.readFile(path.join(dir, n, "store.json"), "utf8"));
+        return path.join(dir, n);
+      } catch {
+        // incomplete or damaged backup: try the previous one
+      }
+    }
+    return null;
+  }
+
+  // Newest valid backup across the configured auto-backup folder (if any) and the default one.
+  async function newestValidBackup(configuredDir: string): Promise<string | null> {
+    const dirs = [defaultBackupDir, ...(configuredDir.trim() ? [path.resolve(configuredDir.trim())] : [])];
+    const found = (await Promise.all(dirs.map(latestValidBackup))).filter((d): d is string => d !== null);
+    return found.sort((a, b) => path.basename(b).localeCompare(path.basename(a)))[0] ?? null;
+  }
+
+  async function patientExists(patientId: string) {
+    const store = await readStoreJson();
+    const patients = Array.isArray(store?.patients) ? store.patients : [];
+    return patients.some((p: any) => safeId(String(p?.id || "")) === patientId);
+  }
+
   function safeRelPath(input: string) {
     const rel = input.replace(/^\/+/, "");
     const norm = path.normalize(rel).replace(/^([.]{2}(\/|\\|$))+/, "");
@@ -150,9 +223,7 @@ function najuStorePlugin(): Plugin {
       server.middlewares.use("/__naju_update_check", async (req, res) => {
         // GET only
         if ((req?.method || "GET").toUpperCase() !== "GET") {
-          res.statusCode = 405;
-          res.setHeader("Content-Type", "application/json; charset=utf-8");
-          res.end(JSON.stringify({ ok: false, error: "Método no permitido" }));
+          sendJson(res, 405, { ok: false, error: "method_not_allowed" });
           return;
         }
 
@@ -187,26 +258,19 @@ function najuStorePlugin(): Plugin {
             })
           );
         } catch (e: any) {
-          res.statusCode = 200;
-          res.setHeader("Content-Type", "application/json; charset=utf-8");
-          res.setHeader("Cache-Control", "no-store");
-          res.end(JSON.stringify({ ok: false, error: String(e?.message || e || "Error") }));
+          sendJson(res, 200, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
         }
       });
 
       server.middlewares.use("/__naju_update_apply", async (req, res) => {
         // POST only
         if ((req?.method || "GET").toUpperCase() !== "POST") {
-          res.statusCode = 405;
-          res.setHeader("Content-Type", "application/json; charset=utf-8");
-          res.end(JSON.stringify({ ok: false, error: "Método no permitido" }));
+          sendJson(res, 405, { ok: false, error: "method_not_allowed" });
           return;
         }
 
         if (!isLocalRequest(req)) {
-          res.statusCode = 403;
-          res.setHeader("Content-Type", "application/json; charset=utf-8");
-          res.end(JSON.stringify({ ok: false, error: "Solo permitido desde este PC." }));
+          sendJson(res, 403, { ok: false, error: "local_only" });
           return;
         }
 
@@ -238,10 +302,670 @@ function najuStorePlugin(): Plugin {
             })
           );
         } catch (e: any) {
+          sendJson(res, 200, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+        }
+      });
+
+      // Command log: one file per day in /patients/logs, oldest pruned beyond LOG_KEEP_DAYS.
+      function logFileFor(day: string) {
+        return path.join(logsDir, `naju-${day}.log`);
+      }
+
+      async function pruneLogs() {
+        const names = (await fs.readdir(logsDir)).filter((n) => /^naju-\d{4}-\d{2}-\d{2}\.log$/.test(n)).sort();
+        for (const n of names.slice(0, Math.max(0, names.length - LOG_KEEP_DAYS))) {
+          await fs.rm(path.join(logsDir, n), { force: true });
+        }
+      }
+
+      // Audit trail: one JSONL file per month in /patients/audit, never pruned (data-protection audits).
+      // The actor is the OS account running NAJU; the client can't choose it.
+      function auditFileFor(month: string) {
+        return path.join(auditDir, `audit-${month}.jsonl`);
+      }
+
+      function osUser() {
+        try {
+          return os.userInfo().username;
+        } catch {
+          return "desconocido";
+        }
+      }
+
+      server.middlewares.use("/__naju_audit", async (req, res) => {
+        try {
+          await fs.mkdir(auditDir, { recursive: true });
+
+          if (req.method === "GET") {
+            const url = new URL(req.url || "/", "http://localhost");
+            const from = url.searchParams.get("from") || "0000-00-00";
+            const to = url.searchParams.get("to") || "9999-99-99";
+            const names = (await fs.readdir(auditDir))
+              .filter((n) => /^audit-\d{4}-\d{2}\.jsonl$/.test(n))
+              .filter((n) => n.slice(6, 13) >= from.slice(0, 7) && n.slice(6, 13) <= to.slice(0, 7))
+              .sort();
+            const entries: any[] = [];
+            for (const n of names) {
+              const text = await fs.readFile(path.join(auditDir, n), "utf8");
+              for (const line of text.split("\n")) {
+                if (!line.trim()) continue;
+                try {
+                  const e = JSON.parse(line);
+                  const day = String(e?.ts || "").slice(0, 10);
+                  if (day >= from && day <= to) entries.push(e);
+                } catch {
+                  // skip a torn line
+                }
+              }
+            }
+            sendJson(res, 200, { ok: true, entries });
+            return;
+          }
+
+          if (req.method === "POST") {
+            const parsed = await readJsonBody(req);
+            const actor = osUser();
+            const byMonth = new Map<string, string[]>();
+            for (const e of Array.isArray(parsed?.entries) ? parsed.entries : []) {
+              const ts = String(e?.ts || new Date().toISOString());
+              const month = ts.slice(0, 7);
+              if (!/^\d{4}-\d{2}$/.test(month)) continue;
+              byMonth.set(month, [...(byMonth.get(month) || []), JSON.stringify({ ...e, ts, actor })]);
+            }
+            for (const [month, lines] of byMonth) await fs.appendFile(auditFileFor(month), lines.join("\n") + "\n", "utf8");
+            sendJson(res, 200, { ok: true });
+            return;
+          }
+
+          sendJson(res, 405, { ok: false, error: "method_not_allowed" });
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+        }
+      });
+
+      server.middlewares.use("/__naju_log", async (req, res) => {
+        try {
+          await fs.mkdir(logsDir, { recursive: true });
+          const day = new Date().toISOString().slice(0, 10);
+
+          if (req.method === "GET") {
+            sendJson(res, 200, { ok: true, path: logFileFor(day), dir: logsDir });
+            return;
+          }
+
+          if (req.method === "POST") {
+            let parsed: any;
+            try {
+              parsed = await readJsonBody(req);
+            } catch (e: any) {
+              // readJsonBody rejects with a message id (payload_too_large, invalid_json).
+              sendJson(res, 400, { ok: false, error: String(e?.message || "invalid_json") });
+              return;
+            }
+            const entries = Array.isArray(parsed?.entries) ? parsed.entries : [];
+            const lines = entries.map((e: any) => JSON.stringify(e)).join("\n");
+            if (lines) {
+              await fs.appendFile(logFileFor(day), lines + "\n", "utf8");
+              await pruneLogs();
+            }
+            sendJson(res, 200, { ok: true });
+            return;
+          }
+
+          sendJson(res, 405, { ok: false, error: "method_not_allowed" });
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+        }
+      });
+
+      // Snapshot store.json + assets into <dir>/naju-backup-<stamp>/, keeping only the newest `keep`.
+      server.middlewares.use("/__naju_backup", async (req, res) => {
+        if ((req?.method || "GET").toUpperCase() !== "POST") {
+          sendJson(res, 405, { ok: false, error: "method_not_allowed" });
+          return;
+        }
+        if (!isLocalRequest(req)) {
+          sendJson(res, 403, { ok: false, error: "local_only" });
+          return;
+        }
+
+        try {
+          const parsed = await readJsonBody(req);
+          const dir = String(parsed?.dir || "").trim();
+          const backupRoot = dir ? path.resolve(dir) : defaultBackupDir;
+          const keep = Math.max(1, Math.floor(Number(parsed?.keep) || 7));
+
+          const stamp = new Date().toISOString().replace(/[-:]/g, "").replace(/\..+$/, "").replace("T", "-");
+          const target = path.join(backupRoot, `naju-backup-${stamp}`);
+          await fs.mkdir(target, { recursive: true });
+          await fs.copyFile(storeFile, path.join(target, "store.json")).catch(() => undefined);
+          await fs.cp(assetsDir, path.join(target, "assets"), { recursive: true }).catch(() => undefined);
+
+          const existing = (await fs.readdir(backupRoot)).filter((n) => n.startsWith("naju-backup-")).sort();
+          const pruned = existing.slice(0, Math.max(0, existing.length - keep));
+          for (const n of pruned) {
+            await fs.rm(path.join(backupRoot, n), { recursive: true, force: true });
+          }
+
+          sendJson(res, 200, { ok: true, path: target, pruned: pruned.length });
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+        }
+      });
+
+      // Copies a stored file out (USB drive, shared folder). The source must be inside assets/; inline
+      // files come as base64. A directory `dest` gets the display filename, suffixed on collision.
+      server.middlewares.use("/__naju_export_file", async (req, res) => {
+        if ((req?.method || "GET").toUpperCase() !== "POST") {
+          sendJson(res, 405, { ok: false, error: "method_not_allowed" });
+          return;
+        }
+        if (!isLocalRequest(req)) {
+          sendJson(res, 403, { ok: false, error: "local_only" });
+          return;
+        }
+
+        try {
+          const parsed = await readJsonBody(req, 64 * 1024 * 1024);
+          const raw = String(parsed?.path || "");
+          let source: string | null = null;
+          if (raw.startsWith("/__naju_asset/")) {
+            const rel = safeRelPath(decodeURIComponent(raw.slice("/__naju_asset/".length)));
+            source = rel ? path.resolve(assetsDir, rel) : null;
+            if (!source || !source.startsWith(path.resolve(assetsDir) + path.sep)) {
+              sendJson(res, 400, { ok: false, error: "path_outside_data" });
+              return;
+            }
+          }
+          const data = source ? await fs.readFile(source) : Buffer.from(String(parsed?.data || ""), "base64");
+
+          const dest = String(parsed?.dest || "").trim();
+          if (!dest) {
+            sendJson(res, 400, { ok: false, error: "destination_required" });
+            return;
+          }
+          const resolved = path.resolve(dest);
+          const isDir = await fs.stat(resolved).then((st) => st.isDirectory(), () => false);
+          const display = path.basename(String(parsed?.filename || "").replace(/[/\\]+/g, "_")) || path.basename(source || "archivo");
+          const wanted = isDir ? path.join(resolved, display) : resolved;
+          const ext = path.extname(wanted);
+          const base = wanted.slice(0, wanted.length - ext.length);
+          for (let n = 1; n < 1000; n++) {
+            const target = n === 1 ? wanted : `${base}_${n}${ext}`;
+            try {
+              await fs.writeFile(target, data, { flag: "wx" });
+              sendJson(res, 200, { ok: true, path: target });
+              return;
+            } catch (e: any) {
+              if (e?.code !== "EEXIST") throw e;
+            }
+          }
+          sendJson(res, 409, { ok: false, error: "too_many_same_name" });
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+        }
+      });
+
+      // Downloads a referral link on the app's behalf (no CORS from the server side) and streams it
+      // back. http(s) only, capped in size and time; the suggested filename goes in X-Naju-Filename.
+      server.middlewares.use("/__naju_fetch_url", async (req, res) => {
+        if (!isLocalRequest(req)) {
+          sendJson(res, 403, { ok: false, error: "local_only" });
+          return;
+        }
+        const params = new URL(req.url || "/", "http://localhost").searchParams;
+        const maxBytes = Math.min(200 * 1024 * 1024, Math.max(1, Number(params.get("max")) || 50 * 1024 * 1024));
+        let target: URL;
+        try {
+          target = new URL(String(params.get("url") || ""));
+        } catch {
+          sendJson(res, 400, { ok: false, error: "invalid_url" });
+          return;
+        }
+        if (target.protocol !== "http:" && target.protocol !== "https:") {
+          sendJson(res, 400, { ok: false, error: "invalid_url" });
+          return;
+        }
+
+        const controller = new AbortController();
+        const timer = setTimeout(() => controller.abort(), 60_000);
+        try {
+          const upstream = await fetch(target, { signal: controller.signal, redirect: "follow" });
+          if (!upstream.ok || !upstream.body) {
+            sendJson(res, 502, { ok: false, error: "download_upstream_status", detail: String(upstream.status) });
+            return;
+          }
+          const declared = Number(upstream.headers.get("content-length"));
+          if (declared > maxBytes) {
+            sendJson(res, 413, { ok: false, error: "download_incomplete" });
+            return;
+          }
+          const disposition = upstream.headers.get("content-disposition") || "";
+          const star = /filename\*\s*=\s*(?:UTF-8'')?([^;]+)/i.exec(disposition);
+          const plain = /filename\s*=\s*"?([^";]+)"?/i.exec(disposition);
+          let filename = "";
+          try {
+            filename = star ? decodeURIComponent(star[1].trim()) : plain ? plain[1].trim() : decodeURIComponent(path.posix.basename(target.pathname));
+          } catch {
+            filename = path.posix.basename(target.pathname);
+          }
           res.statusCode = 200;
-          res.setHeader("Content-Type", "application/json; charset=utf-8");
-          res.setHeader("Cache-Control", "no-store");
-          res.end(JSON.stringify({ ok: false, error: String(e?.message || e || "Error") }));
+          res.setHeader("Content-Type", upstream.headers.get("content-type") || "application/octet-stream");
+          if (declared > 0) res.setHeader("Content-Length", String(declared));
+          res.setHeader("X-Naju-Filename", encodeURIComponent(path.basename(filename.replace(/[/\\]+/g, "_"))));
+          let received = 0;
+          const reader = upstream.body.getReader();
+          for (;;) {
+            const { done, value } = await reader.read();
+            if (done) break;
+            received += value.length;
+            if (received > maxBytes) {
+              controller.abort();
+              res.destroy(); // truncated body: the client sees a failed download, not a short file
+              return;
+            }
+            res.write(value);
+          }
+          res.end();
+        } catch (e: any) {
+          if (!res.headersSent) sendJson(res, 504, controller.signal.aborted ? { ok: false, error: "download_timeout" } : { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+          else res.destroy();
+        } finally {
+          clearTimeout(timer);
+        }
+      });
+
+      // Select a stored file in the OS file manager. Only paths inside /patients are accepted.
+      server.middlewares.use("/__naju_reveal", async (req, res) => {
+        if ((req?.method || "GET").toUpperCase() !== "POST") {
+          sendJson(res, 405, { ok: false, error: "method_not_allowed" });
+          return;
+        }
+        if (!isLocalRequest(req)) {
+          sendJson(res, 403, { ok: false, error: "local_only" });
+          return;
+        }
+
+        try {
+          const parsed = await readJsonBody(req);
+          const raw = String(parsed?.path || "");
+          const rel = raw.startsWith("/__naju_asset/")
+            ? safeRelPath(path.join("assets", decodeURIComponent(raw.slice("/__naju_asset/".length))))
+            : safeRelPath(raw);
+          const abs = rel ? path.resolve(storeDir, rel) : "";
+          if (!abs || !abs.startsWith(path.resolve(storeDir) + path.sep)) {
+            sendJson(res, 400, { ok: false, error: "path_outside_data" });
+            return;
+          }
+          try {
+            await fs.access(abs);
+          } catch {
+            sendJson(res, 404, { ok: false, error: "file_not_found" });
+            return;
+          }
+
+          if (process.platform === "win32") {
+            // explorer.exe exits with 1 even on success, so its code is ignored.
+            await execCmd("explorer.exe", [`/select,${abs}`]);
+          } else if (process.platform === "darwin") {
+            const r = await execCmd("open", ["-R", abs]);
+            if (!r.ok) throw new Error(r.stderr.trim() || "open -R failed");
+          } else if (process.platform === "linux") {
+            // No portable "select file" on Linux: open the containing folder instead.
+            const r = await execCmd("xdg-open", [path.dirname(abs)]);
+            if (!r.ok) throw new Error(r.stderr.trim() || "xdg-open failed");
+          } else {
+            sendJson(res, 400, { ok: false, error: "server_error", detail: `unsupported platform: ${process.platform}` });
+            return;
+          }
+          sendJson(res, 200, { ok: true, path: abs });
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+        }
+      });
+
+      // Opens an external folder or file a patient link points at (network share, old records).
+      // Unlike /__naju_reveal the target is outside /patients by design, so it's only checked to exist.
+      server.middlewares.use("/__naju_open_path", async (req, res) => {
+        if ((req?.method || "GET").toUpperCase() !== "POST") {
+          sendJson(res, 405, { ok: false, error: "method_not_allowed" });
+          return;
+        }
+        if (!isLocalRequest(req)) {
+          sendJson(res, 403, { ok: false, error: "local_only" });
+          return;
+        }
+
+        try {
+          const parsed = await readJsonBody(req);
+          const target = String(parsed?.path || "").trim();
+          if (!target || !path.isAbsolute(target)) {
+            sendJson(res, 400, { ok: false, error: "path_not_absolute" });
+            return;
+          }
+          try {
+            await fs.access(target);
+          } catch {
+            sendJson(res, 404, { ok: false, error: "path_unavailable" });
+            return;
+          }
+          const opener = process.platform === "win32" ? "explorer.exe" : process.platform === "darwin" ? "open" : "xdg-open";
+          const r = await execCmd(opener, [target]);
+          // explorer.exe exits with 1 even on success.
+          if (!r.ok && process.platform !== "win32") throw new Error(r.stderr.trim() || `${opener} failed`);
+          sendJson(res, 200, { ok: true, path: target });
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+        }
+      });
+
+      // Free space on the volume holding /patients.
+      server.middlewares.use("/__naju_disk", async (_req, res) => {
+        try {
+          await ensureDir();
+          const st = await fs.statfs(storeDir);
+          sendJson(res, 200, {
+            ok: true,
+            path: storeDir,
+            free_bytes: Number(st.bavail) * Number(st.bsize),
+            total_bytes: Number(st.blocks) * Number(st.bsize),
+          });
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+        }
+      });
+
+      // Startup health check: the data folder must take a write, and store.json (if any) must be readable and writable.
+      server.middlewares.use("/__naju_storage", async (_req, res) => {
+        const status = { ok: true, path: storeDir, writable: false, store_reachable: false, free_bytes: null as number | null, reason: null as string | null };
+        try {
+          await ensureDir();
+          const probe = path.join(storeDir, `.probe-${process.pid}-${Date.now()}`);
+          await fs.writeFile(probe, "ok", "utf8");
+          await fs.unlink(probe);
+          status.writable = true;
+          const st = await fs.statfs(storeDir);
+          status.free_bytes = Number(st.bavail) * Number(st.bsize);
+          status.store_reachable = await fs.access(storeFile, fsConstants.R_OK | fsConstants.W_OK).then(
+            () => true,
+            (e: any) => e?.code === "ENOENT", // first run: nothing written yet
+          );
+          if (!status.store_reachable) status.reason = "store_not_accessible";
+        } catch (e: any) {
+          status.reason = String(e?.code || e?.message || e || "Error");
+        }
+        sendJson(res, 200, status);
+      });
+
+      // Every file under assets/ (or one patient's folder), for the orphan scanner. Markers are skipped.
+      server.middlewares.use("/__naju_scan_assets", async (req, res) => {
+        try {
+          await ensureAssetsDir();
+          const url = new URL(req.url || "/", "http://localhost");
+          const only = url.searchParams.get("patientId");
+          const dirs = only ? [safeId(only)] : (await fs.readdir(assetsDir, { withFileTypes: true })).filter((d) => d.isDirectory()).map((d) => d.name);
+          const files: { patient_id: string; filename: string; path: string; size: number }[] = [];
+          for (const dir of dirs) {
+            const abs = path.resolve(assetsDir, dir);
+            if (!abs.startsWith(path.resolve(assetsDir) + path.sep)) continue;
+            for (const entry of await fs.readdir(abs, { withFileTypes: true }).catch(() => [])) {
+              if (!entry.isFile() || entry.name === "index.txt") continue;
+              const st = await fs.stat(path.join(abs, entry.name));
+              files.push({ patient_id: dir, filename: entry.name, path: `/__naju_asset/${dir}/${encodeURIComponent(entry.name)}`, size: st.size });
+            }
+          }
+          sendJson(res, 200, { ok: true, files });
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+        }
+      });
+
+      // Where a patient's data lives on disk, and whether each piece exists. Read-only.
+      server.middlewares.use("/__naju_paths", async (req, res) => {
+        try {
+          const url = new URL(req.url || "/", "http://localhost");
+          const patientId = safeId(url.searchParams.get("patientId") || "");
+          if (!(await patientExists(patientId))) {
+            sendJson(res, 404, { ok: false, error: "patient_not_found" });
+            return;
+          }
+          const root = path.resolve(assetsDir, patientId);
+          if (!root.startsWith(path.resolve(assetsDir) + path.sep)) {
+            sendJson(res, 400, { ok: false, error: "path_outside_data" });
+            return;
+          }
+          const exists = (p: string) => fs.lstat(p).then(() => true, () => false);
+
+          let byName: string | null = null;
+          const byNameDir = path.join(storeDir, "by-name");
+          for (const n of await fs.readdir(byNameDir).catch(() => [] as string[])) {
+            const target = await fs.readlink(path.join(byNameDir, n)).catch(() => "");
+            if (target && path.basename(target) === patientId) byName = path.join(byNameDir, n);
+          }
+
+          sendJson(res, 200, {
+            ok: true,
+            paths: [
+              { name: "data", path: storeDir, exists: await exists(storeDir) },
+              { name: "store", path: storeFile, exists: await exists(storeFile) },
+              { name: "root", path: root, exists: await exists(root) },
+              { name: "marker", path: path.join(root, "index.txt"), exists: await exists(path.join(root, "index.txt")) },
+              { name: "by_name", path: byName, exists: Boolean(byName) },
+            ],
+          });
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+        }
+      });
+
+      // Bytes used by the data folder: store.json alone, and the whole tree (assets, logs, audit...).
+      server.middlewares.use("/__naju_usage", async (_req, res) => {
+        try {
+          await ensureDir();
+          const walk = async (dir: string): Promise<number> => {
+            let total = 0;
+            for (const entry of await fs.readdir(dir, { withFileTypes: true }).catch(() => [])) {
+              const abs = path.join(dir, entry.name);
+              if (entry.isDirectory()) total += await walk(abs);
+              else if (entry.isFile()) total += (await fs.stat(abs)).size;
+            }
+            return total;
+          };
+          const storeBytes = await fs.stat(storeFile).then((st) => st.size, () => 0);
+          sendJson(res, 200, { ok: true, store_bytes: storeBytes, tree_bytes: await walk(storeDir) });
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+        }
+      });
+
+      // Human-readable markers for patient folders (named by id): assets/<id>/index.txt and,
+      // where the OS allows symlinks, by-name/<name>_<doc> -> assets/<id>.
+      server.middlewares.use("/__naju_marker", async (req, res) => {
+        if ((req?.method || "GET").toUpperCase() !== "POST") {
+          sendJson(res, 405, { ok: false, error: "method_not_allowed" });
+          return;
+        }
+
+        try {
+          const parsed = await readJsonBody(req);
+          const patientId = safeId(String(parsed?.patientId || ""));
+          if (!(await patientExists(patientId))) {
+            sendJson(res, 404, { ok: false, error: "patient_not_found" });
+            return;
+          }
+          const name = String(parsed?.name || "").trim();
+          const doc = String(parsed?.doc || "").trim();
+
+          const patientDir = path.join(assetsDir, patientId);
+          await fs.mkdir(patientDir, { recursive: true });
+          const text = [`Paciente: ${name}`, `Documento: ${doc || "—"}`, `Id: ${patientId}`, `Actualizado: ${new Date().toISOString()}`, ""].join("\n");
+          await fs.writeFile(path.join(patientDir, "index.txt"), text, "utf8");
+
+          let linked = false;
+          if (process.platform !== "win32") {
+            const byNameDir = path.join(storeDir, "by-name");
+            await fs.mkdir(byNameDir, { recursive: true });
+            // Drop links left by a previous name of this patient.
+            for (const n of await fs.readdir(byNameDir)) {
+              const abs = path.join(byNameDir, n);
+              const target = await fs.readlink(abs).catch(() => "");
+              if (target && path.basename(target) === patientId) await fs.rm(abs, { force: true });
+            }
+            const linkName = safeFileName([name, doc].filter(Boolean).join("_")) || patientId;
+            linked = await fs
+              .symlink(path.join("..", "assets", patientId), path.join(byNameDir, linkName), "dir")
+              .then(() => true)
+              .catch(() => false);
+          }
+
+          sendJson(res, 200, { ok: true, linked });
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+        }
+      });
+
+      // Recreate missing patient folders (assets/<id>/). Idempotent; reports how many were created.
+      server.middlewares.use("/__naju_repair_folders", async (req, res) => {
+        if ((req?.method || "GET").toUpperCase() !== "POST") {
+          sendJson(res, 405, { ok: false, error: "method_not_allowed" });
+          return;
+        }
+
+        try {
+          await ensureAssetsDir();
+          const store = await readStoreJson();
+          const ids: string[] = (Array.isArray(store?.patients) ? store.patients : []).map((p: any) => safeId(String(p?.id || "")));
+          let repaired = 0;
+          for (const id of ids) {
+            const dir = path.join(assetsDir, id);
+            const exists = await fs.stat(dir).then((st) => st.isDirectory()).catch(() => false);
+            if (exists) continue;
+            await fs.mkdir(dir, { recursive: true });
+            repaired++;
+          }
+          sendJson(res, 200, { ok: true, checked: ids.length, repaired });
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+        }
+      });
+
+      // Trash for on-disk assets: "move" puts assets/<id>/<name> under assets/<id>/.trash/, "restore"
+      // puts it back at its original name and "purge" deletes it for good. Only files inside a
+      // patient folder (and, for restore/purge, inside its .trash) are touched.
+      server.middlewares.use("/__naju_trash", async (req, res) => {
+        if ((req?.method || "GET").toUpperCase() !== "POST") {
+          sendJson(res, 405, { ok: false, error: "method_not_allowed" });
+          return;
+        }
+
+        try {
+          const parsed = await readJsonBody(req);
+          const action = String(parsed?.action || "");
+          const parse = (raw: unknown) => {
+            const m = /^\/__naju_asset\/([^/]+)\/(?:(\.trash)\/)?([^/]+)$/.exec(String(raw || ""));
+            if (!m) return null;
+            const patientId = safeId(decodeURIComponent(m[1]));
+            const name = decodeURIComponent(m[3]);
+            if (name !== path.basename(name) || name === "." || name === "..") return null;
+            return { patientId, trashed: Boolean(m[2]), name };
+          };
+          const abs = (p: { patientId: string; trashed: boolean; name: string }) =>
+            p.trashed ? path.join(assetsDir, p.patientId, ".trash", p.name) : path.join(assetsDir, p.patientId, p.name);
+          const url = (p: { patientId: string; trashed: boolean; name: string }) =>
+            `/__naju_asset/${p.patientId}/${p.trashed ? ".trash/" : ""}${encodeURIComponent(p.name)}`;
+          const exists = (file: string) => fs.stat(file).then((st) => st.isFile()).catch(() => false);
+
+          const src = parse(parsed?.path);
+          if (!src || (action === "move") === src.trashed) {
+            sendJson(res, 400, { ok: false, error: "invalid_path" });
+            return;
+          }
+
+          if (action === "move") {
+            // Already gone (the file was missing): nothing to move, the record keeps its path.
+            if (!(await exists(abs(src)))) {
+              sendJson(res, 200, { ok: true, path: null });
+              return;
+            }
+            await fs.mkdir(path.join(assetsDir, src.patientId, ".trash"), { recursive: true });
+            let dest = { ...src, trashed: true };
+            if (await exists(abs(dest))) dest = { ...dest, name: `${Date.now()}-${src.name}` };
+            await fs.rename(abs(src), abs(dest));
+            sendJson(res, 200, { ok: true, path: url(dest) });
+            return;
+          }
+
+          if (action === "restore") {
+            const dest = parse(parsed?.target);
+            if (!dest || dest.trashed || dest.patientId !== src.patientId) {
+              sendJson(res, 400, { ok: false, error: "invalid_path" });
+              return;
+            }
+            if (await exists(abs(dest))) {
+              sendJson(res, 409, { ok: false, error: "file_name_taken" });
+              return;
+            }
+            if (!(await exists(abs(src)))) {
+              sendJson(res, 404, { ok: false, error: "file_not_found" });
+              return;
+            }
+            await fs.rename(abs(src), abs(dest));
+            sendJson(res, 200, { ok: true, path: url(dest) });
+            return;
+          }
+
+          if (action === "purge") {
+            await fs.rm(abs(src), { force: true });
+            sendJson(res, 200, { ok: true });
+            return;
+          }
+
+          sendJson(res, 400, { ok: false, error: "invalid_action" });
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
+        }
+      });
+
+      // Guided recovery of a store.json that doesn't parse: the damaged file is copied aside as
+      // store.broken-<stamp>.json, then replaced by the newest valid backup (restore: true) or left
+      // for the client to overwrite with its own copy (restore: false).
+      server.middlewares.use("/__naju_recover_store", async (req, res) => {
+        if ((req?.method || "GET").toUpperCase() !== "POST") {
+          sendJson(res, 405, { ok: false, error: "method_not_allowed" });
+          return;
+        }
+        if (!isLocalRequest(req)) {
+          sendJson(res, 403, { ok: false, error: "local_only" });
+          return;
+        }
+
+        try {
+          const parsed = await readJsonBody(req);
+          const raw = await fs.readFile(storeFile, "utf8").catch(() => null);
+          if (raw !== null) {
+            try {
+              JSON.parse(raw);
+              sendJson(res, 200, { ok: true, action: "none", restored_from: null, quarantined: null });
+              return;
+            } catch {
+              // damaged: recover below
+            }
+          }
+          const source = parsed?.restore === false ? null : await newestValidBackup(String(parsed?.dir || ""));
+          if (parsed?.restore !== false && !source) {
+            sendJson(res, 404, { ok: false, error: "no_valid_backup" });
+            return;
+          }
+          let quarantined: string | null = null;
+          if (raw !== null) {
+            const stamp = new Date().toISOString().replace(/[-:]/g, "").replace(/\..+$/, "").replace("T", "-");
+            quarantined = path.join(storeDir, `store.broken-${stamp}.json`);
+            await fs.copyFile(storeFile, quarantined);
+          }
+          if (source) await fs.copyFile(path.join(source, "store.json"), storeFile);
+          sendJson(res, 200, { ok: true, action: source ? "restored_backup" : "quarantined", restored_from: source, quarantined });
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
         }
       });
 
@@ -253,12 +977,34 @@ function najuStorePlugin(): Plugin {
           if (req.method === "GET") {
             try {
               const raw = await fs.readFile(storeFile, "utf8");
+              try {
+                JSON.parse(raw);
+              } catch (e: any) {
+                // Left as is: recovery (/__naju_recover_store) keeps a copy before replacing it.
+                // store.json can't tell us its auto_backup_dir, so the client passes the one it knows.
+                const url = new URL(req.url || "/", "http://localhost");
+                const backup = await newestValidBackup(url.searchParams.get("backupDir") || "");
+                sendJson(res, 500, { ok: false, problem: "malformed", error: "store_damaged", detail: String(e?.message || e), backup });
+                return;
+              }
               res.statusCode = 200;
               res.setHeader("Content-Type", "application/json; charset=utf-8");
               res.setHeader("Cache-Control", "no-store");
               res.end(raw);
               return;
-            } catch {
+            } catch (e: any) {
+              // Only a missing file is replaced by an empty store; a locked one must not be overwritten.
+              if (e?.code !== "ENOENT") {
+                const busy = ["EBUSY", "EAGAIN", "EPERM", "EACCES"].includes(String(e?.code));
+                sendJson(res, busy ? 503 : 500, {
+                  ok: false,
+                  busy,
+                  problem: busy ? "locked" : "unreadable",
+                  error: busy ? "store_busy" : "store_damaged",
+                  detail: String(e?.message || e),
+                });
+                return;
+              }
               await fs.writeFile(storeFile, JSON.stringify(defaultStore, null, 2), "utf8");
               res.statusCode = 200;
               res.setHeader("Content-Type", "application/json; charset=utf-8");
@@ -275,9 +1021,7 @@ function najuStorePlugin(): Plugin {
             req.on("data", (chunk) => {
               size += chunk.length;
               if (size > 25 * 1024 * 1024) {
-                res.statusCode = 413;
-                res.setHeader("Content-Type", "application/json; charset=utf-8");
-                res.end(JSON.stringify({ ok: false, error: "Payload demasiado grande" }));
+                sendJson(res, 413, { ok: false, error: "payload_too_large" });
                 req.destroy();
                 return;
               }
@@ -285,16 +1029,21 @@ function najuStorePlugin(): Plugin {
             });
 
             req.on("end", async () => {
+              let parsed: unknown;
               try {
-                const parsed = JSON.parse(body || "{}");
-                await fs.writeFile(storeFile, JSON.stringify(parsed, null, 2), "utf8");
-                res.statusCode = 200;
-                res.setHeader("Content-Type", "application/json; charset=utf-8");
-                res.end(JSON.stringify({ ok: true }));
+                parsed = JSON.parse(body || "{}");
               } catch {
-                res.statusCode = 400;
-                res.setHeader("Content-Type", "application/json; charset=utf-8");
-                res.end(JSON.stringify({ ok: false, error: "JSON inválido" }));
+                sendJson(res, 400, { ok: false, error: "invalid_json" });
+                return;
+              }
+              try {
+                await fs.writeFile(storeFile, JSON.stringify(parsed, null, 2), "utf8");
+                sendJson(res, 200, { ok: true });
+              } catch (e: any) {
+                // A backup copy or antivirus holding store.json shows up as EBUSY/EPERM on Windows:
+                // answer 503 so the client retries instead of treating it as bad data.
+                const busy = ["EBUSY", "EAGAIN", "EPERM", "EACCES"].includes(String(e?.code));
+                sendJson(res, busy ? 503 : 500, { ok: false, busy, error: busy ? "store_busy" : "server_error", detail: String(e?.message || e || "") });
               }
             });
 
@@ -302,10 +1051,8 @@ function najuStorePlugin(): Plugin {
           }
 
           next();
-        } catch {
-          res.statusCode = 500;
-          res.setHeader("Content-Type", "application/json; charset=utf-8");
-          res.end(JSON.stringify({ ok: false, error: "Error interno" }));
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
         }
       });
 
@@ -318,32 +1065,64 @@ function najuStorePlugin(): Plugin {
           const urlObj = new URL(req.url || "/", "http://localhost");
           const rel = safeRelPath(decodeURIComponent(urlObj.pathname || "/"));
 
-          if (req.method === "GET") {
+          if (req.method === "GET" || req.method === "HEAD") {
             if (!rel) {
-              res.statusCode = 400;
-              res.setHeader("Content-Type", "application/json; charset=utf-8");
-              res.end(JSON.stringify({ ok: false, error: "Ruta inválida" }));
+              sendJson(res, 400, { ok: false, error: "invalid_path" });
               return;
             }
 
             const abs = path.resolve(assetsDir, rel);
-            if (!abs.startsWith(path.resolve(assetsDir))) {
-              res.statusCode = 400;
-              res.setHeader("Content-Type", "application/json; charset=utf-8");
-              res.end(JSON.stringify({ ok: false, error: "Ruta inválida" }));
+            if (!abs.startsWith(path.resolve(assetsDir) + path.sep)) {
+              sendJson(res, 400, { ok: false, error: "invalid_path" });
               return;
             }
 
             try {
-              const data = await fs.readFile(abs);
-              res.statusCode = 200;
+              const st = await fs.stat(abs);
+              if (!st.isFile()) throw new Error("not a file");
               res.setHeader("Content-Type", contentTypeByExt(abs));
               res.setHeader("Cache-Control", "no-store");
-              res.end(data);
+              res.setHeader("Accept-Ranges", "bytes");
+
+              // Single "bytes=start-end" ranges only; that's all readFileChunk asks for.
+              const range = /^bytes=(\d+)-(\d*)$/.exec(String(req.headers.range || ""));
+              if (range) {
+                const start = Number(range[1]);
+                const end = Math.min(range[2] ? Number(range[2]) : st.size - 1, st.size - 1);
+                if (start >= st.size || end < start) {
+                  res.statusCode = 416;
+                  res.setHeader("Content-Range", `bytes */${st.size}`);
+                  res.end();
+                  return;
+                }
+                const length = end - start + 1;
+                res.statusCode = 206;
+                res.setHeader("Content-Range", `bytes ${start}-${end}/${st.size}`);
+                res.setHeader("Content-Length", String(length));
+                if (req.method === "HEAD") {
+                  res.end();
+                  return;
+                }
+                const fh = await fs.open(abs, "r");
+                try {
+                  const buf = Buffer.alloc(length);
+                  const { bytesRead } = await fh.read(buf, 0, length, start);
+                  res.end(buf.subarray(0, bytesRead));
+                } finally {
+                  await fh.close();
+                }
+                return;
+              }
+
+              res.statusCode = 200;
+              res.setHeader("Content-Length", String(st.size));
+              if (req.method === "HEAD") {
+                res.end();
+                return;
+              }
+              res.end(await fs.readFile(abs));
             } catch {
-              res.statusCode = 404;
-              res.setHeader("Content-Type", "application/json; charset=utf-8");
-              res.end(JSON.stringify({ ok: false, error: "Archivo no encontrado" }));
+              sendJson(res, 404, { ok: false, error: "file_not_found" });
             }
             return;
           }
@@ -356,9 +1135,7 @@ function najuStorePlugin(): Plugin {
               size += chunk.length;
               // Permite más que el store.json (audios pueden ser pesados). Ajusta si necesitas.
               if (size > 75 * 1024 * 1024) {
-                res.statusCode = 413;
-                res.setHeader("Content-Type", "application/json; charset=utf-8");
-                res.end(JSON.stringify({ ok: false, error: "Audio demasiado grande" }));
+                sendJson(res, 413, { ok: false, error: "payload_too_large" });
                 req.destroy();
                 return;
               }
@@ -373,9 +1150,13 @@ function najuStorePlugin(): Plugin {
                 const dataBase64 = String(parsed.dataBase64 || "");
 
                 if (!patientId || !filename || !dataBase64) {
-                  res.statusCode = 400;
-                  res.setHeader("Content-Type", "application/json; charset=utf-8");
-                  res.end(JSON.stringify({ ok: false, error: "Payload incompleto" }));
+                  sendJson(res, 400, { ok: false, error: "incomplete_payload" });
+                  return;
+                }
+
+                // Never create a folder for a patient the store doesn't know about.
+                if (!(await patientExists(patientId))) {
+                  sendJson(res, 404, { ok: false, error: "patient_not_found" });
                   return;
                 }
 
@@ -384,22 +1165,16 @@ function najuStorePlugin(): Plugin {
 
                 const abs = path.resolve(patientDir, filename);
                 if (!abs.startsWith(path.resolve(patientDir))) {
-                  res.statusCode = 400;
-                  res.setHeader("Content-Type", "application/json; charset=utf-8");
-                  res.end(JSON.stringify({ ok: false, error: "Nombre de archivo inválido" }));
+                  sendJson(res, 400, { ok: false, error: "invalid_file_name" });
                   return;
                 }
 
                 const buf = Buffer.from(dataBase64, "base64");
                 await fs.writeFile(abs, buf);
 
-                res.statusCode = 200;
-                res.setHeader("Content-Type", "application/json; charset=utf-8");
-                res.end(JSON.stringify({ ok: true, path: `/__naju_asset/${patientId}/${filename}` }));
+                sendJson(res, 200, { ok: true, path: `/__naju_asset/${patientId}/${filename}` });
               } catch {
-                res.statusCode = 400;
-                res.setHeader("Content-Type", "application/json; charset=utf-8");
-                res.end(JSON.stringify({ ok: false, error: "JSON inválido" }));
+                sendJson(res, 400, { ok: false, error: "invalid_json" });
               }
             });
 
@@ -407,10 +1182,8 @@ function najuStorePlugin(): Plugin {
           }
 
           next();
-        } catch {
-          res.statusCode = 500;
-          res.setHeader("Content-Type", "application/json; charset=utf-8");
-          res.end(JSON.stringify({ ok: false, error: "Error interno" }));
+        } catch (e: any) {
+          sendJson(res, 500, { ok: false, error: "server_error", detail: String(e?.message || e || "") });
         }
       });
     },
@@ -0,0 +1,9 @@
+import { defineConfig } from "vitest/config";
+
+// Kept apart from vite.config.ts so tests don't start the dev-server middleware.
+export default defineConfig({
+  test: {
+    environment: "happy-dom",
+    include: ["src/**/*.test.ts"],
+  },
+});